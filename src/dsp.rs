@@ -1,4 +1,4 @@
-use std::{ops::{Deref, Index}, marker::PhantomData};
+use std::{ops::{Deref, Index}, marker::PhantomData, collections::VecDeque};
 
 pub trait Sample: Copy {
     fn mult_weigh(&self, w: f32) -> Self;
@@ -62,8 +62,8 @@ pub trait Signal {
     fn iter<'s>(&'s self) -> SignalIterator<'s, Self> where Self: Sized {
         return SignalIterator { signal: self, ix: 0 }
     }
-    fn resample(self, target_length: usize) -> Interpolator<Self> where Self: Sized {
-        return Interpolator { signal: self, length: target_length }
+    fn resample(self, target_length: usize, quality: Quality) -> Interpolator<Self> where Self: Sized {
+        return Interpolator { signal: self, length: target_length, quality }
     }
     fn convert<O: Sample>(self) -> Converter<Self, O> where Self: Sized {
         return Converter { signal: self, _phantom_o: PhantomData }
@@ -110,9 +110,53 @@ impl <S: Signal> Signal for std::sync::Arc<S> {
     }
 }
 
+/// Interpolation used when resampling, from cheapest to most accurate.
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub enum Quality {
+    /// Nearest neighbour.
+    Fast,
+    /// Linear.
+    Normal,
+    /// Cubic (Catmull-Rom).
+    High,
+    /// Hann-windowed sinc.
+    Best,
+}
+
+/// Number of underlying samples on each side of the interpolated point
+/// considered by Quality::Best.
+const SINC_TAPS: isize = 8;
+
+/// Sinc at `x` samples from the centre, Hann-windowed to reach zero `taps`
+/// samples away.
+fn windowed_sinc(x: f32, taps: f32) -> f32 {
+    let window = 0.5 + 0.5 * (std::f32::consts::PI * x / taps).cos();
+    let sinc = if x == 0.0 { 1.0 } else {
+        (std::f32::consts::PI * x).sin() / (std::f32::consts::PI * x)
+    };
+    sinc * window
+}
+
 pub struct Interpolator<S: Signal> {
     signal: S,
     length: usize,
+    quality: Quality,
+}
+
+impl <S: Signal> Interpolator<S> {
+    /// Underlying sample at `ix`, with indices outside of the signal clamped
+    /// to its ends.
+    fn clamped(&self, ix: isize) -> S::Sample {
+        let last = (self.signal.length() - 1) as isize;
+        self.signal.get(ix.clamp(0, last) as usize)
+    }
+
+    /// Weighted sum of underlying samples starting at `ix0`.
+    fn weighted(&self, ix0: isize, weights: &[f32]) -> S::Sample {
+        weights.iter().enumerate().fold(S::Sample::zero(), |acc, (i, w)| {
+            acc.add_saturated(self.clamped(ix0 + i as isize).mult_weigh(*w))
+        })
+    }
 }
 
 impl <S: Signal> Signal for Interpolator<S> {
@@ -142,6 +186,34 @@ impl <S: Signal> Signal for Interpolator<S> {
         // Distances of uix from uix0 and uix1, used for weighted sum.
         let duix0 = uix - (uix0 as f32);
         let duix1 = 1.0 - duix0;
+        match self.quality {
+            Quality::Fast => {
+                return self.signal.get(if duix0 < 0.5 { uix0 } else { uix1 });
+            },
+            Quality::Normal => (),
+            Quality::High => {
+                let t = duix0;
+                let (t2, t3) = (t * t, t * t * t);
+                let weights = [
+                    (-t3 + 2.0 * t2 - t) / 2.0,
+                    (3.0 * t3 - 5.0 * t2 + 2.0) / 2.0,
+                    (-3.0 * t3 + 4.0 * t2 + t) / 2.0,
+                    (t3 - t2) / 2.0,
+                ];
+                return self.weighted(uix0 as isize - 1, &weights);
+            },
+            Quality::Best => {
+                // On the stack, as this runs for every output sample.
+                let mut weights = [0.0f32; 2 * SINC_TAPS as usize];
+                for (w, i) in weights.iter_mut().zip(1 - SINC_TAPS..=SINC_TAPS) {
+                    *w = windowed_sinc((i as f32) - duix0, SINC_TAPS as f32);
+                }
+                // Normalized, so that a constant signal stays constant.
+                let sum = weights.iter().sum::<f32>();
+                weights.iter_mut().for_each(|w| *w /= sum);
+                return self.weighted(uix0 as isize + 1 - SINC_TAPS, &weights);
+            },
+        }
         // Values at uix0 and uix1, used for weighted sum.
         let uv0 = self.signal.get(uix0);
         let uv1 = self.signal.get(uix1);
@@ -184,17 +256,494 @@ impl <S: Signal> Signal for Volume<S> {
     }
 }
 
+/// In-place radix-2 FFT. Both slices must have the same power-of-two length.
+pub fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    assert!(n.is_power_of_two() && im.len() == n);
+
+    // Bit-reversal permutation.
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let ang = -2.0 * std::f32::consts::PI / (len as f32);
+        for start in (0..n).step_by(len) {
+            for k in 0..(len / 2) {
+                let (ws, wc) = (ang * (k as f32)).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let tr = re[b] * wc - im[b] * ws;
+                let ti = re[b] * ws + im[b] * wc;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+#[derive(PartialEq,Eq,Clone,Copy)]
+pub enum Window {
+    Rectangular,
+    Hann,
+}
+
+impl Window {
+    fn weight(&self, i: usize, n: usize) -> f32 {
+        match self {
+            Window::Rectangular => 1.0,
+            Window::Hann => {
+                let v = (std::f32::consts::PI * (i as f32) / ((n - 1) as f32)).sin();
+                v * v
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct StftParams {
+    /// FFT size in samples, must be a power of two.
+    pub size: usize,
+    /// Distance between the starts of consecutive frames, in samples.
+    pub hop: usize,
+    pub window: Window,
+}
+
+/// Short-time Fourier transform of a signal. Returns one frame per hop, each
+/// frame containing the magnitudes of the lower size/2 frequency bins. Partial
+/// frames at the end of the signal are dropped.
+pub fn stft(signal: &[f32], p: &StftParams) -> Vec<Vec<f32>> {
+    let mut frames = vec![];
+    if signal.len() < p.size || p.hop == 0 {
+        return frames;
+    }
+    let window = (0..p.size).map(|i| p.window.weight(i, p.size)).collect::<Vec<f32>>();
+    let mut re = vec![0.0f32; p.size];
+    let mut im = vec![0.0f32; p.size];
+    for start in (0..=(signal.len() - p.size)).step_by(p.hop) {
+        for i in 0..p.size {
+            re[i] = signal[start + i] * window[i];
+            im[i] = 0.0;
+        }
+        fft(&mut re, &mut im);
+        frames.push((0..(p.size / 2)).map(|i| (re[i] * re[i] + im[i] * im[i]).sqrt()).collect());
+    }
+    frames
+}
+
+/// Scales the side (L-R) component of a stereo frame by `width`, keeping the
+/// mid (L+R) component. 0.0 collapses to mono, 1.0 leaves the frame as is and
+/// larger values widen it.
+pub fn stereo_width(frame: (f32, f32), width: f32) -> (f32, f32) {
+    let (l, r) = frame;
+    let mid = (l + r) / 2.0;
+    let side = (l - r) / 2.0 * width;
+    (mid + side, mid - side)
+}
+
+/// Level below which soft_clip leaves samples alone.
+const CLIP_KNEE: f32 = 0.75;
+
+/// Saturates a sample into -1.0 to 1.0, leaving it as is below the knee and
+/// easing into full scale above it, instead of the harsh wrap or clip past
+/// full scale.
+pub fn soft_clip(v: f32) -> f32 {
+    let a = v.abs();
+    if a <= CLIP_KNEE {
+        return v;
+    }
+    let range = 1.0 - CLIP_KNEE;
+    (CLIP_KNEE + range * ((a - CLIP_KNEE) / range).tanh()).copysign(v)
+}
+
+/// Left and right gain for a pan position, -1.0 (left) through 1.0 (right).
+/// The centre plays at full volume on both sides, like mono output did.
+pub fn pan_gains(pan: f32) -> (f32, f32) {
+    let pan = pan.clamp(-1.0, 1.0);
+    ((1.0 - pan).min(1.0), (1.0 + pan).min(1.0))
+}
+
+/// Peak level follower for meters: jumps up to new peaks immediately, then
+/// falls exponentially, by 60 dB over the release time.
+#[derive(Clone, Copy, Debug)]
+pub struct PeakMeter {
+    level: f32,
+    decay: f32,
+}
+
+impl PeakMeter {
+    pub fn new(sample_rate: u32, release: f32) -> Self {
+        let samples = (release * sample_rate as f32).max(1.0);
+        Self {
+            level: 0.0,
+            decay: 0.001f32.powf(1.0 / samples),
+        }
+    }
+
+    /// Feeds one sample to the meter.
+    pub fn feed(&mut self, v: f32) {
+        self.level = (self.level * self.decay).max(v.abs());
+    }
+
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+}
+
+/// Streaming sample rate converter for stereo frames, pulling frames at one
+/// rate from a source and interpolating them to another with a windowed
+/// sinc. When going down in rate, the sinc is widened to cut off at the new
+/// Nyquist frequency, so that what the new rate can't hold doesn't alias.
+/// Frames are passed through untouched when both rates are the same.
+#[derive(Clone, Debug)]
+pub struct Resampler {
+    /// Source frames per output frame.
+    step: f64,
+    /// Highest frequency kept, relative to the source's Nyquist frequency.
+    cutoff: f32,
+    /// Source frames around the next output frame, which falls `pos` after
+    /// the middle one.
+    frames: VecDeque<(f32, f32)>,
+    pos: f64,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32) -> Self {
+        let step = from as f64 / std::cmp::max(to, 1) as f64;
+        let cutoff = (1.0 / step).min(1.0) as f32;
+        // As many zero crossings of the sinc either side at any cutoff.
+        let taps = (SINC_TAPS as f32 / cutoff).ceil() as usize;
+        Self {
+            step,
+            cutoff,
+            frames: vec![(0.0, 0.0); 2 * taps].into(),
+            // So that the first call pulls frames up to the first one being
+            // in the middle, and output starts with no delay.
+            pos: (taps + 1) as f64,
+        }
+    }
+
+    /// Returns the next output frame, pulling as many frames from `source` as
+    /// needed to get there.
+    pub fn next<F: FnMut() -> (f32, f32)>(&mut self, mut source: F) -> (f32, f32) {
+        if self.step == 1.0 {
+            return source();
+        }
+        while self.pos >= 1.0 {
+            self.pos -= 1.0;
+            self.frames.pop_front();
+            self.frames.push_back(source());
+        }
+        let t = self.pos as f32;
+        self.pos += self.step;
+        let taps = (self.frames.len() / 2) as f32;
+        // Normalized, so that a constant signal stays constant.
+        let (mut sum, mut l, mut r) = (0.0, 0.0, 0.0);
+        for (i, f) in self.frames.iter().enumerate() {
+            let w = windowed_sinc((i as f32 + 1.0 - taps - t) * self.cutoff, taps * self.cutoff);
+            sum += w;
+            l += f.0 * w;
+            r += f.1 * w;
+        }
+        (l / sum, r / sum)
+    }
+}
+
+/// Converts interleaved stereo samples from one rate to another.
+pub fn resample_stereo(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    let frames = samples.len() / 2;
+    let out = (frames as u64 * to as u64 / std::cmp::max(from, 1) as u64) as usize;
+    let mut resampler = Resampler::new(from, to);
+    let mut source = samples.chunks_exact(2).map(|f| (f[0], f[1]));
+    let mut res = Vec::with_capacity(out * 2);
+    for _ in 0..out {
+        let (l, r) = resampler.next(|| source.next().unwrap_or((0.0, 0.0)));
+        res.push(l);
+        res.push(r);
+    }
+    res
+}
+
+/// First order low-pass filter, rolling off at 6 dB per octave above the
+/// cutoff.
+#[derive(Clone, Copy, Debug)]
+pub struct OnePole {
+    a: f32,
+    z: f32,
+}
+
+impl OnePole {
+    pub fn low_pass(sample_rate: f32, cutoff: f32) -> Self {
+        Self {
+            a: 1.0 - (-2.0 * std::f32::consts::PI * cutoff / sample_rate).exp(),
+            z: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        self.z += self.a * (x - self.z);
+        self.z
+    }
+}
+
+/// Second order IIR filter, with coefficient formulas from the RBJ audio EQ
+/// cookbook. Coefficients can be replaced while running without resetting
+/// the filter state.
+#[derive(Clone, Copy, Debug)]
+pub struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    fn normalized(b: [f32; 3], a: [f32; 3]) -> Self {
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    /// Common terms of the shelving filters: A, cos(w0) and 2*sqrt(A)*alpha,
+    /// for a shelf slope of 1.
+    fn shelf_terms(sample_rate: f32, freq: f32, gain_db: f32) -> (f32, f32, f32) {
+        let a = 10.0f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * std::f32::consts::PI * freq / sample_rate;
+        let alpha = w0.sin() / 2.0 * std::f32::consts::SQRT_2;
+        (a, w0.cos(), 2.0 * a.sqrt() * alpha)
+    }
+
+    pub fn low_shelf(sample_rate: f32, freq: f32, gain_db: f32) -> Self {
+        let (a, cos, sa) = Self::shelf_terms(sample_rate, freq, gain_db);
+        Self::normalized([
+            a * ((a + 1.0) - (a - 1.0) * cos + sa),
+            2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+            a * ((a + 1.0) - (a - 1.0) * cos - sa),
+        ], [
+            (a + 1.0) + (a - 1.0) * cos + sa,
+            -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+            (a + 1.0) + (a - 1.0) * cos - sa,
+        ])
+    }
+
+    pub fn high_shelf(sample_rate: f32, freq: f32, gain_db: f32) -> Self {
+        let (a, cos, sa) = Self::shelf_terms(sample_rate, freq, gain_db);
+        Self::normalized([
+            a * ((a + 1.0) + (a - 1.0) * cos + sa),
+            -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+            a * ((a + 1.0) + (a - 1.0) * cos - sa),
+        ], [
+            (a + 1.0) - (a - 1.0) * cos + sa,
+            2.0 * ((a - 1.0) - (a + 1.0) * cos),
+            (a + 1.0) - (a - 1.0) * cos - sa,
+        ])
+    }
+
+    /// Common terms of the pass filters: cos(w0) and alpha.
+    fn pass_terms(sample_rate: f32, freq: f32, q: f32) -> (f32, f32) {
+        let w0 = 2.0 * std::f32::consts::PI * freq / sample_rate;
+        (w0.cos(), w0.sin() / (2.0 * q))
+    }
+
+    /// Pass filters share their poles, differing only in the numerator.
+    fn pass(sample_rate: f32, freq: f32, q: f32, b: impl Fn(f32, f32) -> [f32; 3]) -> Self {
+        let (cos, alpha) = Self::pass_terms(sample_rate, freq, q);
+        Self::normalized(b(cos, alpha), [1.0 + alpha, -2.0 * cos, 1.0 - alpha])
+    }
+
+    pub fn low_pass(sample_rate: f32, freq: f32, q: f32) -> Self {
+        Self::pass(sample_rate, freq, q, |cos, _| [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0])
+    }
+
+    pub fn high_pass(sample_rate: f32, freq: f32, q: f32) -> Self {
+        Self::pass(sample_rate, freq, q, |cos, _| [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0])
+    }
+
+    /// Band-pass with a peak gain of 0 dB at `freq`.
+    pub fn band_pass(sample_rate: f32, freq: f32, q: f32) -> Self {
+        Self::pass(sample_rate, freq, q, |_, alpha| [alpha, 0.0, -alpha])
+    }
+
+    pub fn peaking(sample_rate: f32, freq: f32, gain_db: f32, q: f32) -> Self {
+        let a = 10.0f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * std::f32::consts::PI * freq / sample_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        Self::normalized([
+            1.0 + alpha * a,
+            -2.0 * cos,
+            1.0 - alpha * a,
+        ], [
+            1.0 + alpha / a,
+            -2.0 * cos,
+            1.0 - alpha / a,
+        ])
+    }
+
+    /// Takes over the coefficients of another filter, keeping this one's
+    /// state.
+    pub fn set_coefficients(&mut self, other: &Biquad) {
+        *self = Biquad { z1: self.z1, z2: self.z2, ..*other };
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_quality() {
+        // Every other point of the output falls halfway between two points
+        // of the input.
+        let ramp = (0..64).map(|v| v as f32).collect::<Vec<f32>>();
+        let square = (0..64).map(|v| (v * v) as f32).collect::<Vec<f32>>();
+        let at = |s: &Vec<f32>, q: Quality, ix: usize| s.clone().resample(127, q).get(ix);
+
+        // Nearest neighbour only ever returns input values.
+        assert_eq!(at(&ramp, Quality::Fast, 63), 32.0);
+        assert!((0..127).all(|ix| at(&ramp, Quality::Fast, ix).fract() == 0.0));
+        // Everything else follows a ramp.
+        for q in [Quality::Normal, Quality::High, Quality::Best] {
+            assert!((at(&ramp, q, 63) - 31.5).abs() < 1e-3);
+            assert_eq!(at(&ramp, q, 64), 32.0);
+        }
+        // Linear interpolation cuts across curves, cubic follows them.
+        assert_eq!(at(&square, Quality::Normal, 63), (31.0 * 31.0 + 32.0 * 32.0) / 2.0);
+        assert_eq!(at(&square, Quality::High, 63), 31.5 * 31.5);
+        assert!((at(&square, Quality::Best, 63) - 31.5 * 31.5).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_stereo_width() {
+        let frame = (0.75, 0.25);
+        assert_eq!(stereo_width(frame, 1.0), frame);
+        let (l, r) = stereo_width(frame, 0.0);
+        assert_eq!(l, r);
+        assert_eq!(l, 0.5);
+        let (l, r) = stereo_width(frame, 2.0);
+        assert_eq!(l + r, 1.0);
+        assert_eq!(l - r, 1.0);
+    }
+
+    #[test]
+    fn test_soft_clip() {
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert_eq!(soft_clip(-0.75), -0.75);
+        let mut last = soft_clip(0.75);
+        for i in 1..100 {
+            let v = soft_clip(0.75 + i as f32 * 0.1);
+            assert!(v >= last && v <= 1.0);
+            assert_eq!(soft_clip(-0.75 - i as f32 * 0.1), -v);
+            last = v;
+        }
+        assert!(soft_clip(1.0) < 1.0 && soft_clip(1.0) > 0.9);
+    }
+
+    #[test]
+    fn test_peak_meter() {
+        let mut meter = PeakMeter::new(1000, 0.5);
+        assert_eq!(meter.level(), 0.0);
+        meter.feed(-0.8);
+        assert_eq!(meter.level(), 0.8);
+        // Quieter samples don't pull the level down faster than the decay.
+        meter.feed(0.1);
+        assert!(meter.level() < 0.8 && meter.level() > 0.78);
+        // 60 dB down after the release time.
+        (0..499).for_each(|_| meter.feed(0.0));
+        assert!((meter.level() / 0.8 - 0.001).abs() < 1e-4, "{}", meter.level());
+        // Louder peaks are taken immediately.
+        meter.feed(0.5);
+        assert_eq!(meter.level(), 0.5);
+    }
+
+    #[test]
+    fn test_resampler() {
+        // A 1kHz tone rendered at 48kHz should come out as the same tone at
+        // 44.1kHz, give or take interpolation error.
+        let tone = |sr: f32, i: usize| (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / sr).sin();
+        let rendered = (0..4800).flat_map(|i| [tone(48000.0, i), 0.5 * tone(48000.0, i)]).collect::<Vec<_>>();
+        let out = resample_stereo(&rendered, 48000, 44100);
+        assert_eq!(out.len(), 4410 * 2);
+        for (i, f) in out.chunks_exact(2).enumerate() {
+            let want = tone(44100.0, i);
+            assert!((f[0] - want).abs() < 0.005, "{} {} {}", i, f[0], want);
+            assert!((f[1] - want / 2.0).abs() < 0.005);
+        }
+
+        // Going down to 22.05kHz, an 18kHz tone is beyond what the new rate
+        // can hold, and is filtered out instead of aliasing down to 4.05kHz.
+        let rendered = (0..4800).flat_map(|i| [(2.0 * std::f32::consts::PI * 18000.0 * i as f32 / 48000.0).sin(); 2]).collect::<Vec<_>>();
+        let out = resample_stereo(&rendered, 48000, 22050);
+        // Past the edges, where the filter is still filling up.
+        let peak = out[200..out.len() - 200].iter().fold(0.0f32, |p, v| p.max(v.abs()));
+        assert!(peak < 0.01, "{}", peak);
+
+        let mut same = Resampler::new(44100, 44100);
+        let mut n = 0;
+        assert_eq!(same.next(|| { n += 1; (n as f32, 0.0) }), (1.0, 0.0));
+        assert_eq!(same.next(|| { n += 1; (n as f32, 0.0) }), (2.0, 0.0));
+    }
+
+    #[test]
+    fn test_stft_frames() {
+        let signal = vec![0.0f32; 1000];
+        let frames = stft(&signal, &StftParams { size: 256, hop: 128, window: Window::Hann });
+        assert_eq!(frames.len(), 6);
+        for f in frames.iter() {
+            assert_eq!(f.len(), 128);
+        }
+
+        let frames = stft(&signal[..200], &StftParams { size: 256, hop: 128, window: Window::Hann });
+        assert_eq!(frames.len(), 0);
+    }
+
+    #[test]
+    fn test_stft_peak() {
+        // Sine exactly on bin 16 of a 128-point FFT.
+        let signal = (0..128).map(|i| (2.0 * std::f32::consts::PI * 16.0 * (i as f32) / 128.0).sin()).collect::<Vec<f32>>();
+        let frames = stft(&signal, &StftParams { size: 128, hop: 128, window: Window::Rectangular });
+        assert_eq!(frames.len(), 1);
+        let peak = frames[0].iter().fold(0.0f32, |a, b| a.max(b));
+        assert_eq!(frames[0][16], peak);
+    }
+
     #[test]
     fn test_resample_i8() {
         let input = vec![
             0i8, 0i8, 0i8, 0i8,
             127i8, 127i8, 127i8, 127i8
         ];
-        let resampled = input.resample(10);
+        let resampled = input.resample(10, Quality::Normal);
         assert_eq!(resampled.length(), 10);
         let resampled = resampled.iter().collect::<Vec<i8>>();
         assert_eq!(resampled.length(), 10);
@@ -19,23 +19,45 @@ pub fn imgui_init(display: &glium::Display) -> (imgui_winit_support::WinitPlatfo
 
     winit_platform.attach_window(imgui_context.io_mut(), window, dpi_mode);
 
-    let custom_ranges = imgui::FontGlyphRanges::from_slice(&[
-        0x0020, 0xffff,
-        0]); // this 0 is required to close the ranges list
-    imgui_context.fonts().add_font(&[imgui::FontSource::TtfData {
-        data: include_bytes!("../fonts/Terminus.ttf"),
-        size_pixels: 14.0,
-        config: Some(imgui::FontConfig {
-            glyph_ranges: custom_ranges,
-            size_pixels: 14.0,
-            ..Default::default()
-        }),
-    }]);
+    add_fonts(&mut imgui_context);
 
     (winit_platform, imgui_context)
 }
 
-pub fn draw_sample(ui: &imgui::Ui, sample: &Vec<f32>) {
+/// Character ranges rasterized into the font atlas, covering the whole Basic
+/// Multilingual Plane so that names from any codepage show up.
+const GLYPH_RANGES: &[u32] = &[
+    0x0020, 0xffff,
+    0]; // this 0 is required to close the ranges list
+
+/// Loads Terminus, with DejaVu Sans Mono merged in to fill in glyphs that
+/// Terminus doesn't have.
+fn add_fonts(imgui_context: &mut imgui::Context) {
+    imgui_context.fonts().add_font(&[
+        imgui::FontSource::TtfData {
+            data: include_bytes!("../fonts/Terminus.ttf"),
+            size_pixels: 14.0,
+            config: Some(imgui::FontConfig {
+                glyph_ranges: imgui::FontGlyphRanges::from_slice(GLYPH_RANGES),
+                size_pixels: 14.0,
+                ..Default::default()
+            }),
+        },
+        imgui::FontSource::TtfData {
+            data: include_bytes!("../fonts/DejaVuSansMono.ttf"),
+            size_pixels: 14.0,
+            config: Some(imgui::FontConfig {
+                glyph_ranges: imgui::FontGlyphRanges::from_slice(GLYPH_RANGES),
+                size_pixels: 14.0,
+                ..Default::default()
+            }),
+        },
+    ]);
+}
+
+/// Draws a waveform, with a vertical line at each of the given playback
+/// positions (0.0 to 1.0).
+pub fn draw_sample(ui: &imgui::Ui, sample: &Vec<f32>, playheads: &[f32]) {
     let draw_list = ui.get_window_draw_list();
 
     // Origin
@@ -48,14 +70,116 @@ pub fn draw_sample(ui: &imgui::Ui, sample: &Vec<f32>) {
     let c0 = [0.029, 0.029, 0.029];
     draw_list.add_rect_filled_multicolor([x0, y0], [x1, y1], c0, c0, c0, c0);
 
-    let mut points = Vec::<mint::Vector2<f32>>::new();
-    for x in 0..((x1-x0) as usize) {
-        let xv = (x as f32) / ((x1 - x0) as f32);
-        let s = lerp(0.0, sample.len() as f32, xv);
-        let yv = (sample[s as usize] + 1.0) / 2.0;
-        points.push(mint::Vector2 { x: lerp(x0, x1, xv), y: lerp(y1, y0, yv) } );
+    // Draw the full range of values covered by each pixel column, so that
+    // peaks don't get lost when zoomed out.
+    for (x, (lo, hi)) in minmax_columns(sample, width as usize).into_iter().enumerate() {
+        let x = x0 + (x as f32) + 0.5;
+        let (ylo, yhi) = (lerp(y1, y0, (lo + 1.0) / 2.0), lerp(y1, y0, (hi + 1.0) / 2.0));
+        draw_list.add_line([x, ylo + 0.5], [x, yhi - 0.5], [0.8, 0.8, 0.8]).thickness(1.0).build();
+    }
+    for p in playheads {
+        let x = playhead_x(x0, width, *p);
+        draw_list.add_line([x, y0], [x, y1], [1.0, 0.5, 0.2]).thickness(1.0).build();
+    }
+}
+
+/// Draws a horizontal level meter filling the available width, on a log
+/// scale of the 48 dB below full scale. Turns red when the level reaches
+/// full scale.
+pub fn draw_level_meter(ui: &imgui::Ui, level: f32) {
+    let draw_list = ui.get_window_draw_list();
+
+    let o = ui.cursor_screen_pos();
+    let (width, height) = (ui.content_region_avail()[0].max(10.0), 4.0);
+    let (x0, y0) = (o[0], o[1]);
+    let (x1, y1) = (x0 + width, y0 + height);
+    ui.dummy([width, height]);
+    let c0 = [0.029, 0.029, 0.029];
+    draw_list.add_rect_filled_multicolor([x0, y0], [x1, y1], c0, c0, c0, c0);
+    let c = if level >= 1.0 { [1.0, 0.2, 0.2] } else { [0.3, 0.8, 0.3] };
+    let fill = 1.0 + 20.0 * level.max(1e-9).log10() / 48.0;
+    draw_list.add_rect([x0, y0], [lerp(x0, x1, fill.clamp(0.0, 1.0)), y1], c).filled(true).build();
+}
+
+/// Maps a playback position to the X coordinate of a waveform view.
+fn playhead_x(x0: f32, width: f32, position: f32) -> f32 {
+    x0 + position.clamp(0.0, 1.0) * width
+}
+
+/// Splits a buffer into `columns` equal spans and returns the minimum and
+/// maximum value of each. Spans always cover at least one value, even if
+/// the buffer is shorter than the number of columns.
+fn minmax_columns(sample: &[f32], columns: usize) -> Vec<(f32, f32)> {
+    if sample.is_empty() {
+        return vec![];
+    }
+    (0..columns).map(|x| {
+        let s0 = x * sample.len() / columns;
+        let s1 = std::cmp::max(s0 + 1, (x + 1) * sample.len() / columns);
+        sample[s0..s1].iter().fold((f32::MAX, f32::MIN), |(lo, hi), v| (lo.min(*v), hi.max(*v)))
+    }).collect()
+}
+
+fn heatmap_color(v: f32) -> [f32; 3] {
+    let v = v.clamp(0.0, 1.0);
+    if v < 0.5 {
+        let v = v * 2.0;
+        [lerp(0.029, 0.5, v), 0.029, lerp(0.029, 0.6, v)]
+    } else {
+        let v = (v - 0.5) * 2.0;
+        [lerp(0.5, 1.0, v), lerp(0.029, 0.9, v), lerp(0.6, 0.2, v)]
+    }
+}
+
+/// Draws STFT frames as a heatmap, time on the X axis and frequency on the Y
+/// axis. Magnitudes are shown on a log scale spanning `range_db` decibels
+/// below the loudest bin.
+pub fn draw_spectrogram(ui: &imgui::Ui, frames: &[Vec<f32>], range_db: f32) {
+    let draw_list = ui.get_window_draw_list();
+
+    let o = ui.cursor_screen_pos();
+    let (x0, y0) = (o[0], o[1] + 5.0);
+    let (width, height) = (600.0, 200.0);
+    let (x1, y1) = (x0 + width, y0 + height);
+    ui.dummy([width, height+10.0]);
+    let c0 = [0.029, 0.029, 0.029];
+    draw_list.add_rect_filled_multicolor([x0, y0], [x1, y1], c0, c0, c0, c0);
+
+    if frames.is_empty() || frames[0].is_empty() {
+        return;
+    }
+    let peak = frames.iter().flatten().fold(0.0f32, |a, b| a.max(*b));
+    if peak <= 0.0 {
+        return;
+    }
+    let peak_db = 20.0 * peak.log10();
+
+    // Cells are a few pixels wide to keep the vertex count of the draw list
+    // sane for long renders.
+    let cell = 4.0;
+    let (ncols, nrows) = ((width / cell) as usize, (height / cell) as usize);
+    let nbins = frames[0].len();
+    for col in 0..ncols {
+        let f0 = col * frames.len() / ncols;
+        let f1 = std::cmp::max(f0 + 1, (col + 1) * frames.len() / ncols);
+        if f0 >= frames.len() {
+            break;
+        }
+        for row in 0..nrows {
+            let b0 = row * nbins / nrows;
+            let b1 = std::cmp::max(b0 + 1, (row + 1) * nbins / nrows);
+            let mut mag = 0.0f32;
+            for frame in &frames[f0..std::cmp::min(f1, frames.len())] {
+                for v in &frame[b0..std::cmp::min(b1, nbins)] {
+                    mag = mag.max(*v);
+                }
+            }
+            let db = 20.0 * mag.max(1e-9).log10();
+            let c = heatmap_color(1.0 - (peak_db - db) / range_db);
+            let (cx, cy) = (x0 + (col as f32) * cell, y1 - ((row + 1) as f32) * cell);
+            draw_list.add_rect([cx, cy], [cx + cell, cy + cell], c).filled(true).build();
+        }
     }
-    draw_list.add_polyline(points, [0.8, 0.8, 0.8]).filled(false).thickness(1.0).build();
 }
 
 pub fn create_window() -> (EventLoop<()>, glium::Display) {
@@ -147,4 +271,46 @@ impl Filepicker {
         });
         found
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_playhead_x() {
+        assert_eq!(playhead_x(10.0, 400.0, 0.0), 10.0);
+        assert_eq!(playhead_x(10.0, 400.0, 0.25), 110.0);
+        assert_eq!(playhead_x(10.0, 400.0, 1.0), 410.0);
+        assert_eq!(playhead_x(10.0, 400.0, 1.5), 410.0);
+    }
+
+    #[test]
+    fn test_fonts() {
+        let mut ctx = imgui::Context::create();
+        add_fonts(&mut ctx);
+        let fonts = ctx.fonts();
+        fonts.build_rgba32_texture();
+        let font = fonts.get_font(fonts.fonts()[0]).unwrap();
+        // Terminus and the fallback are merged into one font.
+        assert_eq!(font.config_data_count, 2);
+        // Box drawing characters, as found in CP437 module names, are in the
+        // 0x2000-0x2fff page.
+        assert!(font.used_4k_pages_map[0] & (1 << 2) != 0);
+        // Found in Terminus, and only in the fallback.
+        let found = |c: char| !unsafe {
+            imgui::sys::ImFont_FindGlyphNoFallback(font as *const _ as *mut imgui::sys::ImFont, c as imgui::sys::ImWchar)
+        }.is_null();
+        assert!(found('\u{2588}'));
+        assert!(found('\u{266f}'));
+    }
+
+    #[test]
+    fn test_minmax_columns() {
+        let sample = vec![0.0, 1.0, -0.5, 0.25, 0.0, 0.0, -1.0, 0.5];
+        assert_eq!(minmax_columns(&sample, 2), vec![(-0.5, 1.0), (-1.0, 0.5)]);
+        assert_eq!(minmax_columns(&sample, 4), vec![(0.0, 1.0), (-0.5, 0.25), (0.0, 0.0), (-1.0, 0.5)]);
+        // More columns than values repeats values instead of skipping them.
+        assert_eq!(minmax_columns(&sample[..2], 4), vec![(0.0, 0.0), (0.0, 0.0), (1.0, 1.0), (1.0, 1.0)]);
+        assert_eq!(minmax_columns(&[], 4), vec![]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::io::Write;
+
+/// Width and height of the built-in bitmap font, in pixels at scale 1.
+pub const GLYPH_WIDTH: usize = 5;
+pub const GLYPH_HEIGHT: usize = 7;
+
+/// 5x7 bitmap glyphs for the characters used in pattern cells, one byte per
+/// row with the leftmost pixel in bit 4.
+fn glyph(c: char) -> Option<[u8; GLYPH_HEIGHT]> {
+    Some(match c {
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        'A' => [0x0e, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+        'B' => [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e],
+        'C' => [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e],
+        'D' => [0x1c, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1c],
+        'E' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f],
+        'F' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10],
+        'G' => [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f],
+        '#' => [0x0a, 0x0a, 0x1f, 0x0a, 0x1f, 0x0a, 0x0a],
+        '-' => [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+        '?' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+        _ => return None,
+    })
+}
+
+/// Converts an imgui text color (0.0 to 1.0 per component) to 8 bits per
+/// component, dropping alpha.
+pub fn rgb(c: [f32; 4]) -> [u8; 3] {
+    [c[0], c[1], c[2]].map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+/// Off-screen RGB image.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, background: [u8; 3]) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![background; width * height],
+        }
+    }
+
+    /// Fills a rectangle, clipped to the image.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: [u8; 3]) {
+        for py in y..std::cmp::min(y + height, self.height) {
+            for px in x..std::cmp::min(x + width, self.width) {
+                self.pixels[py * self.width + px] = color;
+            }
+        }
+    }
+
+    /// Draws text with the built-in font, each font pixel becoming a
+    /// `scale` by `scale` square. Characters are GLYPH_WIDTH+1 font pixels
+    /// apart, and ones the font doesn't have are left blank.
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str, color: [u8; 3], scale: usize) {
+        for (i, c) in text.chars().enumerate() {
+            let rows = match glyph(c) {
+                Some(rows) => rows,
+                None => continue,
+            };
+            let cx = x + i * (GLYPH_WIDTH + 1) * scale;
+            for (gy, bits) in rows.iter().enumerate() {
+                for gx in 0..GLYPH_WIDTH {
+                    if bits & (0x10 >> gx) != 0 {
+                        self.fill_rect(cx + gx * scale, y + gy * scale, scale, scale, color);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Writes an image as an 8-bit RGB PNG.
+pub fn write_png<W: Write>(w: W, image: &Image) -> std::io::Result<()> {
+    let mut encoder = png::Encoder::new(w, image.width as u32, image.height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&image.pixels.concat())?;
+    writer.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_draw_text() {
+        let (bg, fg) = ([0, 0, 0], [255, 255, 255]);
+        let mut image = Image::new(20, 10, bg);
+        image.draw_text(1, 1, "-x.", fg, 1);
+        let lit = |x: usize, y: usize| image.pixels[y * 20 + x] == fg;
+        // The dash's middle row, from x=1 to x=5.
+        assert!((1..6).all(|x| lit(x, 4)));
+        assert!(!lit(0, 4) && !lit(6, 4));
+        // Nothing for the unknown character.
+        assert!((7..12).all(|x| (0..10).all(|y| !lit(x, y))));
+        // The dot, two glyphs over.
+        assert!(lit(14, 6) && lit(15, 7) && !lit(13, 6));
+
+        let mut image = Image::new(20, 20, bg);
+        image.draw_text(0, 0, ".", fg, 2);
+        assert!(image.pixels[10 * 20 + 2] == fg && image.pixels[11 * 20 + 5] == fg);
+        assert!(image.pixels[9 * 20 + 2] == bg);
+    }
+
+    #[test]
+    fn test_write_png() {
+        let mut image = Image::new(2, 1, [0, 0, 0]);
+        image.pixels[1] = rgb([1.0, 0.5, 0.0, 1.0]);
+        let mut buf = vec![];
+        write_png(&mut buf, &image).unwrap();
+
+        let mut reader = png::Decoder::new(&buf[..]).read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        assert_eq!((info.width, info.height), (2, 1));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(&data[..info.buffer_size()], &[0, 0, 0, 255, 128, 0]);
+    }
+}
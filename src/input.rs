@@ -1,4 +1,5 @@
 use std::collections::{BTreeSet, BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
 use winit::event::{VirtualKeyCode};
 
 use crate::notes;
@@ -73,3 +74,75 @@ impl PianoKeyboard {
         self.notes.get(kc).cloned()
     }
 }
+
+/// Number of most recent tap intervals averaged into the tempo.
+const TAP_HISTORY: usize = 4;
+/// Taps further apart than this start a new measurement.
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct TapTempo {
+    taps: VecDeque<Instant>,
+}
+
+impl TapTempo {
+    pub fn new() -> Self {
+        Self {
+            taps: VecDeque::new(),
+        }
+    }
+
+    /// Registers a tap and returns the tempo measured so far, if at least two
+    /// taps have been made.
+    pub fn tap(&mut self, at: Instant) -> Option<f32> {
+        if let Some(last) = self.taps.back() {
+            if at.saturating_duration_since(*last) > TAP_TIMEOUT {
+                self.taps.clear();
+            }
+        }
+        self.taps.push_back(at);
+        while self.taps.len() > TAP_HISTORY + 1 {
+            self.taps.pop_front();
+        }
+        self.bpm()
+    }
+
+    pub fn bpm(&self) -> Option<f32> {
+        if self.taps.len() < 2 {
+            return None;
+        }
+        let first = self.taps.front().unwrap();
+        let last = self.taps.back().unwrap();
+        let interval = last.duration_since(*first).as_secs_f32() / ((self.taps.len() - 1) as f32);
+        if interval <= 0.0 {
+            return None;
+        }
+        Some(60.0 / interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tap_tempo_even() {
+        let mut tt = TapTempo::new();
+        let start = Instant::now();
+        assert_eq!(tt.tap(start), None);
+        for i in 1..10 {
+            let bpm = tt.tap(start + Duration::from_millis(500 * i)).unwrap();
+            assert!((bpm - 120.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_tap_tempo_timeout() {
+        let mut tt = TapTempo::new();
+        let start = Instant::now();
+        tt.tap(start);
+        tt.tap(start + Duration::from_millis(1000));
+        assert_eq!(tt.tap(start + Duration::from_millis(10000)), None);
+        let bpm = tt.tap(start + Duration::from_millis(10250)).unwrap();
+        assert!((bpm - 240.0).abs() < 0.01);
+    }
+}
@@ -1,4 +1,4 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, atomic::Ordering};
 
 use glium::glutin::event::{Event, WindowEvent};
 use glium::glutin::event_loop::{ControlFlow};
@@ -6,7 +6,7 @@ use glium::Surface;
 use cpal::traits::{HostTrait, DeviceTrait, StreamTrait};
 use imgui_glium_renderer::Renderer;
 use imgui_winit_support::WinitPlatform;
-use winit::event::{ElementState};
+use winit::event::{ElementState, VirtualKeyCode};
 use imgui::Condition::{Appearing, FirstUseEver};
 
 mod promod;
@@ -16,13 +16,50 @@ mod synth;
 mod gui;
 mod input;
 mod dsp;
+mod wav;
+mod image;
 
-use sound::{Generator};
+use sound::Processor;
 
 
 struct Synthesizer {
     adsr_params: sound::ADSRParams,
     waveform_kind: synth::WaveformKind,
+    /// Second waveform crossfaded in by `morph_blend`, swept by an LFO.
+    morph_kind: synth::WaveformKind,
+    morph_blend: f32,
+    morph_lfo_rate: f32,
+    morph_lfo_depth: f32,
+    /// Fraction of the period pulse waves spend high.
+    pulse_duty: f32,
+    /// FM modulator frequency relative to the note, and modulation index.
+    fm_ratio: f32,
+    fm_index: f32,
+    /// Pitch vibrato, with the depth in semitones.
+    vibrato_rate: f32,
+    vibrato_depth: f32,
+    vibrato_kind: synth::WaveformKind,
+    /// Amplitude LFO, with a depth of 1.0 swinging down to silence.
+    tremolo_rate: f32,
+    tremolo_depth: f32,
+    /// Stereo position of voices, -1.0 (left) through 1.0 (right).
+    pan: f32,
+    /// Oscillators stacked per note, spread up to `detune` cents either
+    /// side of it.
+    unison: u8,
+    detune: f32,
+    /// Start new voices in phase with a free-running clock instead of at
+    /// phase zero.
+    shared_phase: bool,
+    /// Band-limit square and saw waveforms (PolyBLEP).
+    antialiased: bool,
+    filter: Option<sound::FilterKind>,
+    filter_cutoff: f32,
+    filter_q: f32,
+    phase_epoch: std::time::Instant,
+    /// Tempo settable by tapping, which the master echo can follow.
+    bpm: f32,
+    tap_tempo: input::TapTempo,
 }
 
 impl Synthesizer {
@@ -30,24 +67,244 @@ impl Synthesizer {
         Self {
             adsr_params: sound::ADSRParams {
                 a: 0.0,
+                hold: 0.0,
                 d: 0.2,
                 s_level: 1.0,
                 r: 0.1,
+                curve: sound::AdsrCurve::Linear,
             },
             waveform_kind: synth::WaveformKind::Sine,
+            morph_kind: synth::WaveformKind::Square,
+            morph_blend: 0.0,
+            morph_lfo_rate: 1.0,
+            morph_lfo_depth: 0.0,
+            pulse_duty: 0.5,
+            fm_ratio: 1.0,
+            fm_index: 2.0,
+            vibrato_rate: 5.0,
+            vibrato_depth: 0.0,
+            vibrato_kind: synth::WaveformKind::Sine,
+            tremolo_rate: 5.0,
+            tremolo_depth: 0.0,
+            pan: 0.0,
+            unison: 1,
+            detune: 10.0,
+            shared_phase: false,
+            antialiased: true,
+            filter: None,
+            filter_cutoff: 1000.0,
+            filter_q: 0.707,
+            phase_epoch: std::time::Instant::now(),
+            bpm: 120.0,
+            tap_tempo: input::TapTempo::new(),
         }
     }
 
+    /// Everything live synth voices are built from.
+    fn voice_params(&self) -> VoiceParams {
+        VoiceParams {
+            adsr_params: self.adsr_params.clone(),
+            kinds: (self.waveform_kind, self.morph_kind),
+            morph: (self.morph_blend, self.morph_lfo_rate, self.morph_lfo_depth),
+            shape: synth::WaveParams { duty: self.pulse_duty, fm_ratio: self.fm_ratio, fm_index: self.fm_index },
+            vibrato: (self.vibrato_rate, self.vibrato_depth, self.vibrato_kind),
+            tremolo: (self.tremolo_rate, self.tremolo_depth),
+            pan: self.pan,
+            unison: (self.unison, self.detune),
+            epoch: if self.shared_phase { Some(self.phase_epoch) } else { None },
+            antialiased: self.antialiased,
+            filter: (self.filter, self.filter_cutoff, self.filter_q),
+        }
+    }
+
+    fn tap(&mut self) {
+        self.tap_at(std::time::Instant::now());
+    }
+
+    fn tap_at(&mut self, at: std::time::Instant) {
+        if let Some(bpm) = self.tap_tempo.tap(at) {
+            self.bpm = bpm;
+        }
+    }
+
+    /// Length of one beat at the tapped tempo, in seconds.
+    fn beat_seconds(&self) -> f32 {
+        60.0 / self.bpm
+    }
+
     fn imgui_draw(&mut self, ui: &imgui::Ui) {
         if imgui::CollapsingHeader::new("Synthesizer Options").default_open(false).build(ui) {
-            ui.radio_button("Sine", &mut self.waveform_kind, synth::WaveformKind::Sine);
+            ui.text("A:");
+            ui.same_line();
+            ui.radio_button("Sine##a", &mut self.waveform_kind, synth::WaveformKind::Sine);
+            ui.same_line();
+            ui.radio_button("Square##a", &mut self.waveform_kind, synth::WaveformKind::Square);
+            ui.same_line();
+            ui.radio_button("Pulse##a", &mut self.waveform_kind, synth::WaveformKind::Pulse);
+            ui.same_line();
+            ui.radio_button("Triangle##a", &mut self.waveform_kind, synth::WaveformKind::Triangle);
+            ui.same_line();
+            ui.radio_button("Sawtooth##a", &mut self.waveform_kind, synth::WaveformKind::Sawtooth);
+            ui.same_line();
+            ui.radio_button("Noise##a", &mut self.waveform_kind, synth::WaveformKind::Noise);
+            ui.same_line();
+            ui.radio_button("FM##a", &mut self.waveform_kind, synth::WaveformKind::Fm);
+            ui.text("B:");
+            ui.same_line();
+            ui.radio_button("Sine##b", &mut self.morph_kind, synth::WaveformKind::Sine);
+            ui.same_line();
+            ui.radio_button("Square##b", &mut self.morph_kind, synth::WaveformKind::Square);
+            ui.same_line();
+            ui.radio_button("Pulse##b", &mut self.morph_kind, synth::WaveformKind::Pulse);
+            ui.same_line();
+            ui.radio_button("Triangle##b", &mut self.morph_kind, synth::WaveformKind::Triangle);
+            ui.same_line();
+            ui.radio_button("Sawtooth##b", &mut self.morph_kind, synth::WaveformKind::Sawtooth);
+            ui.same_line();
+            ui.radio_button("Noise##b", &mut self.morph_kind, synth::WaveformKind::Noise);
+            ui.same_line();
+            ui.radio_button("FM##b", &mut self.morph_kind, synth::WaveformKind::Fm);
+            if [self.waveform_kind, self.morph_kind].contains(&synth::WaveformKind::Pulse) {
+                ui.slider("Duty cycle", 0.01, 0.99, &mut self.pulse_duty);
+            }
+            if [self.waveform_kind, self.morph_kind].contains(&synth::WaveformKind::Fm) {
+                ui.slider("FM ratio", 0.25, 8.0, &mut self.fm_ratio);
+                ui.slider("FM index", 0.0, 10.0, &mut self.fm_index);
+            }
+            ui.slider("Blend", 0.0, 1.0, &mut self.morph_blend);
+            ui.slider("Blend LFO Rate", 0.0, 20.0, &mut self.morph_lfo_rate);
+            ui.slider("Blend LFO Depth", 0.0, 1.0, &mut self.morph_lfo_depth);
+            ui.slider("Vibrato Rate", 0.0, 20.0, &mut self.vibrato_rate);
+            ui.slider("Vibrato Depth (semitones)", 0.0, 2.0, &mut self.vibrato_depth);
+            ui.text("Vibrato:");
+            for (name, kind) in [
+                ("Sine##v", synth::WaveformKind::Sine), ("Triangle##v", synth::WaveformKind::Triangle),
+                ("Square##v", synth::WaveformKind::Square), ("Sawtooth##v", synth::WaveformKind::Sawtooth),
+            ] {
+                ui.same_line();
+                ui.radio_button(name, &mut self.vibrato_kind, kind);
+            }
+            ui.slider("Tremolo Rate", 0.0, 20.0, &mut self.tremolo_rate);
+            ui.slider("Tremolo Depth", 0.0, 1.0, &mut self.tremolo_depth);
+            ui.slider("Pan", -1.0, 1.0, &mut self.pan);
+            ui.slider("Unison voices", 1, 8, &mut self.unison);
+            ui.slider("Detune (cents)", 0.0, 50.0, &mut self.detune);
+            ui.checkbox("Shared phase", &mut self.shared_phase);
             ui.same_line();
-            ui.radio_button("Square", &mut self.waveform_kind, synth::WaveformKind::Square);
+            ui.checkbox("Anti-aliased", &mut self.antialiased);
+
+            let filter_name = |f: Option<sound::FilterKind>| match f {
+                None => "None",
+                Some(sound::FilterKind::Low) => "Low-pass",
+                Some(sound::FilterKind::High) => "High-pass",
+                Some(sound::FilterKind::Band) => "Band-pass",
+            };
+            if let Some(_combo) = ui.begin_combo("Filter", filter_name(self.filter)) {
+                for f in [None, Some(sound::FilterKind::Low), Some(sound::FilterKind::High), Some(sound::FilterKind::Band)] {
+                    if ui.selectable_config(filter_name(f)).selected(self.filter == f).build() {
+                        self.filter = f;
+                    }
+                }
+            }
+            if self.filter.is_some() {
+                ui.slider_config("Cutoff (Hz)", 20.0, 20000.0).flags(imgui::SliderFlags::LOGARITHMIC).build(&mut self.filter_cutoff);
+                ui.slider("Q", 0.1, 20.0, &mut self.filter_q);
+            }
 
             ui.slider("A", 0.0, 1.0, &mut self.adsr_params.a);
+            ui.slider("H", 0.0, 1.0, &mut self.adsr_params.hold);
             ui.slider("D", 0.0, 1.0, &mut self.adsr_params.d);
             ui.slider("S", 0.0, 1.0, &mut self.adsr_params.s_level);
             ui.slider("R", 0.0, 1.0, &mut self.adsr_params.r);
+            ui.radio_button("Linear", &mut self.adsr_params.curve, sound::AdsrCurve::Linear);
+            ui.same_line();
+            ui.radio_button("Exponential", &mut self.adsr_params.curve, sound::AdsrCurve::Exponential);
+
+            ui.slider("BPM", 20.0, 300.0, &mut self.bpm);
+            ui.same_line();
+            if ui.button("Tap (Tab)") {
+                self.tap();
+            }
+        }
+    }
+}
+
+/// Work done on a background thread, so that the GUI and audio, which share
+/// the tracker's lock, keep running while it does.
+struct Job<T>(Option<std::thread::JoinHandle<T>>);
+
+impl<T: Send + 'static> Job<T> {
+    fn spawn<F: FnOnce() -> T + Send + 'static>(f: F) -> Self {
+        Self(Some(std::thread::spawn(f)))
+    }
+
+    /// Returns the outcome of the job once, when it has finished. An error
+    /// means it panicked.
+    fn poll(&mut self) -> Option<std::thread::Result<T>> {
+        if !self.0.as_ref()?.is_finished() {
+            return None;
+        }
+        self.0.take().map(|h| h.join())
+    }
+}
+
+struct SpectrumView {
+    params: dsp::StftParams,
+    seconds: f32,
+    range_db: f32,
+    frames: Option<Vec<Vec<f32>>>,
+    /// Render in progress, which replaces `frames` when done.
+    job: Option<Job<Vec<Vec<f32>>>>,
+}
+
+impl SpectrumView {
+    fn new() -> Self {
+        Self {
+            params: dsp::StftParams {
+                size: 1024,
+                hop: 512,
+                window: dsp::Window::Hann,
+            },
+            seconds: 60.0,
+            range_db: 60.0,
+            frames: None,
+            job: None,
+        }
+    }
+
+    fn imgui_draw(&mut self, ui: &imgui::Ui, module: &Arc<promod::Module>, sample_rate: u32) {
+        if let Some(_combo) = ui.begin_combo("FFT size", format!("{}", self.params.size)) {
+            for size in [256, 512, 1024, 2048, 4096] {
+                if ui.selectable_config(format!("{}", size)).selected(self.params.size == size).build() {
+                    self.params.size = size;
+                    self.params.hop = std::cmp::min(self.params.hop, size);
+                }
+            }
+        }
+        let mut hop = self.params.hop as i32;
+        ui.slider("Hop", 16, self.params.size as i32, &mut hop);
+        self.params.hop = hop as usize;
+        ui.radio_button("Hann", &mut self.params.window, dsp::Window::Hann);
+        ui.same_line();
+        ui.radio_button("Rectangular", &mut self.params.window, dsp::Window::Rectangular);
+        ui.slider("Seconds", 1.0, 600.0, &mut self.seconds);
+        ui.slider("Range (dB)", 20.0, 120.0, &mut self.range_db);
+        if let Some(frames) = self.job.as_mut().and_then(Job::poll) {
+            self.job = None;
+            self.frames = frames.ok();
+        }
+        if self.job.is_some() {
+            ui.text("Rendering...");
+        } else if ui.button("Render") {
+            let length = (self.seconds * (sample_rate as f32)) as usize;
+            let (module, params) = (module.clone(), self.params);
+            self.job = Some(Job::spawn(move || {
+                let rendered = promod::Player::render(&module, sample_rate as f32, length);
+                dsp::stft(&rendered, &params)
+            }));
+        }
+        if let Some(frames) = &self.frames {
+            gui::draw_spectrogram(ui, frames, self.range_db);
         }
     }
 }
@@ -56,8 +313,163 @@ struct Tracker {
     player: Option<promod::Player>,
     sample_rate: u32,
     filepicker: Option<gui::Filepicker>,
+    /// Path the loaded module came from, which exports are written next to.
+    module_path: Option<std::path::PathBuf>,
+    /// Outcome of the last export, shown next to the export button.
+    export_status: Option<String>,
+    /// Export being written, which reports to `export_status` when done.
+    export_job: Option<Job<String>>,
+    /// Export waiting for confirmation to replace an existing file.
+    overwrite: Option<(Export, std::path::PathBuf)>,
+    spectrum: SpectrumView,
+    /// Length of the loaded song, in seconds.
+    duration: f32,
+    export_trim: wav::SilenceTrim,
+    scrub: Scrub,
+    /// Sample rate of exported files, independent of the engine's.
+    export_rate: u32,
+    auto_gain: promod::AutoGain,
+    /// Auto-gain measurement of the loaded module, applied to the player
+    /// when it's done.
+    gain_job: Option<Job<f32>>,
 
     selected_pattern: usize,
+    /// Only show notes in the pattern view, hiding samples and effects.
+    compact: bool,
+    note_display: NoteDisplay,
+    search_kind: SearchKind,
+    search_text: String,
+    search_results: Vec<promod::SearchMatch>,
+}
+
+/// Synthesizer settings captured by the live notegen.
+#[derive(Clone,PartialEq)]
+struct VoiceParams {
+    adsr_params: sound::ADSRParams,
+    kinds: (synth::WaveformKind, synth::WaveformKind),
+    morph: (f32, f32, f32),
+    shape: synth::WaveParams,
+    vibrato: (f32, f32, synth::WaveformKind),
+    tremolo: (f32, f32),
+    pan: f32,
+    unison: (u8, f32),
+    epoch: Option<std::time::Instant>,
+    antialiased: bool,
+    filter: (Option<sound::FilterKind>, f32, f32),
+}
+
+impl VoiceParams {
+    fn notegen(self, sr: u32, bypass: sound::Bypass) -> sound::NoteGen {
+        let (wk, mk) = self.kinds;
+        let (blend, lfo_rate, lfo_depth) = self.morph;
+        let (vibrato_rate, vibrato_depth, vibrato_kind) = self.vibrato;
+        let (tremolo_rate, tremolo_depth) = self.tremolo;
+        let (filter, cutoff, q) = self.filter;
+        let ratios = synth::unison_ratios(self.unison.0, self.unison.1);
+        Box::new(move |note| {
+            let t = self.epoch.map(|e| e.elapsed().as_secs_f64()).unwrap_or(0.0);
+            let oscs = ratios.as_slice().iter().map(|r| {
+                let freq = note.freq() * r;
+                let (a, b) = (wk.new_with(freq, self.shape), mk.new_with(freq, self.shape));
+                let wave = synth::MorphWave::new(a, b, blend);
+                let mut osc = synth::MorphOscillator::new_at(sr, wave, synth::Lfo::new(sr, lfo_rate, lfo_depth), t);
+                osc.set_antialiased(self.antialiased);
+                if vibrato_depth > 0.0 {
+                    let mut vibrato = synth::Lfo::new(sr, vibrato_rate, vibrato_depth);
+                    vibrato.waveform = vibrato_kind;
+                    osc.set_vibrato(vibrato);
+                }
+                osc
+            }).collect();
+            let osc = synth::Unison::new(oscs);
+            let envelope = sound::ADSR::new(&self.adsr_params);
+            let filters = filter.map(|f| Box::new(sound::Filter::new(f, sr, cutoff, q)) as sound::DynProcessor);
+            let chain = sound::ProcessChain(filters.into_iter().collect());
+            let voice = synth::Tremolo::new(sr, sound::envelope(osc, envelope, sr), tremolo_rate, tremolo_depth);
+            Box::new(sound::pan(sound::process(voice, chain, bypass.clone()), self.pan))
+        })
+    }
+}
+
+/// What the live notegen was last built from. Live sound settings are only
+/// picked up on keyboard events, and rebuilding the notegen for each of
+/// those would also restart the crossfade of held voices.
+#[derive(Clone,PartialEq)]
+enum NotegenKey {
+    Module { module: ModuleRef, ix: usize, quality: dsp::Quality, loops: promod::LoopRule, glide: f32, one_shot: bool },
+    Instrument { module: ModuleRef, quality: dsp::Quality, loops: promod::LoopRule, split: KeySplit, glide: f32, one_shot: Vec<bool> },
+    Synthesizer(VoiceParams),
+}
+
+/// Identifies a loaded module by its allocation, which stays reserved for as
+/// long as this holds on to it, so that a later module can't take its place.
+#[derive(Clone)]
+struct ModuleRef(std::sync::Weak<promod::Module>);
+
+impl ModuleRef {
+    fn new(module: &Arc<promod::Module>) -> Self {
+        Self(Arc::downgrade(module))
+    }
+}
+
+impl PartialEq for ModuleRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ptr_eq(&other.0)
+    }
+}
+
+#[derive(Default)]
+struct NotegenCache {
+    key: Option<NotegenKey>,
+}
+
+impl NotegenCache {
+    /// Records what the notegen would now be built from, returning whether
+    /// it needs rebuilding. Nothing gets built from no key.
+    fn update(&mut self, key: Option<NotegenKey>) -> bool {
+        if key == self.key {
+            return false;
+        }
+        self.key = key;
+        self.key.is_some()
+    }
+}
+
+/// Formats the loaded module can be exported to, written next to it.
+#[derive(Clone,Copy)]
+enum Export {
+    Wav,
+    Json,
+}
+
+impl Export {
+    fn extension(&self) -> &'static str {
+        match self {
+            Export::Wav => "wav",
+            Export::Json => "json",
+        }
+    }
+}
+
+#[derive(PartialEq,Eq,Clone,Copy)]
+enum SearchKind {
+    Note,
+    Sample,
+    Effect,
+}
+
+impl SearchKind {
+    /// Builds a query from user input. Sample numbers are hexadecimal, like
+    /// in the pattern view.
+    fn query(&self, text: &str) -> Option<promod::Search> {
+        let text = text.trim();
+        match self {
+            SearchKind::Note if !text.is_empty() => Some(promod::Search::Note(text.into())),
+            SearchKind::Sample => u8::from_str_radix(text, 16).ok().map(promod::Search::Sample),
+            SearchKind::Effect => text.chars().next().map(promod::Search::Effect),
+            _ => None,
+        }
+    }
 }
 
 impl Tracker {
@@ -66,15 +478,48 @@ impl Tracker {
             player: None,
             sample_rate,
             filepicker: None,
+            module_path: None,
+            export_status: None,
+            export_job: None,
+            overwrite: None,
+            spectrum: SpectrumView::new(),
+            duration: 0.0,
+            export_trim: wav::SilenceTrim::new(),
+            scrub: Scrub::new(),
+            export_rate: 44100,
+            auto_gain: promod::AutoGain::new(),
+            gain_job: None,
 
             selected_pattern: 0,
+            compact: false,
+            note_display: NoteDisplay::Name,
+            search_kind: SearchKind::Note,
+            search_text: String::new(),
+            search_results: vec![],
         }
     }
+    /// Starts writing the module to `out` in the background, with the
+    /// player's current settings.
+    fn export(kind: Export, out: std::path::PathBuf, player: &promod::Player, trim: wav::SilenceTrim, rate: u32) -> Job<String> {
+        let player = player.offline();
+        Job::spawn(move || {
+            let written = match kind {
+                Export::Wav => player.render_to_wav(&out, trim, rate),
+                Export::Json => std::fs::File::create(&out).map_err(promod::Error::from)
+                    .and_then(|f| player.module.write_json(&mut std::io::BufWriter::new(f))),
+            };
+            match written {
+                Ok(()) => format!("Wrote {}", out.display()),
+                Err(e) => format!("Export failed: {:?}", e),
+            }
+        })
+    }
     fn imgui_draw_main_window(&mut self, ui: &imgui::Ui) {
         if imgui::CollapsingHeader::new("Tracker").default_open(true).build(ui) {
             if let Some(_) = &self.player{
                 if ui.button("Close") {
                     self.player = None;
+                    self.gain_job = None;
                 }
             } else {
                 if ui.button(format!("Load...")) {
@@ -84,6 +529,10 @@ impl Tracker {
                 }
             }
             if let Some(p) = &mut self.player{
+                if let Some(gain) = self.gain_job.as_mut().and_then(Job::poll) {
+                    self.gain_job = None;
+                    p.gain = gain.unwrap_or(1.0);
+                }
                 if p.playing {
                     ui.same_line();
                     if ui.button("Pause") {
@@ -91,32 +540,237 @@ impl Tracker {
                     }
                     ui.same_line();
                     if ui.button("Stop") {
-                        p.playing = false;
-                        p.row = 0;
-                        p.program = 0;
-                        p.pattern = 0;
+                        p.stop();
                     }
                 } else {
                     ui.same_line();
                     if ui.button("Play") {
-                        p.playing = true
+                        p.play();
+                    }
+                }
+                if let Some(path) = &self.module_path {
+                    let mut export = None;
+                    ui.same_line();
+                    if ui.button("Export WAV") {
+                        export = Some(Export::Wav);
+                    }
+                    ui.same_line();
+                    if ui.button("Export JSON") {
+                        export = Some(Export::Json);
+                    }
+                    if let Some(kind) = export.filter(|_| self.export_job.is_none()) {
+                        let out = path.with_extension(kind.extension());
+                        if out.exists() {
+                            self.overwrite = Some((kind, out));
+                        } else {
+                            self.export_job = Some(Self::export(kind, out, p, self.export_trim, self.export_rate));
+                        }
                     }
                 }
+                if let Some((kind, out)) = self.overwrite.clone() {
+                    ui.text(format!("{} already exists.", out.display()));
+                    ui.same_line();
+                    if ui.button("Overwrite") {
+                        self.overwrite = None;
+                        self.export_job = Some(Self::export(kind, out, p, self.export_trim, self.export_rate));
+                    }
+                    ui.same_line();
+                    if ui.button("Cancel") {
+                        self.overwrite = None;
+                    }
+                }
+                if let Some(status) = self.export_job.as_mut().and_then(Job::poll) {
+                    self.export_job = None;
+                    self.export_status = Some(status.unwrap_or_else(|_| "Export failed".into()));
+                }
+                if self.export_job.is_some() {
+                    ui.text("Exporting...");
+                } else if let Some(status) = &self.export_status {
+                    ui.text(status);
+                }
+                let (bpm, tpd, dpm) = p.tempo();
+                ui.same_line();
+                ui.text(format!("BPM: {}, Speed: {}, Rows/min: {:.1}", bpm, tpd, dpm));
+                ui.same_line();
+                ui.text(format!("Played: {:.1} s", p.elapsed_seconds()));
+                if p.counting_in() > 0 {
+                    ui.same_line();
+                    ui.text(format!("Count-in: {}", p.counting_in()));
+                }
+                ui.checkbox("Loop", &mut p.looped);
+                ui.same_line();
+                ui.set_next_item_width(100.0);
+                ui.slider("Transpose (semitones)", -24, 24, &mut p.transpose);
+                ui.same_line();
+                ui.set_next_item_width(100.0);
+                ui.slider("Finetune (1/8 semitones)", -8, 8, &mut p.finetune);
+                ui.set_next_item_width(100.0);
+                ui.slider("Gain", 0.0, 16.0, &mut p.gain);
+                ui.same_line();
+                ui.set_next_item_width(100.0);
+                if ui.slider("Speed", 0.25, 2.0, &mut p.speed) {
+                    self.duration = p.duration_estimate();
+                }
+                for speed in [0.5, 1.0, 2.0] {
+                    ui.same_line();
+                    if ui.small_button(format!("{}x", speed)) {
+                        p.speed = speed;
+                        self.duration = p.duration_estimate();
+                    }
+                }
+                ui.same_line();
+                ui.checkbox("Auto-gain on load", &mut self.auto_gain.enabled);
+                if self.auto_gain.enabled {
+                    ui.slider("Target RMS (dBFS)", -30.0, -6.0, &mut self.auto_gain.target_db);
+                    ui.slider("Peak ceiling (dBFS)", -12.0, 0.0, &mut self.auto_gain.ceiling_db);
+                    ui.slider("Measured (s)", 1.0, 120.0, &mut self.auto_gain.seconds);
+                    if self.gain_job.is_some() {
+                        ui.text("Measuring...");
+                    } else if ui.button("Measure now") {
+                        let (auto_gain, module, sample_rate) = (self.auto_gain, p.module.clone(), self.sample_rate as f32);
+                        self.gain_job = Some(Job::spawn(move || auto_gain.gain(&module, sample_rate)));
+                    }
+                }
+                if let Some(_t) = ui.begin_combo("Export rate", format!("{} Hz", self.export_rate)) {
+                    for rate in [22050, 44100, 48000, 96000] {
+                        if ui.selectable_config(format!("{} Hz", rate)).selected(self.export_rate == rate).build() {
+                            self.export_rate = rate;
+                        }
+                    }
+                }
+                ui.checkbox("Trim silence on export", &mut self.export_trim.enabled);
+                if self.export_trim.enabled {
+                    ui.slider("Threshold", 0.0, 0.05, &mut self.export_trim.threshold);
+                }
+                let mut count_in = p.count_in as i32;
+                if ui.slider("Count-in (rows)", 0, 16, &mut count_in) {
+                    p.count_in = count_in as usize;
+                }
+                ui.text("Metronome:");
+                ui.same_line();
+                if ui.button("Off") {
+                    p.click_grid = vec![];
+                }
+                ui.same_line();
+                if ui.button("Beats") {
+                    p.click_grid = vec![true, false, false, false];
+                }
+                ui.same_line();
+                if ui.button("Bars") {
+                    p.click_grid = vec![false; 16];
+                    p.click_grid[0] = true;
+                }
+                ui.same_line();
+                if ui.button("3+3+2") {
+                    p.click_grid = vec![true, false, false, true, false, false, true, false];
+                }
+                let mut steps = p.click_grid.len() as i32;
+                if ui.slider("Click steps (rows)", 0, 16, &mut steps) {
+                    p.click_grid.resize(steps as usize, false);
+                }
+                for (i, step) in p.click_grid.iter_mut().enumerate() {
+                    if i % 8 != 0 {
+                        ui.same_line();
+                    }
+                    ui.checkbox(format!("##click{}", i), step);
+                }
+            }
+
+            if let Some(p) = &mut self.player {
+                let mut compat = p.compat();
+                ui.text("Compatibility:");
+                ui.same_line();
+                let pt = ui.radio_button("ProTracker", &mut compat, promod::PlayerCompat::ProTracker);
+                ui.same_line();
+                let ft = ui.radio_button("FastTracker", &mut compat, promod::PlayerCompat::FastTracker);
+                if pt || ft {
+                    p.set_compat(compat);
+                    self.duration = p.duration_estimate();
+                }
+                let mut t = p.position_seconds();
+                if ui.slider_config("Position", 0.0, self.duration).display_format("%.1f s").build(&mut t) {
+                    if !self.scrub.enabled {
+                        p.seek_seconds(t);
+                    } else {
+                        self.scrub.drag(p, t);
+                    }
+                }
+                if !ui.is_item_active() {
+                    self.scrub.release(p);
+                }
+                ui.same_line();
+                ui.checkbox("Scrub", &mut self.scrub.enabled);
+                ui.text("E8x:");
+                ui.same_line();
+                ui.radio_button("Ignore", &mut p.e8x, promod::E8xMode::Ignore);
+                ui.same_line();
+                ui.radio_button("Panning", &mut p.e8x, promod::E8xMode::Panning);
+                ui.checkbox("Amiga LED filter (E0x)", &mut p.led_filter);
+                if p.led_filter {
+                    ui.same_line();
+                    ui.text(if p.filter_on() { "On" } else { "Off" });
+                }
+                ui.text("Pan:");
+                for ch in 0..p.pans.len() {
+                    ui.same_line();
+                    ui.set_next_item_width(50.0);
+                    ui.slider(format!("##pan{}", ch), -1.0, 1.0, &mut p.pans[ch]);
+                }
+                ui.text("Sample without note:");
+                ui.same_line();
+                ui.radio_button("Update", &mut p.sample_only, promod::SampleOnlyMode::Update);
+                ui.same_line();
+                ui.radio_button("Retrigger", &mut p.sample_only, promod::SampleOnlyMode::Retrigger);
+                ui.text("Resampling:");
+                for (name, quality) in [
+                    ("Fast", dsp::Quality::Fast), ("Normal", dsp::Quality::Normal),
+                    ("High", dsp::Quality::High), ("Best", dsp::Quality::Best),
+                ] {
+                    ui.same_line();
+                    ui.radio_button(name, &mut p.quality, quality);
+                }
+                ui.text("Loop samples with repeat length:");
+                ui.same_line();
+                ui.radio_button("Over 1", &mut p.loops, promod::LoopRule::AboveOne);
+                ui.same_line();
+                ui.radio_button("Over 0", &mut p.loops, promod::LoopRule::AboveZero);
             }
 
             if let Some(fp) = &mut self.filepicker {
                 if let Some(path) = fp.draw(ui) {
                     self.filepicker = None;
                     let m = Arc::new(promod::Module::load(&path).unwrap());
-                    self.player = Some(promod::Player::new(&m, self.sample_rate as f32));
+                    self.spectrum.frames = None;
+                    self.spectrum.job = None;
+                    let player = promod::Player::new(&m, self.sample_rate as f32);
+                    self.gain_job = self.auto_gain.enabled.then(|| {
+                        let (auto_gain, module, sample_rate) = (self.auto_gain, m.clone(), self.sample_rate as f32);
+                        Job::spawn(move || auto_gain.gain(&module, sample_rate))
+                    });
+                    self.duration = player.duration_estimate();
+                    self.player = Some(player);
+                    self.module_path = Some(path);
+                    self.export_status = None;
+                    self.overwrite = None;
                 }
             }
         }
     }
-    fn imgui_draw(&mut self, ui: &imgui::Ui) -> Option<usize> {
+    /// Draws the module windows. `auditioning` are the playback positions of
+    /// a sample being played live, if any.
+    fn imgui_draw(&mut self, ui: &imgui::Ui, auditioning: Option<(usize, Vec<f32>)>) -> Option<usize> {
         let mut res: Option<usize> = None;
-        if let Some(player) = &self.player {
-            let module = &player.module;
+        if let Some(player) = &mut self.player {
+            let module = player.module.clone();
+            let mut playheads = vec![vec![]; module.samples.len()];
+            for (ix, pos) in player.sample_positions() {
+                playheads[ix].push(pos);
+            }
+            if let Some((ix, positions)) = auditioning {
+                if let Some(p) = playheads.get_mut(ix) {
+                    p.extend(positions);
+                }
+            }
             ui.window(format!("{} - Samples", module.title)).size([440.0, 900.0], FirstUseEver).position([0.0, 300.0], FirstUseEver)
             .build(|| {
                 for (i, sample) in module.samples.iter().enumerate() {
@@ -129,7 +783,7 @@ impl Tracker {
                         };
                         ui.text(format!("Length: {} bytes, Volume: {}, Repeat: {}", nbytes, volume, repeat));
                         let id = ui.push_id(format!("sample {}", i));
-                        gui::draw_sample(ui, &sample.data);
+                        gui::draw_sample(ui, &sample.data, &playheads[i]);
                         if ui.button("Play") {
                             res = Some(i);
                         }
@@ -138,6 +792,29 @@ impl Tracker {
                 }
             });
 
+            ui.window(format!("{} - Effect Log", module.title)).size([440.0, 300.0], FirstUseEver).position([900.0, 400.0], FirstUseEver)
+            .build(|| {
+                ui.checkbox("Log effects", &mut player.log_effects);
+                ui.same_line();
+                if ui.button("Clear") {
+                    player.effect_log.clear();
+                }
+                ui.child_window("##effectlog").build(|| {
+                    for e in player.effect_log.iter() {
+                        ui.text(format!("{:02x}:{:02x}.{} ch{} {} vol {:02} period {}",
+                            e.program, e.row, e.tick, e.channel + 1, e.effect, e.volume, e.period));
+                    }
+                    if player.playing && player.log_effects {
+                        ui.set_scroll_here_y_with_ratio(1.0);
+                    }
+                });
+            });
+
+            ui.window(format!("{} - Spectrum", module.title)).size([640.0, 380.0], FirstUseEver).position([900.0, 0.0], FirstUseEver)
+            .build(|| {
+                self.spectrum.imgui_draw(ui, &module, self.sample_rate);
+            });
+
             if player.playing {
                 self.selected_pattern = player.pattern;
             }
@@ -155,44 +832,174 @@ impl Tracker {
                             .build();
                         if clicked {
                             self.selected_pattern = *cur;
+                            if let Some(o) = module.positions().iter().position(|p| *p as usize == *cur) {
+                                player.seek(o, 0);
+                            }
+                        }
+                    }
+                }
+                ui.text("Routing");
+                for pos in 0..player.channel_map.len() {
+                    ui.same_line();
+                    ui.set_next_item_width(40.0);
+                    let src = player.channel_map[pos];
+                    if let Some(_combo) = ui.begin_combo(format!("##route{}", pos), format!("{}", src + 1)) {
+                        for cur in 0..player.channel_map.len() {
+                            if ui.selectable_config(format!("{}", cur + 1)).selected(src == cur).build() {
+                                player.channel_map[pos] = cur;
+                            }
                         }
                     }
                 }
+                ui.text("Now");
+                for state in player.channel_states() {
+                    ui.same_line();
+                    let name = match (state.active, state.note) {
+                        (true, Some(n)) => notes::midi_name(n.midi()),
+                        _ => "---".into(),
+                    };
+                    let v = 0.4 + 0.6 * (state.volume as f32) / 64.0;
+                    ui.text_colored([v, v, v, 1.0], format!("{:<4}", name));
+                }
+                ui.text("Mute");
+                for ch in 0..player.muted.len() {
+                    ui.same_line();
+                    ui.checkbox(format!("##mute{}", ch), &mut player.muted[ch]);
+                }
+                ui.text("Solo");
+                for ch in 0..player.muted.len() {
+                    ui.same_line();
+                    if ui.small_button(format!("{}##solo", ch + 1)) {
+                        player.solo(ch);
+                    }
+                }
+                ui.same_line();
+                if ui.small_button("All") {
+                    player.unmute_all();
+                }
+                if imgui::CollapsingHeader::new("Search").build(ui) {
+                    ui.radio_button("Note", &mut self.search_kind, SearchKind::Note);
+                    ui.same_line();
+                    ui.radio_button("Sample", &mut self.search_kind, SearchKind::Sample);
+                    ui.same_line();
+                    ui.radio_button("Effect", &mut self.search_kind, SearchKind::Effect);
+                    ui.input_text("##search", &mut self.search_text).build();
+                    ui.same_line();
+                    if ui.button("Find") {
+                        self.search_results = match self.search_kind.query(&self.search_text) {
+                            Some(q) => module.search(&q),
+                            None => vec![],
+                        };
+                    }
+                    ui.text(format!("{} matches", self.search_results.len()));
+                    for m in self.search_results.iter() {
+                        let order = m.order.map(|o| format!("{:02x}", o)).unwrap_or("--".into());
+                        if ui.selectable(format!("order {} pattern {:02x} row {:02x} channel {}", order, m.pattern, m.row, m.channel + 1)) {
+                            self.selected_pattern = m.pattern;
+                            if let Some(o) = m.order {
+                                player.seek_position(o, m.row);
+                            }
+                        }
+                    }
+                }
+                ui.checkbox("Compact", &mut self.compact);
+                ui.same_line();
+                if ui.button(format!("Notes: {}", self.note_display.label())) {
+                    self.note_display = self.note_display.next();
+                }
+                if let Some(path) = &self.module_path {
+                    ui.same_line();
+                    if ui.button("Export PNG") && self.selected_pattern < module.patterns.len() {
+                        let out = path.with_extension(format!("pattern{:02x}.png", self.selected_pattern));
+                        let img = pattern_image(&module.patterns[self.selected_pattern], self.note_display, self.compact);
+                        let res = std::fs::File::create(&out).and_then(|f| image::write_png(&mut std::io::BufWriter::new(f), &img));
+                        self.export_status = Some(match res {
+                            Ok(()) => format!("Wrote {}", out.display()),
+                            Err(e) => format!("Export failed: {:?}", e),
+                        });
+                    }
+                }
+                let loop_text = |p: Option<(usize, usize)>| p.map(|(o, r)| format!("{:02x}/{:02x}", o, r)).unwrap_or("--".into());
+                ui.text(format!("Loop: {} to {}", loop_text(player.loop_start), loop_text(player.loop_end)));
+                ui.same_line();
+                if ui.small_button("Clear loop") {
+                    player.loop_start = None;
+                    player.loop_end = None;
+                }
+                if ui.is_item_hovered() {
+                    ui.tooltip_text("Click a row number to start the loop there, shift-click to end it.");
+                }
+                // Song position the selected pattern is first played at, for
+                // setting loop points from the pattern view.
+                let selected_position = module.positions().iter().position(|p| *p as usize == self.selected_pattern);
                 if self.selected_pattern < module.patterns.len() {
                     let mut rowcol = imgui::TableColumnSetup::new("Row");
                     rowcol.init_width_or_weight = 30.0;
-                    if let Some(_) = ui.begin_table_header_with_flags("Pattern", [
-                        rowcol,
-                        imgui::TableColumnSetup::new("1"),
-                        imgui::TableColumnSetup::new("2"),
-                        imgui::TableColumnSetup::new("3"),
-                        imgui::TableColumnSetup::new("4"),
-                    ], imgui::TableFlags::SIZING_FIXED_FIT) {
-                        for (i, row) in module.patterns[self.selected_pattern].rows.iter().enumerate() {
+                    if let Some(_) = ui.begin_table_with_flags("Pattern", module.channels + 1, imgui::TableFlags::SIZING_FIXED_FIT) {
+                        ui.table_setup_column_with(rowcol);
+                        for ch in 0..module.channels {
+                            ui.table_setup_column(format!("{}", ch + 1));
+                        }
+                        // Headers by hand, to fit a level meter under each
+                        // channel number.
+                        let peaks = player.channel_peaks();
+                        ui.table_next_row_with_flags(imgui::TableRowFlags::HEADERS);
+                        ui.table_next_column();
+                        ui.table_header("Row");
+                        for ch in 0..module.channels {
+                            ui.table_next_column();
+                            ui.table_header(format!("{}", ch + 1));
+                            gui::draw_level_meter(ui, peaks.as_slice().get(ch).copied().unwrap_or(0.0));
+                        }
+                        let pattern = &module.patterns[self.selected_pattern];
+                        let mut lengths = vec![vec![None; pattern.num_rows()]; module.channels];
+                        for (ch, l) in lengths.iter_mut().enumerate() {
+                            for nl in pattern.note_lengths(ch) {
+                                l[nl.row] = Some(nl.rows);
+                            }
+                        }
+                        for (i, row) in pattern_view(pattern, self.note_display).into_iter().enumerate() {
                             ui.table_next_column();
                             if cur_row == i {
                                 ui.table_set_bg_color(imgui::TableBgTarget::ROW_BG0, [0.2, 0.2, 0.2]);
                             }
-                            ui.text(format!("{:02x}", i));
-                            for c in row.channels.iter() {
-                                let note = c.snote();
-                                let sn = c.sample_number();
-                                let sample = if sn == 0 {
-                                    format!("..")
-                                } else if sn < 16 {
-                                    format!(".{:X}", sn)
+                            let looped = match (selected_position, player.loop_start, player.loop_end) {
+                                (Some(o), Some(start), Some(end)) => (o, i) >= start && (o, i) <= end,
+                                _ => false,
+                            };
+                            if looped {
+                                ui.text_colored([0.5, 1.0, 0.5, 1.0], format!("{:02x}", i));
+                            } else {
+                                ui.text(format!("{:02x}", i));
+                            }
+                            if let (true, Some(o)) = (ui.is_item_clicked(), selected_position) {
+                                if ui.io().key_shift {
+                                    player.loop_end = Some((o, i));
                                 } else {
-                                    format!("{:02X}", sn)
-                                };
+                                    player.loop_start = Some((o, i));
+                                }
+                            }
+                            for (ch, c) in row.into_iter().enumerate() {
                                 ui.table_next_column();
-                                ui.text_colored([1.0, 1.0, 1.0, 1.0], note);
+                                ui.text_colored(NOTE_COLOR, c.note);
+                                if ui.is_item_hovered() {
+                                    if let Some(rows) = lengths.get(ch).and_then(|l| l[i]) {
+                                        let (ticks, ms) = player.rows_duration(rows);
+                                        ui.tooltip_text(format!("{} rows, {} ticks, {:.0} ms", rows, ticks, ms));
+                                    }
+                                }
+                                if self.compact {
+                                    ui.same_line_with_spacing(0.0, 0.0);
+                                    ui.text("  ");
+                                    continue;
+                                }
                                 ui.same_line_with_spacing(0.0, 0.0);
-                                ui.text_colored([0.4, 0.7, 0.7, 1.0], sample);
+                                ui.text_colored(SAMPLE_COLOR, c.sample);
                                 ui.same_line_with_spacing(0.0, 0.0);
-                                let effect = c.effect().string().chars().collect::<Vec<char>>();
-                                ui.text_colored([1.0, 0.5, 0.87, 1.0], format!("{}", effect[0]));
+                                let effect = c.effect.chars().collect::<Vec<char>>();
+                                ui.text_colored(EFFECT_COLOR, format!("{}", effect[0]));
                                 ui.same_line_with_spacing(0.0, 0.0);
-                                ui.text_colored([1.0, 0.87, 0.5, 1.0], format!("{}{}   ", effect[1], effect[2]));
+                                ui.text_colored(PARAM_COLOR, format!("{}{}   ", effect[1], effect[2]));
                             }
                         }
                     }
@@ -204,21 +1011,425 @@ impl Tracker {
     }
 }
 
+/// Colors of the parts of a pattern cell.
+const NOTE_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const SAMPLE_COLOR: [f32; 4] = [0.4, 0.7, 0.7, 1.0];
+const EFFECT_COLOR: [f32; 4] = [1.0, 0.5, 0.87, 1.0];
+const PARAM_COLOR: [f32; 4] = [1.0, 0.87, 0.5, 1.0];
+
+/// How the note of a pattern cell is shown.
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+enum NoteDisplay {
+    /// Note name, eg. C-2.
+    Name,
+    /// Amiga period in decimal, eg. 0428.
+    Period,
+    /// Amiga period in hexadecimal, eg. 1AC.
+    Hex,
+}
+
+impl NoteDisplay {
+    fn label(&self) -> &'static str {
+        match self {
+            NoteDisplay::Name => "names",
+            NoteDisplay::Period => "periods",
+            NoteDisplay::Hex => "hex",
+        }
+    }
+
+    fn next(&self) -> Self {
+        match self {
+            NoteDisplay::Name => NoteDisplay::Period,
+            NoteDisplay::Period => NoteDisplay::Hex,
+            NoteDisplay::Hex => NoteDisplay::Name,
+        }
+    }
+
+    /// Formats the note of a cell. All notes of a mode are the same width,
+    /// with dots for cells without one.
+    fn format(&self, c: &promod::Data) -> String {
+        match (self, c.period()) {
+            (NoteDisplay::Name, _) => c.snote(),
+            (NoteDisplay::Period, 0) => "....".into(),
+            (NoteDisplay::Period, p) => format!("{:04}", p),
+            (NoteDisplay::Hex, 0) => "...".into(),
+            (NoteDisplay::Hex, p) => format!("{:03X}", p),
+        }
+    }
+}
+
+/// Text of a single pattern cell, as shown in the pattern table.
+struct CellView {
+    note: String,
+    sample: String,
+    effect: String,
+}
+
+/// Formats a pattern for display, one entry per row and one cell per channel.
+fn pattern_view(pattern: &promod::Pattern, notes: NoteDisplay) -> Vec<Vec<CellView>> {
+    pattern.rows.iter().map(|row| {
+        row.channels.iter().map(|c| {
+            let sn = c.sample_number();
+            let sample = if sn == 0 {
+                "..".to_string()
+            } else if sn < 16 {
+                format!(".{:X}", sn)
+            } else {
+                format!("{:02X}", sn)
+            };
+            CellView {
+                note: notes.format(c),
+                sample,
+                effect: c.effect().string(),
+            }
+        }).collect()
+    }).collect()
+}
+
+/// Renders a pattern the way the pattern table shows it, for sharing as an
+/// image. Every fourth row is highlighted to make beats easier to follow.
+fn pattern_image(pattern: &promod::Pattern, notes: NoteDisplay, compact: bool) -> image::Image {
+    let scale = 2;
+    let (cw, ch) = ((image::GLYPH_WIDTH + 1) * scale, (image::GLYPH_HEIGHT + 2) * scale);
+    let view = pattern_view(pattern, notes);
+    let channels = view.first().map(|r| r.len()).unwrap_or(0);
+    let note_chars = notes.format(&promod::Data::new(0, 0, 0)).len();
+    // Row number, then each channel followed by a space.
+    let cell_chars = note_chars + if compact { 1 } else { 6 };
+    let (cols, rows) = (2 + 1 + channels * cell_chars, view.len());
+    let mut img = image::Image::new((cols + 2) * cw, (rows + 2) * ch, [16, 16, 16]);
+    for (i, row) in view.iter().enumerate() {
+        let y = (i + 1) * ch;
+        if i % 4 == 0 {
+            img.fill_rect(0, y - scale, img.width, ch, [40, 40, 40]);
+        }
+        img.draw_text(cw, y, &format!("{:02X}", i), [160, 160, 160], scale);
+        for (n, c) in row.iter().enumerate() {
+            let x = (1 + 3 + n * cell_chars) * cw;
+            img.draw_text(x, y, &c.note, image::rgb(NOTE_COLOR), scale);
+            if compact {
+                continue;
+            }
+            let x = x + note_chars * cw;
+            img.draw_text(x, y, &c.sample, image::rgb(SAMPLE_COLOR), scale);
+            img.draw_text(x + 2 * cw, y, &c.effect[..1], image::rgb(EFFECT_COLOR), scale);
+            img.draw_text(x + 3 * cw, y, &c.effect[1..], image::rgb(PARAM_COLOR), scale);
+        }
+    }
+    img
+}
+
 #[derive(PartialEq,Eq,Clone,Copy)]
 enum LiveSoundSource {
     Module(usize),
+    Instrument,
     Synthesizer,
 }
 
+/// Range of MIDI notes mapped to a module sample.
+#[derive(Clone,PartialEq)]
+struct SplitRange {
+    low: i32,
+    high: i32,
+    sample: usize,
+}
+
+/// Maps notes to module samples, turning a whole module into a multisampled
+/// instrument.
+#[derive(Clone,PartialEq)]
+struct KeySplit {
+    ranges: Vec<SplitRange>,
+}
+
+impl KeySplit {
+    /// Middle C, the lowest note on the piano keyboard.
+    const BASE: i32 = 60;
+
+    fn new() -> Self {
+        Self::octaves(4)
+    }
+
+    /// One octave per sample, starting at middle C.
+    fn octaves(nsamples: usize) -> Self {
+        Self {
+            ranges: (0..nsamples).map(|i| {
+                let low = Self::BASE + 12 * (i as i32);
+                SplitRange { low, high: low + 11, sample: i }
+            }).collect(),
+        }
+    }
+
+    /// One key per sample, starting at middle C.
+    fn keys(nsamples: usize) -> Self {
+        Self {
+            ranges: (0..nsamples).map(|i| {
+                let low = Self::BASE + (i as i32);
+                SplitRange { low, high: low, sample: i }
+            }).collect(),
+        }
+    }
+
+    fn sample_for(&self, note: notes::Note) -> Option<usize> {
+        let n = note.midi();
+        self.ranges.iter().find(|r| r.low <= n && n <= r.high).map(|r| r.sample)
+    }
+
+    /// Plays each note with the sample of its range. Keys outside every
+    /// range stay silent.
+    fn notegen(self, samples: Vec<Arc<promod::Sample>>, one_shot: Vec<bool>, glide: Glide, sample_rate: u32, quality: dsp::Quality, loops: promod::LoopRule) -> sound::NoteGen {
+        Box::new(move |note| {
+            let ix = match self.sample_for(note).filter(|ix| *ix < samples.len()) {
+                Some(ix) => ix,
+                None => return Box::new(sound::OneShot::new(vec![])),
+            };
+            let mut sp = samples[ix].clone().play(note, sample_rate, quality, loops);
+            glide.apply(&mut sp, note);
+            if one_shot.as_slice().get(ix).copied().unwrap_or(false) {
+                sp.stop_looping();
+                return Box::new(sound::ungated(sp));
+            }
+            Box::new(sp)
+        })
+    }
+
+    fn imgui_draw(&mut self, ui: &imgui::Ui, nsamples: usize) {
+        if imgui::CollapsingHeader::new("Instrument Split").default_open(false).build(ui) {
+            if ui.button("Octaves") {
+                *self = Self::octaves(nsamples);
+            }
+            ui.same_line();
+            if ui.button("Keys") {
+                *self = Self::keys(nsamples);
+            }
+            let mut remove = None;
+            for (i, r) in self.ranges.iter_mut().enumerate() {
+                let id = ui.push_id(format!("split {}", i));
+                ui.set_next_item_width(60.0);
+                ui.input_int(format!("{}##low", notes::midi_name(r.low)), &mut r.low).build();
+                ui.same_line();
+                ui.set_next_item_width(60.0);
+                ui.input_int(format!("{}##high", notes::midi_name(r.high)), &mut r.high).build();
+                ui.same_line();
+                ui.set_next_item_width(60.0);
+                let mut sample = (r.sample + 1) as i32;
+                if ui.input_int("Sample", &mut sample).build() {
+                    r.sample = (sample.clamp(1, std::cmp::max(nsamples, 1) as i32) - 1) as usize;
+                }
+                ui.same_line();
+                if ui.button("x") {
+                    remove = Some(i);
+                }
+                id.end();
+            }
+            if let Some(i) = remove {
+                self.ranges.remove(i);
+            }
+            if ui.button("Add range") {
+                let low = self.ranges.last().map(|r| r.high + 1).unwrap_or(Self::BASE);
+                self.ranges.push(SplitRange { low, high: low + 11, sample: 0 });
+            }
+        }
+    }
+}
+
+/// Scrubbing through the song with the position slider: while dragging, a
+/// short grain of the song is played wherever the pointer moved to.
+struct Scrub {
+    enabled: bool,
+    /// Length of each grain, in seconds.
+    grain: f32,
+    /// Song position the last grain was played from, while dragging.
+    last: Option<f32>,
+    /// Whether the song was playing when the drag started, to carry on
+    /// playing once it ends.
+    was_playing: Option<bool>,
+}
+
+impl Scrub {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            grain: 0.08,
+            last: None,
+            was_playing: None,
+        }
+    }
+
+    /// Returns where to play a grain from, given the song position the
+    /// pointer was dragged to. Small movements within half a grain of the
+    /// last one don't start another, so that holding the pointer still
+    /// doesn't stutter.
+    fn grain_at(&mut self, t: f32) -> Option<f32> {
+        if let Some(last) = self.last {
+            if (t - last).abs() < self.grain / 2.0 {
+                return None;
+            }
+        }
+        self.last = Some(t);
+        Some(t)
+    }
+
+    /// Drags the song position to `t`, playing a grain from there if it
+    /// moved far enough.
+    fn drag(&mut self, p: &mut promod::Player, t: f32) {
+        if self.was_playing.is_none() {
+            self.was_playing = Some(p.playing);
+        }
+        if let Some(t) = self.grain_at(t) {
+            p.seek_seconds(t);
+            p.play_grain(self.grain);
+        }
+    }
+
+    /// Ends a drag, so that the next one starts a grain right away. The song
+    /// resumes playing from where it was dragged to if it was playing
+    /// before.
+    fn release(&mut self, p: &mut promod::Player) {
+        self.last = None;
+        if self.was_playing.take() == Some(true) {
+            p.resume();
+        }
+    }
+}
+
+/// Pitch glide between successive live-played module sample notes.
+struct Glide {
+    samples: usize,
+    last_note: Arc<Mutex<Option<notes::Note>>>,
+}
+
+impl Glide {
+    fn new(seconds: f32, sample_rate: u32, last_note: &Arc<Mutex<Option<notes::Note>>>) -> Self {
+        Self {
+            samples: (seconds * (sample_rate as f32)) as usize,
+            last_note: last_note.clone(),
+        }
+    }
+
+    /// Makes a newly started note slide in from the previously started one.
+    fn apply<S: dsp::Signal>(&self, sp: &mut promod::SamplePlayback<S>, note: notes::Note) {
+        let mut last = self.last_note.lock().unwrap();
+        if let Some(prev) = *last {
+            sp.glide(prev.freq() / note.freq(), self.samples);
+        }
+        *last = Some(note);
+    }
+}
+
+/// Master volume change per hotkey press.
+const VOLUME_STEP: f32 = 0.05;
+const MAX_VOLUME: f32 = 2.0;
+
+fn step_volume(volume: f32, steps: i32) -> f32 {
+    (volume + (steps as f32) * VOLUME_STEP).clamp(0.0, MAX_VOLUME)
+}
+
 struct AudioSink {
     poly: sound::PolyphonicGenerator,
     tracker: Tracker,
     config: cpal::SupportedStreamConfig,
     device: cpal::Device,
+    /// Rate everything is generated and processed at, converted to the
+    /// device rate on the way out.
+    engine_rate: u32,
+    resampler: dsp::Resampler,
+
+    volume: f32,
+    /// Gain applied to the mix before soft clipping, see dsp::soft_clip.
+    master_gain: f32,
+    muted: bool,
+    effects_bypass: sound::Bypass,
+    /// Equalizer for the left side (or mono output), with the bands set by
+    /// the UI. The right side runs its own copy.
+    eq: sound::Equalizer,
+    eq_right: sound::Equalizer,
+    eq_enabled: bool,
+    /// Stereo width applied to the output, see dsp::stereo_width.
+    stereo_width: f32,
+    /// Echo on the whole mix, for the left side (or mono output). The right
+    /// side runs its own copy with the same settings.
+    echo: sound::Delay,
+    echo_right: sound::Delay,
+    echo_enabled: bool,
+    /// Keep the echo time at one beat of the synthesizer's tapped tempo.
+    echo_sync: bool,
+    /// Shared delay and reverb, fed by the synth and tracker sends.
+    sends: sound::SendBus,
+    synth_sends: sound::SendLevels,
+    tracker_sends: sound::SendLevels,
+    /// Sums the synth, tracker and effect returns, and further mono sources,
+    /// eg. a metronome, played in the middle next to them.
+    mixer: sound::Mixer,
+
+    xruns: XrunCounter,
+    /// Whether to try raising the priority of the audio thread. This usually
+    /// needs extra permissions, so it's off by default.
+    raise_priority: bool,
+    /// Outcome of raising the audio thread priority, once attempted.
+    priority_status: Option<Result<(), String>>,
 }
 
-impl AudioSink {
+/// Counts likely buffer underruns of the audio output, as well as errors
+/// reported by the audio backend. cpal doesn't report underruns by itself, so
+/// they are guessed from callbacks coming in so late that the previous
+/// buffer must have run out.
+struct XrunCounter {
+    /// Time of the last callback and how long its buffer plays for.
+    last: Option<(std::time::Instant, std::time::Duration)>,
+    count: usize,
+}
+
+impl XrunCounter {
     fn new() -> Self {
+        Self {
+            last: None,
+            count: 0,
+        }
+    }
+
+    /// Records a callback asking for `frames` frames at `now`. Devices queue
+    /// up more than one buffer, so only gaps of over twice the previous
+    /// buffer's length are counted.
+    fn callback(&mut self, now: std::time::Instant, frames: usize, sample_rate: u32) {
+        if let Some((last, covered)) = self.last {
+            if now.saturating_duration_since(last) > covered * 2 {
+                self.count += 1;
+            }
+        }
+        let covered = std::time::Duration::from_secs_f64(frames as f64 / std::cmp::max(sample_rate, 1) as f64);
+        self.last = Some((now, covered));
+    }
+
+    fn error(&mut self) {
+        self.count += 1;
+    }
+
+    fn reset(&mut self) {
+        self.count = 0;
+    }
+}
+
+/// Raises the priority of the calling thread. On Linux, setpriority applies
+/// to the calling thread only, and going below the default niceness needs
+/// CAP_SYS_NICE or a suitable RLIMIT_NICE.
+#[cfg(target_os = "linux")]
+fn raise_thread_priority() -> Result<(), String> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, -11) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error().to_string())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn raise_thread_priority() -> Result<(), String> {
+    Err("not supported on this platform".into())
+}
+
+impl AudioSink {
+    /// Opens the default output device. The engine runs at `engine_rate`, or
+    /// at the device rate if None.
+    fn new(engine_rate: Option<u32>) -> Self {
         let host = cpal::default_host();
         let device = host.default_output_device().expect("no output device available");
         log::info!("Audio device: {}", device.name().unwrap_or("UNKNOWN".into()));
@@ -226,21 +1437,69 @@ impl AudioSink {
             .or_else(|| AudioSink::get_config(&device, cpal::SampleFormat::I16));
         let config = config.expect("no good audio config").with_sample_rate(cpal::SampleRate(44100));
         log::info!("Audio output config: {:?}", config);
+        let engine_rate = engine_rate.unwrap_or(config.sample_rate().0);
+        log::info!("Engine sample rate: {}", engine_rate);
 
+        let eq = sound::Equalizer::new(engine_rate);
+        let eq_right = sound::Equalizer::new(engine_rate);
+        let sends = sound::SendBus::new(engine_rate);
+        // Echoes mixed in under the dry signal.
+        let echo = || {
+            let mut echo = sound::Delay::new(engine_rate, sound::MAX_DELAY_TIME, 0.4);
+            (echo.time, echo.wet, echo.dry) = (0.3, 0.5, 1.0);
+            echo
+        };
+        // Short enough to go unnoticed, long enough not to click.
+        let mut poly = sound::PolyphonicGenerator::new();
+        poly.crossfade = (engine_rate / 200) as usize;
         Self {
-            poly: sound::PolyphonicGenerator::new(),
-            tracker: Tracker::new(config.sample_rate().0),
+            poly,
+            tracker: Tracker::new(engine_rate),
+            resampler: dsp::Resampler::new(engine_rate, config.sample_rate().0),
             config,
             device,
+            engine_rate,
+
+            volume: 1.0,
+            master_gain: 1.0,
+            muted: false,
+            effects_bypass: sound::Bypass::default(),
+            eq,
+            eq_right,
+            eq_enabled: false,
+            stereo_width: 1.0,
+            echo: echo(),
+            echo_right: echo(),
+            echo_enabled: false,
+            echo_sync: false,
+            sends,
+            synth_sends: sound::SendLevels::default(),
+            tracker_sends: sound::SendLevels::default(),
+            mixer: sound::Mixer::default(),
+
+            xruns: XrunCounter::new(),
+            raise_priority: false,
+            priority_status: None,
         }
     }
 
     fn get_config(device: &cpal::Device, format: cpal::SampleFormat) -> Option<cpal::SupportedStreamConfigRange> {
-        let configs = device.supported_output_configs().expect("no output configs");
-        configs.filter(|c| c.channels() == 2 && c.max_sample_rate().0 >= 44100 && c.sample_format() == format).next()
+        let configs = device.supported_output_configs().expect("no output configs").collect::<Vec<_>>();
+        let usable = |c: &cpal::SupportedStreamConfigRange, channels: u16| {
+            c.channels() == channels && c.max_sample_rate().0 >= 44100 && c.sample_format() == format
+        };
+        // Prefer stereo, but don't reject mono-only devices.
+        configs.iter().find(|c| usable(c, 2))
+            .or_else(|| configs.iter().find(|c| usable(c, 1)))
+            .cloned()
     }
 
+    /// Rate of the engine, which everything feeding the sink has to run at.
     fn sample_rate(&self) -> u32 {
+        self.engine_rate
+    }
+
+    fn output_rate(&self) -> u32 {
         self.config.sample_rate().0
     }
 
@@ -250,14 +1509,82 @@ impl AudioSink {
 
     fn fill_sound_buffer<T>(&mut self, data: &mut [T], mul: f32, _info: &cpal::OutputCallbackInfo)
         where T: From<f32> {
-        for frame in data.chunks_mut(self.channels()) {
-            let v_p = self.poly.next();
-            let v_t = self.tracker.player.as_mut().map(|p| p.next()).unwrap_or(0.0);
+        let channels = self.channels();
+        self.xruns.callback(std::time::Instant::now(), data.len() / channels, self.output_rate());
+        if self.raise_priority && self.priority_status.is_none() {
+            self.priority_status = Some(raise_thread_priority());
+        }
+        let gain = if self.muted { 0.0 } else { self.volume * self.master_gain };
+        let poly = &mut self.poly;
+        let tracker = &mut self.tracker;
+        self.eq_right.bands = self.eq.bands;
+        let (eq, eq_right) = (&mut self.eq, &mut self.eq_right);
+        let bypass = self.effects_bypass.load(Ordering::Relaxed);
+        let eq_enabled = self.eq_enabled && !bypass;
+        let width = self.stereo_width;
+        (self.echo_right.time, self.echo_right.feedback, self.echo_right.wet) = (self.echo.time, self.echo.feedback, self.echo.wet);
+        let (echo, echo_right) = (&mut self.echo, &mut self.echo_right);
+        let echo_enabled = self.echo_enabled && !bypass;
+        let sends = &mut self.sends;
+        let (synth_sends, tracker_sends) = (self.synth_sends, self.tracker_sends);
+        let mixer = &mut self.mixer;
+        let resampler = &mut self.resampler;
+        mix_into(data, channels, mul, || resampler.next(|| {
+            let (l_p, r_p) = poly.next_stereo();
+            let (l_t, r_t) = tracker.player.as_mut().map(|p| p.next_stereo()).unwrap_or((0.0, 0.0));
+            // Effects keep running while bypassed, so that their tails are
+            // there when they're switched back on.
+            let wet = sends.process(&[((l_p + r_p) / 2.0, synth_sends), ((l_t + r_t) / 2.0, tracker_sends)]);
+            let wet_gain = if bypass { 0.0 } else { 1.0 };
+            let (l, r) = mixer.mix(&[((l_p, r_p), 1.0), ((l_t, r_t), 1.0), ((wet, wet), wet_gain)]);
+            let (l, r) = if echo_enabled {
+                (echo.process(l), echo_right.process(r))
+            } else {
+                (l, r)
+            };
+            let (l, r) = if eq_enabled {
+                (eq.process(l), eq_right.process(r))
+            } else {
+                (l, r)
+            };
+            master_stage(dsp::stereo_width((l, r), width), gain)
+        }));
+    }
+}
 
-            let v = v_p + v_t;
-            for sample in frame.iter_mut() {
-                *sample = T::from(mul * v);
-            }
+/// Last stage of the output: the master volume and gain, then clipping.
+/// Clipped last, so that nothing after it can push the output past full
+/// scale.
+fn master_stage((l, r): (f32, f32), gain: f32) -> (f32, f32) {
+    (dsp::soft_clip(l * gain), dsp::soft_clip(r * gain))
+}
+
+/// Sets a delay's time to one beat of the synthesizer's tempo, halved until
+/// it fits the delay line for slow tempos.
+fn sync_to_tempo(delay: &mut sound::Delay, synthesizer: &Synthesizer) {
+    let mut time = synthesizer.beat_seconds();
+    while time > sound::MAX_DELAY_TIME {
+        time /= 2.0;
+    }
+    delay.time = time;
+}
+
+/// Fills an interleaved output buffer with `channels` samples per frame,
+/// pulling one stereo frame from `source` per output frame. Mono devices get
+/// the average of both sides, devices with more than two channels get it on
+/// the extra channels.
+fn mix_into<T, F>(data: &mut [T], channels: usize, mul: f32, mut source: F)
+    where T: From<f32>, F: FnMut() -> (f32, f32) {
+    for frame in data.chunks_mut(channels) {
+        let (l, r) = source();
+        let mid = (l + r) / 2.0;
+        for (i, sample) in frame.iter_mut().enumerate() {
+            let v = match (channels, i) {
+                (2.., 0) => l,
+                (2.., 1) => r,
+                _ => mid,
+            };
+            *sample = T::from(mul * v);
         }
     }
 }
@@ -266,10 +1593,33 @@ struct Application {
     piano_keyboard: input::PianoKeyboard,
     synthesizer: Synthesizer,
     live_sound_source: LiveSoundSource,
+    notegen: NotegenCache,
+    key_split: KeySplit,
+    /// Time for live-played module samples to slide in pitch from the
+    /// previous note, in seconds. Zero disables gliding.
+    glide: f32,
+    last_live_note: Arc<Mutex<Option<notes::Note>>>,
 
     audio_sink: Arc<Mutex<AudioSink>>,
 
     last_frame: std::time::Instant,
+    /// When the master volume was last changed by a hotkey, for showing the
+    /// new level briefly.
+    volume_changed: Option<std::time::Instant>,
+    /// Aftertouch pressure set from the UI, standing in for a MIDI
+    /// controller. Applied to all voices, or as polyphonic aftertouch to
+    /// the voice of the last key pressed.
+    pressure: i32,
+    poly_pressure: bool,
+    last_key: Option<notes::Note>,
+    /// Velocity of notes played on the computer keyboard, which has none of
+    /// its own.
+    velocity: f32,
+    /// Mark where voices in the scope moved between envelope stages.
+    scope_stages: bool,
+    /// Module samples which play to their end when live-played, instead of
+    /// being released on key up.
+    one_shot: Vec<bool>,
 }
 
 struct EventLoopContext<'a> {
@@ -280,23 +1630,65 @@ struct EventLoopContext<'a> {
 }
 
 impl Application {
-    fn new() -> Self {
+    fn new(engine_rate: Option<u32>) -> Self {
         Self {
             keyboard: input::Keyboard::new(),
             piano_keyboard: input::PianoKeyboard::new(),
             synthesizer: Synthesizer::new(),
             live_sound_source: LiveSoundSource::Synthesizer,
+            notegen: NotegenCache::default(),
+            key_split: KeySplit::new(),
+            glide: 0.0,
+            last_live_note: Arc::new(Mutex::new(None)),
 
-            audio_sink: Arc::new(Mutex::new(AudioSink::new())),
+            audio_sink: Arc::new(Mutex::new(AudioSink::new(engine_rate))),
 
             last_frame: std::time::Instant::now(),
+            volume_changed: None,
+            pressure: 127,
+            poly_pressure: false,
+            last_key: None,
+            velocity: 1.0,
+            scope_stages: false,
+            one_shot: vec![],
         }
     }
 
+    /// Identifies the notegen the current live sound settings build, if any.
+    fn notegen_key(&self, sink: &AudioSink) -> Option<NotegenKey> {
+        let player = sink.tracker.player.as_ref();
+        Some(match self.live_sound_source {
+            LiveSoundSource::Module(ix) => {
+                let p = player?;
+                NotegenKey::Module {
+                    module: ModuleRef::new(&p.module),
+                    ix,
+                    quality: p.quality,
+                    loops: p.loops,
+                    glide: self.glide,
+                    one_shot: self.one_shot.as_slice().get(ix).copied().unwrap_or(false),
+                }
+            },
+            LiveSoundSource::Instrument => {
+                let p = player?;
+                NotegenKey::Instrument {
+                    module: ModuleRef::new(&p.module),
+                    quality: p.quality,
+                    loops: p.loops,
+                    split: self.key_split.clone(),
+                    glide: self.glide,
+                    one_shot: self.one_shot.clone(),
+                }
+            },
+            LiveSoundSource::Synthesizer => NotegenKey::Synthesizer(self.synthesizer.voice_params()),
+        })
+    }
+
     fn audio_stream(&self) -> cpal::Stream {
         let s = self.audio_sink.lock().unwrap();
         let config = s.config.clone();
         let audio_sink = self.audio_sink.clone();
+        let errors_sink = self.audio_sink.clone();
         let stream = match s.config.sample_format() {
             cpal::SampleFormat::F32 => {
                 s.device.build_output_stream(
@@ -307,6 +1699,7 @@ impl Application {
                     },
                     move |err| {
                         log::error!("Audio error: {:?}", err);
+                        errors_sink.lock().unwrap().xruns.error();
                     },
                     None
                 )
@@ -320,6 +1713,7 @@ impl Application {
                     },
                     move |err| {
                         log::error!("Audio error: {:?}", err);
+                        errors_sink.lock().unwrap().xruns.error();
                     },
                     None
                 )
@@ -382,8 +1776,16 @@ impl Application {
                 event: WindowEvent::KeyboardInput { input, .. },
                 ..
             } => {
+                // Also handed to imgui, for its text fields.
+                let gl_window = ctx.display.gl_window();
+                ctx.winit_platform.handle_event(ctx.imgui_context.io_mut(), gl_window.window(), &event);
+                let captured = ctx.imgui_context.io().want_capture_keyboard;
                 if let Some(kc) = input.virtual_keycode {
                     match input.state {
+                        // Keys typed into a text field neither play notes
+                        // nor trigger hotkeys. Releases still go through, so
+                        // that notes held from before stop.
+                        ElementState::Pressed if captured => (),
                         ElementState::Pressed => {
                             self.keyboard.press(kc);
                         },
@@ -393,25 +1795,42 @@ impl Application {
                     }
                 }
                 let mut sink = self.audio_sink.lock().unwrap();
+                let key = self.notegen_key(&sink);
                 match self.live_sound_source {
+                    _ if !self.notegen.update(key) => {},
                     LiveSoundSource::Module(ix) => {
                         if let Some(p) = &sink.tracker.player {
                             let sample = p.module.samples[ix].clone();
+                            let quality = p.quality;
+                            let loops = p.loops;
                             let sample_rate = sink.sample_rate();
+                            let glide = Glide::new(self.glide, sample_rate, &self.last_live_note);
+                            let one_shot = self.one_shot.as_slice().get(ix).copied().unwrap_or(false);
                             sink.poly.set_notegen(Box::new(move |note| {
-                                Box::new(sample.clone().play(note, sample_rate))
+                                let mut sp = sample.clone().play(note, sample_rate, quality, loops);
+                                glide.apply(&mut sp, note);
+                                if one_shot {
+                                    sp.stop_looping();
+                                    return Box::new(sound::ungated(sp));
+                                }
+                                Box::new(sp)
                             }));
                         }
                     },
+                    LiveSoundSource::Instrument => {
+                        if let Some(p) = &sink.tracker.player {
+                            let samples = p.module.samples.clone();
+                            let quality = p.quality;
+                            let loops = p.loops;
+                            let sample_rate = sink.sample_rate();
+                            let glide = Glide::new(self.glide, sample_rate, &self.last_live_note);
+                            let notegen = self.key_split.clone().notegen(samples, self.one_shot.clone(), glide, sample_rate, quality, loops);
+                            sink.poly.set_notegen(notegen);
+                        }
+                    },
                     LiveSoundSource::Synthesizer => {
-                        let wk = self.synthesizer.waveform_kind.clone();
-                        let sr = sink.sample_rate();
-                        let params = self.synthesizer.adsr_params.clone();
-                        sink.poly.set_notegen(Box::new(move |note| {
-                            let osc = synth::Oscillator::new(sr, wk.new(note.freq()));
-                            let envelope = sound::ADSR::new(&params);
-                            Box::new(sound::envelope(osc, envelope, sr))
-                        }));
+                        let notegen = self.synthesizer.voice_params().notegen(sink.sample_rate(), sink.effects_bypass.clone());
+                        sink.poly.set_notegen(notegen);
                     },
                 }
 
@@ -424,7 +1843,27 @@ impl Application {
                     match ev {
                         input::KeyboardEvent::Down(kc) => {
                             if let Some(n) = self.piano_keyboard.translate(&kc) {
-                                sink.poly.start(n);
+                                sink.poly.start_velocity(n, self.velocity);
+                                self.last_key = Some(n);
+                            }
+                            match kc {
+                                VirtualKeyCode::Tab => self.synthesizer.tap(),
+                                VirtualKeyCode::Equals | VirtualKeyCode::Plus | VirtualKeyCode::NumpadAdd => {
+                                    sink.volume = step_volume(sink.volume, 1);
+                                    self.volume_changed = Some(std::time::Instant::now());
+                                },
+                                VirtualKeyCode::Minus | VirtualKeyCode::NumpadSubtract => {
+                                    sink.volume = step_volume(sink.volume, -1);
+                                    self.volume_changed = Some(std::time::Instant::now());
+                                },
+                                VirtualKeyCode::B => {
+                                    sink.effects_bypass.fetch_xor(true, Ordering::Relaxed);
+                                },
+                                VirtualKeyCode::M => {
+                                    sink.muted = !sink.muted;
+                                    self.volume_changed = Some(std::time::Instant::now());
+                                },
+                                _ => (),
                             }
                         }
                         input::KeyboardEvent::Up(kc) => {
@@ -451,6 +1890,15 @@ impl Application {
     fn imgui_draw(&mut self, ui: &imgui::Ui) {
         let mut sink = self.audio_sink.lock().unwrap();
         ui.window("toysynth").size([300.0, 300.0], Appearing).position([0.0, 20.0], Appearing).collapsed(false, Appearing).build(|| {
+            ui.slider("Volume", 0.0, MAX_VOLUME, &mut sink.volume);
+            ui.same_line();
+            ui.checkbox("Mute", &mut sink.muted);
+            ui.slider("Master gain", 0.0, 4.0, &mut sink.master_gain);
+            ui.slider("Stereo Width", 0.0, 2.0, &mut sink.stereo_width);
+            let mut bypass = sink.effects_bypass.load(Ordering::Relaxed);
+            if ui.checkbox("Bypass effects (B)", &mut bypass) {
+                sink.effects_bypass.store(bypass, Ordering::Relaxed);
+            }
             ui.text("Live Play");
             ui.radio_button("Synthesizer", &mut self.live_sound_source, LiveSoundSource::Synthesizer);
             ui.same_line();
@@ -464,12 +1912,177 @@ impl Application {
                     });
                 },
             }
+            ui.same_line();
+            let nsamples = sink.tracker.player.as_ref().map(|p| p.module.samples.len());
+            ui.disabled(nsamples.is_none(), || {
+                ui.radio_button("Instrument", &mut self.live_sound_source, LiveSoundSource::Instrument);
+            });
+            if let Some(nsamples) = nsamples {
+                self.key_split.imgui_draw(ui, nsamples);
+            }
+            if self.live_sound_source != LiveSoundSource::Synthesizer {
+                ui.slider("Glide (s)", 0.0, 1.0, &mut self.glide);
+            }
+            // What to enter in a pattern to get the note last played live.
+            let last_note = *self.last_live_note.lock().unwrap();
+            if let (LiveSoundSource::Module(ix), Some(note)) = (self.live_sound_source, last_note) {
+                if let Some(sample) = sink.tracker.player.as_ref().and_then(|p| p.module.samples.get(ix)) {
+                    let period = promod::period_for_note(&note, sample.finetune);
+                    ui.text(format!("Last note: {} (period {})", promod::Data::new(period, 0, 0).snote(), period));
+                }
+            }
+            if let Some(nsamples) = nsamples {
+                self.one_shot.resize(nsamples, false);
+                match self.live_sound_source {
+                    LiveSoundSource::Module(ix) if ix < nsamples => {
+                        ui.checkbox("One-shot (ignore key up)", &mut self.one_shot[ix]);
+                    },
+                    LiveSoundSource::Instrument => {
+                        ui.text("One-shot samples (ignore key up):");
+                        for (i, one_shot) in self.one_shot.iter_mut().enumerate() {
+                            if i % 8 != 0 {
+                                ui.same_line();
+                            }
+                            ui.checkbox(format!("{}##oneshot", i + 1), one_shot);
+                        }
+                    },
+                    _ => (),
+                }
+            }
             self.synthesizer.imgui_draw(ui);
+            if imgui::CollapsingHeader::new("Audio").default_open(false).build(ui) {
+                ui.text(format!("Engine: {} Hz, output: {} Hz", sink.sample_rate(), sink.output_rate()));
+                ui.text(format!("Xruns: {}", sink.xruns.count));
+                ui.same_line();
+                if ui.small_button("Reset") {
+                    sink.xruns.reset();
+                }
+                ui.checkbox("Raise audio thread priority", &mut sink.raise_priority);
+                match &sink.priority_status {
+                    Some(Ok(())) => ui.text("Priority raised"),
+                    Some(Err(e)) => ui.text(format!("Could not raise priority: {}", e)),
+                    None => (),
+                }
+            }
+            if imgui::CollapsingHeader::new("Sends").default_open(false).build(ui) {
+                ui.slider("Synth to delay", 0.0, 1.0, &mut sink.synth_sends.delay);
+                ui.slider("Synth to reverb", 0.0, 1.0, &mut sink.synth_sends.reverb);
+                ui.slider("Tracker to delay", 0.0, 1.0, &mut sink.tracker_sends.delay);
+                ui.slider("Tracker to reverb", 0.0, 1.0, &mut sink.tracker_sends.reverb);
+                ui.slider("Delay time (s)", 0.01, sound::MAX_DELAY_TIME, &mut sink.sends.delay.time);
+                ui.slider("Delay feedback", 0.0, 0.95, &mut sink.sends.delay.feedback);
+            }
+            if imgui::CollapsingHeader::new("Effects").default_open(false).build(ui) {
+                ui.checkbox("Echo", &mut sink.echo_enabled);
+                ui.same_line();
+                ui.checkbox("Sync to tap tempo", &mut sink.echo_sync);
+                if sink.echo_sync {
+                    sync_to_tempo(&mut sink.echo, &self.synthesizer);
+                    ui.text(format!("Echo time: {:.3} s", sink.echo.time));
+                } else {
+                    ui.slider("Echo time (s)", 0.01, sound::MAX_DELAY_TIME, &mut sink.echo.time);
+                }
+                ui.slider("Echo feedback", 0.0, 0.95, &mut sink.echo.feedback);
+                ui.slider("Echo mix", 0.0, 1.0, &mut sink.echo.wet);
+            }
+            if imgui::CollapsingHeader::new("Master EQ").default_open(false).build(ui) {
+                ui.checkbox("Enabled", &mut sink.eq_enabled);
+                let ranges = [("Low", 20.0, 1000.0), ("Mid", 200.0, 8000.0), ("High", 1000.0, 16000.0)];
+                for (band, (name, lo, hi)) in sink.eq.bands.iter_mut().zip(ranges) {
+                    ui.slider(format!("{} Hz", name), lo, hi, &mut band.freq);
+                    ui.slider(format!("{} dB", name), -18.0, 18.0, &mut band.gain_db);
+                }
+            }
+            if imgui::CollapsingHeader::new("Voices").default_open(false).build(ui) {
+                let mut max_voices = sink.poly.max_voices as i32;
+                if ui.slider("Limit (0: none)", 0, 32, &mut max_voices) {
+                    sink.poly.max_voices = max_voices as usize;
+                }
+                ui.text("Steal:");
+                ui.same_line();
+                ui.radio_button("Oldest", &mut sink.poly.steal, sound::StealPolicy::Oldest);
+                ui.same_line();
+                ui.radio_button("Lowest", &mut sink.poly.steal, sound::StealPolicy::Lowest);
+                ui.same_line();
+                ui.radio_button("Highest", &mut sink.poly.steal, sound::StealPolicy::Highest);
+                let rate = sink.sample_rate() as f32;
+                let mut crossfade = sink.poly.crossfade as f32 / rate * 1000.0;
+                if ui.slider("Crossfade (ms)", 0.0, 50.0, &mut crossfade) {
+                    sink.poly.crossfade = (crossfade / 1000.0 * rate) as usize;
+                }
+                ui.slider("Aftertouch depth", 0.0, 1.0, &mut sink.poly.aftertouch_depth);
+                let pressed = ui.slider("Pressure", 0, 127, &mut self.pressure);
+                ui.same_line();
+                ui.checkbox("Last key only", &mut self.poly_pressure);
+                if pressed {
+                    match (self.poly_pressure, self.last_key) {
+                        (true, Some(n)) => sink.poly.poly_aftertouch(n, self.pressure as u8),
+                        (true, None) => (),
+                        (false, _) => sink.poly.channel_aftertouch(self.pressure as u8),
+                    }
+                }
+                ui.slider("Velocity", 0.0, 1.0, &mut self.velocity);
+                ui.text("Velocity curve:");
+                ui.same_line();
+                ui.radio_button("Linear##velocity", &mut sink.poly.velocity_curve, sound::VelocityCurve::Linear);
+                ui.same_line();
+                ui.radio_button("Exponential##velocity", &mut sink.poly.velocity_curve, sound::VelocityCurve::Exponential);
+                ui.same_line();
+                ui.radio_button("Fixed##velocity", &mut sink.poly.velocity_curve, sound::VelocityCurve::Fixed);
+            }
+            if imgui::CollapsingHeader::new("Scope").default_open(false).build(ui) {
+                let mut length = sink.poly.scope_length() as i32;
+                if ui.slider("Length", 64, 8192, &mut length) {
+                    sink.poly.set_scope_length(length as usize);
+                }
+                ui.checkbox("Envelope stages", &mut self.scope_stages);
+                for (nap, scope) in sink.poly.scopes.iter() {
+                    if !self.scope_stages {
+                        gui::draw_sample(ui, scope, &[]);
+                        continue;
+                    }
+                    let (stage, marks) = sink.poly.envelope_marks(nap);
+                    let positions = marks.iter().map(|(p, _)| *p).collect::<Vec<f32>>();
+                    gui::draw_sample(ui, scope, &positions);
+                    // Stages entered at each mark: the end of the attack,
+                    // the end of the decay, and the start of the release.
+                    let names = marks.iter().map(|(_, s)| format!("{:?}", s)).collect::<Vec<String>>();
+                    ui.text(format!("Stage: {}, marks: {}", stage.map(|s| format!("{:?}", s)).unwrap_or("-".into()), names.join(", ")));
+                }
+            }
             sink.tracker.imgui_draw_main_window(ui);
         });
-        let play_sample = sink.tracker.imgui_draw(ui);
+        if let Some(t) = self.volume_changed {
+            if t.elapsed() < std::time::Duration::from_millis(1500) {
+                ui.tooltip(|| {
+                    if sink.muted {
+                        ui.text("Muted");
+                    } else {
+                        ui.text(format!("Volume: {:.0}%", sink.volume * 100.0));
+                    }
+                });
+            } else {
+                self.volume_changed = None;
+            }
+        }
+        let auditioning = match self.live_sound_source {
+            LiveSoundSource::Module(ix) => Some((ix, sink.poly.positions())),
+            _ => None,
+        };
+        let play_sample = sink.tracker.imgui_draw(ui, auditioning);
         if let Some(ix) = play_sample {
             self.live_sound_source = LiveSoundSource::Module(ix);
+            // Heard once at C-2 (period 428), the note samples are usually
+            // tuned to.
+            let sample_rate = sink.sample_rate();
+            let audition = sink.tracker.player.as_ref().map(|p| {
+                let sample = p.module.samples[ix].clone();
+                let gain = sample.volume as f32 / 64.0;
+                (sample.play_once(notes::A4.mod_semitones(-9), sample_rate, p.quality), gain)
+            });
+            if let Some((once, gain)) = audition {
+                sink.mixer.add(Box::new(once), gain);
+            }
         }
     }
 }
@@ -477,9 +2090,294 @@ impl Application {
 fn main() {
     env_logger::init_from_env( env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"));
 
-    let app = Application::new();
+    // The engine runs at the device rate unless asked otherwise, eg. to
+    // match the rate of exported files.
+    let engine_rate = std::env::var("TRACK_ENGINE_RATE").ok().and_then(|r| r.parse().ok());
+    let app = Application::new(engine_rate);
     let stream = app.audio_stream();
     stream.play().unwrap();
 
     app.run();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo_tap_tempo() {
+        let mut synthesizer = Synthesizer::new();
+        let t0 = std::time::Instant::now();
+        for i in 0..4 {
+            synthesizer.tap_at(t0 + std::time::Duration::from_millis(400 * i));
+        }
+        assert!((synthesizer.bpm - 150.0).abs() < 1e-3);
+
+        // The echo comes back one tapped beat after the input.
+        let mut echo = sound::Delay::new(1000, sound::MAX_DELAY_TIME, 0.0);
+        sync_to_tempo(&mut echo, &synthesizer);
+        let out = (0..1000).map(|i| echo.process(if i == 0 { 1.0 } else { 0.0 })).collect::<Vec<f32>>();
+        assert_eq!(out.as_slice().iter().position(|v| *v != 0.0), Some(400));
+
+        // Beats too long for the delay line are halved until they fit.
+        synthesizer.bpm = 20.0;
+        sync_to_tempo(&mut echo, &synthesizer);
+        assert_eq!(echo.time, 1.5);
+    }
+
+    #[test]
+    fn test_notegen_cache() {
+        let synthesizer = Synthesizer::new();
+        let mut cache = NotegenCache::default();
+        assert!(!cache.update(None));
+        assert!(cache.update(Some(NotegenKey::Synthesizer(synthesizer.voice_params()))));
+        assert!(!cache.update(Some(NotegenKey::Synthesizer(synthesizer.voice_params()))));
+
+        let mut panned = Synthesizer::new();
+        panned.pan = 0.5;
+        assert!(cache.update(Some(NotegenKey::Synthesizer(panned.voice_params()))));
+        assert!(!cache.update(Some(NotegenKey::Synthesizer(panned.voice_params()))));
+
+        // Losing the key, eg. with the module unloaded, makes the next one
+        // rebuild even if it's the same as before.
+        assert!(!cache.update(None));
+        assert!(cache.update(Some(NotegenKey::Synthesizer(panned.voice_params()))));
+
+        // A module loaded after the previous one was dropped is different,
+        // even with the same contents.
+        let module = || Arc::new(promod::Module {
+            title: String::new(),
+            samples: vec![],
+            patterns: vec![],
+            program: vec![],
+            song_length: 0,
+            channels: 4,
+        });
+        let key = |m: &Arc<promod::Module>| Some(NotegenKey::Module {
+            module: ModuleRef::new(m),
+            ix: 0,
+            quality: dsp::Quality::Normal,
+            loops: promod::LoopRule::AboveOne,
+            glide: 0.0,
+            one_shot: false,
+        });
+        let first = module();
+        assert!(cache.update(key(&first)));
+        assert!(!cache.update(key(&first)));
+        drop(first);
+        for _ in 0..10 {
+            assert!(cache.update(key(&module())));
+        }
+    }
+
+    #[test]
+    fn test_pattern_view_rows() {
+        let view = pattern_view(&promod::Pattern::empty(32, 4), NoteDisplay::Name);
+        assert_eq!(view.len(), 32);
+        assert!(view.iter().all(|r| r.len() == 4));
+        assert_eq!(view[31][3].sample, "..");
+    }
+
+    #[test]
+    fn test_pattern_view_cells() {
+        let mut pattern = promod::Pattern::empty(64, 4);
+        pattern.rows[3].channels[1] = promod::Data::new(428, 0x1f, 0xC20);
+        pattern.rows[4].channels[2] = promod::Data::new(0, 3, 0);
+        let view = pattern_view(&pattern, NoteDisplay::Name);
+        let c = &view[3][1];
+        assert_eq!((c.note.as_str(), c.sample.as_str(), c.effect.as_str()), ("C-2", "1F", "C20"));
+        let c = &view[4][2];
+        assert_eq!((c.note.as_str(), c.sample.as_str(), c.effect.as_str()), ("...", ".3", "..."));
+
+        // Room for the row number and 4 channels of 9 characters, plus a
+        // margin of one character all around.
+        let img = pattern_image(&pattern, NoteDisplay::Name, false);
+        assert_eq!((img.width, img.height), ((3 + 36 + 2) * 12, 66 * 18));
+        assert_eq!(pattern_image(&pattern, NoteDisplay::Name, true).width, (3 + 16 + 2) * 12);
+        // Periods take one more character per channel.
+        assert_eq!(pattern_image(&pattern, NoteDisplay::Period, false).width, (3 + 40 + 2) * 12);
+    }
+
+    #[test]
+    fn test_note_display() {
+        let (c, empty) = (promod::Data::new(428, 1, 0), promod::Data::new(0, 1, 0));
+        assert_eq!(NoteDisplay::Name.format(&c), "C-2");
+        assert_eq!(NoteDisplay::Period.format(&c), "0428");
+        assert_eq!(NoteDisplay::Hex.format(&c), "1AC");
+        assert_eq!(NoteDisplay::Name.format(&empty), "...");
+        assert_eq!(NoteDisplay::Period.format(&empty), "....");
+        assert_eq!(NoteDisplay::Hex.format(&empty), "...");
+        assert_eq!(NoteDisplay::Hex.format(&promod::Data::new(1712, 0, 0)), "6B0");
+
+        let mut mode = NoteDisplay::Name;
+        for _ in 0..3 {
+            mode = mode.next();
+        }
+        assert_eq!(mode, NoteDisplay::Name);
+    }
+
+    #[test]
+    fn test_xrun_counter() {
+        let mut xruns = XrunCounter::new();
+        let start = std::time::Instant::now();
+        let ms = std::time::Duration::from_millis;
+        // 441 frames at 44.1kHz last 10ms.
+        xruns.callback(start, 441, 44100);
+        xruns.callback(start + ms(10), 441, 44100);
+        xruns.callback(start + ms(25), 441, 44100);
+        assert_eq!(xruns.count, 0);
+        xruns.callback(start + ms(50), 441, 44100);
+        assert_eq!(xruns.count, 1);
+        // A bigger buffer covers a longer gap.
+        xruns.callback(start + ms(55), 4410, 44100);
+        xruns.callback(start + ms(200), 441, 44100);
+        assert_eq!(xruns.count, 1);
+        xruns.error();
+        assert_eq!(xruns.count, 2);
+        xruns.reset();
+        assert_eq!(xruns.count, 0);
+    }
+
+    #[test]
+    fn test_key_split() {
+        let split = KeySplit {
+            ranges: vec![
+                SplitRange { low: 60, high: 64, sample: 3 },
+                SplitRange { low: 65, high: 71, sample: 7 },
+            ],
+        };
+        let c4 = notes::Note::new(261.63);
+        assert_eq!(split.sample_for(c4), Some(3));
+        assert_eq!(split.sample_for(c4.mod_semitones(4)), Some(3));
+        assert_eq!(split.sample_for(c4.mod_semitones(5)), Some(7));
+        assert_eq!(split.sample_for(c4.mod_semitones(11)), Some(7));
+        assert_eq!(split.sample_for(c4.mod_semitones(12)), None);
+        assert_eq!(split.sample_for(c4.mod_semitones(-1)), None);
+
+        let split = KeySplit::octaves(2);
+        assert_eq!(split.sample_for(c4.mod_semitones(13)), Some(1));
+
+        // Keys outside every range are silent instead of playing a fallback
+        // sample.
+        let samples = (0..2).map(|_| Arc::new(promod::Sample {
+            name: String::new(),
+            length: 500,
+            finetune: 0,
+            volume: 64,
+            repeat_start: 0,
+            repeat_length: 0,
+            data: vec![1.0; 1000],
+            right: None,
+        })).collect::<Vec<_>>();
+        let glide = Glide::new(0.0, 44100, &Arc::new(Mutex::new(None)));
+        let notegen = KeySplit::keys(2).notegen(samples, vec![], glide, 44100, dsp::Quality::Normal, promod::LoopRule::AboveOne);
+        for (note, sounds) in [(c4, true), (c4.mod_semitones(1), true), (c4.mod_semitones(2), false), (c4.mod_semitones(-1), false)] {
+            let mut voice = notegen(note);
+            voice.trigger_start();
+            assert_eq!(voice.next() != 0.0, sounds);
+            assert_eq!(voice.is_finished(), !sounds);
+        }
+    }
+
+    #[test]
+    fn test_scrub_grains() {
+        let mut scrub = Scrub::new();
+        scrub.grain = 0.1;
+        assert_eq!(scrub.grain_at(10.0), Some(10.0));
+        // Jitter around the last grain is ignored.
+        assert_eq!(scrub.grain_at(10.04), None);
+        assert_eq!(scrub.grain_at(9.97), None);
+        // Moving further, in either direction, plays from the pointer.
+        assert_eq!(scrub.grain_at(10.2), Some(10.2));
+        assert_eq!(scrub.grain_at(10.1), Some(10.1));
+        assert_eq!(scrub.grain_at(9.0), Some(9.0));
+        // A new drag starts a grain even where the last one ended.
+        scrub.last = None;
+        assert_eq!(scrub.grain_at(9.0), Some(9.0));
+    }
+
+    #[test]
+    fn test_scrub_playing() {
+        let module = Arc::new(promod::Module {
+            title: String::new(),
+            samples: vec![],
+            patterns: vec![promod::Pattern::empty(64, 4)],
+            program: vec![0],
+            song_length: 1,
+            channels: 4,
+        });
+        let mut player = promod::Player::new(&module, 1000.0);
+        let mut scrub = Scrub::new();
+        player.play();
+        scrub.drag(&mut player, 0.5);
+        (0..1000).for_each(|_| { player.next_stereo(); });
+        assert!(!player.playing);
+        // Dragging on doesn't change what the song was doing before.
+        scrub.drag(&mut player, 1.0);
+        scrub.release(&mut player);
+        assert!(player.playing);
+        let t = player.position_seconds();
+        (0..1000).for_each(|_| { player.next_stereo(); });
+        assert!(player.playing && player.position_seconds() > t);
+
+        // A paused song stays paused.
+        player.stop();
+        scrub.drag(&mut player, 0.5);
+        (0..1000).for_each(|_| { player.next_stereo(); });
+        scrub.release(&mut player);
+        assert!(!player.playing);
+        // Releasing without a drag does nothing.
+        scrub.release(&mut player);
+        assert!(!player.playing);
+    }
+
+    #[test]
+    fn test_step_volume() {
+        let mut v = 1.0;
+        for _ in 0..100 {
+            v = step_volume(v, 1);
+        }
+        assert_eq!(v, MAX_VOLUME);
+        for _ in 0..100 {
+            v = step_volume(v, -1);
+        }
+        assert_eq!(v, 0.0);
+    }
+
+    #[test]
+    fn test_mix_into_mono() {
+        let mut n = 0.0;
+        let mut data = vec![0.0f32; 4];
+        mix_into(&mut data, 1, 1.0, || { n += 1.0; (n, n) });
+        assert_eq!(data, vec![1.0, 2.0, 3.0, 4.0]);
+        mix_into(&mut data, 1, 1.0, || (1.0, 0.0));
+        assert_eq!(data, vec![0.5; 4]);
+    }
+
+    #[test]
+    fn test_mix_into_stereo() {
+        let mut n = 0.0;
+        let mut data = vec![0.0f32; 4];
+        mix_into(&mut data, 2, 1.0, || { n += 1.0; (n, n) });
+        assert_eq!(data, vec![1.0, 1.0, 2.0, 2.0]);
+        mix_into(&mut data, 2, 1.0, || { n += 1.0; (n, -n) });
+        assert_eq!(data, vec![3.0, -3.0, 4.0, -4.0]);
+    }
+
+    #[test]
+    fn test_mix_into_clipped() {
+        // Inputs up to 16 times full scale at the highest volume and gain,
+        // loud enough to wrap around if they made it to 16 bits unclipped.
+        let mut n = 0.0;
+        let mut data = vec![0.0f32; 64];
+        mix_into(&mut data, 2, 32767.0, || { n += 0.5; master_stage((n, -n), MAX_VOLUME * 4.0) });
+        for frame in data.chunks(2) {
+            assert!(frame[0] > 0.0 && frame[0] <= 32767.0);
+            assert!(frame[1] < 0.0 && frame[1] >= -32767.0);
+            assert_eq!(frame[0] as i16, -(frame[1] as i16));
+        }
+        assert!(data.windows(3).step_by(2).all(|w| w[2] >= w[0]));
+        // Quiet frames are only scaled.
+        assert_eq!(master_stage((0.1, -0.2), 2.0), (0.2, -0.4));
+    }
+}
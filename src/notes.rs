@@ -29,6 +29,18 @@ impl Note {
     pub fn flat(&self) -> Self {
         self.mod_semitones(-1)
     }
+    /// Nearest MIDI note number, with A4 being 69.
+    pub fn midi(&self) -> i32 {
+        (69.0 + 12.0 * (self.0 / 440.0).log2()).round() as i32
+    }
+}
+
+/// Name of a MIDI note number, eg. C4 for 60.
+pub fn midi_name(n: i32) -> String {
+    let names: [&'static str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    format!("{}{}", names[n.rem_euclid(12) as usize], n.div_euclid(12) - 1)
 }
 
 #[allow(dead_code)]
@@ -1,10 +1,12 @@
+use std::collections::VecDeque;
 use std::io::Read;
 use std::sync::Arc;
 
 use byteorder::{BigEndian, ReadBytesExt};
+use serde::{Serialize, ser::SerializeStruct};
 
-use crate::{notes, sound, sound::{Enveloped}};
-use crate::dsp::{Signal, Interpolator};
+use crate::{notes, sound, sound::{Enveloped, Generator}, synth, wav};
+use crate::dsp::{self, Signal, Interpolator};
 
 #[derive(Debug)]
 pub enum Error {
@@ -24,7 +26,9 @@ impl From<std::io::Error> for Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug)]
+/// Serializes to the module's structure: its song, sample headers and
+/// decoded patterns, but none of the sample data.
+#[derive(Debug, Serialize)]
 pub struct Module {
     pub title: String,
 
@@ -32,65 +36,142 @@ pub struct Module {
 
     pub patterns: Vec<Pattern>,
 
+    /// Pattern played at each song position. Only the first `song_length`
+    /// entries are part of the song, files often have leftovers after that.
     pub program: Vec<u8>,
+    pub song_length: usize,
+
+    /// Number of channels in each pattern row.
+    pub channels: usize,
 }
 
 impl Module {
     pub fn load(path: &std::path::Path) -> Result<Self> {
-        let mut f = std::fs::File::open(path)?;
+        let mut f = std::io::BufReader::new(std::fs::File::open(path)?);
+        Self::from_reader(&mut f)
+    }
+
+    pub fn from_reader<R: Read>(f: &mut R) -> Result<Self> {
+        Self::from_reader_limited(f, MAX_MODULE_SIZE)
+    }
+
+    /// Writes the module's structure as JSON, for inspecting it with other
+    /// tools.
+    pub fn write_json<W: std::io::Write>(&self, w: &mut W) -> Result<()> {
+        serde_json::to_writer_pretty(w, self).map_err(std::io::Error::from)?;
+        Ok(())
+    }
+
+    /// Same as from_reader, but refuses modules whose headers declare more
+    /// than `limit` bytes of pattern and sample data, before anything is
+    /// allocated for them.
+    pub fn from_reader_limited<R: Read>(f: &mut R, limit: usize) -> Result<Self> {
+        // 31-sample modules have a signature right after the song positions.
+        // Older 15-sample Soundtracker modules have none, and their pattern
+        // data starts earlier, so peek at where the signature would be to
+        // tell them apart.
+        let mut header = vec![];
+        (&mut *f).take(SIGNATURE_OFFSET as u64 + 4).read_to_end(&mut header)?;
+        let channels = header.get(SIGNATURE_OFFSET..).and_then(signature_channels);
+        let signed = channels.is_some();
+        let mut channels = channels.unwrap_or(4);
+        let mk = header.get(SIGNATURE_OFFSET..) == Some(b"M.K.");
+        let flt8 = header.get(SIGNATURE_OFFSET..) == Some(b"FLT8");
+        let nsamples = if signed { 31 } else { 15 };
+        let f = &mut (&header[..]).chain(f);
 
         let mut title = vec![0u8; 20];
         f.read_exact(&mut title)?;
         let title = std::str::from_utf8(&title).or(Err(Error::ParseError("invalid title")))?.trim_end_matches(char::from(0));
 
-        let mut samples = (0..31)
+        let mut samples = (0..nsamples)
             .map(|i| {
-                Sample::parse_header(&mut f)
+                Sample::parse_header(f)
                     .map_err(|e| {
                         Error::SampleError { sample: i, inner: e.into() }
                     })
             })
             .collect::<Result<Vec<_>>>()?;
 
-        let _npos = f.read_u8()?;
+        let npos = f.read_u8()?;
+        let song_length = (npos as usize).clamp(1, 128);
         let _unused = f.read_u8()?;
 
         let mut ptable = vec![0u8; 128];
         f.read_exact(&mut ptable)?;
-        let ptable: Vec<u8> = Vec::from(ptable);
+        let mut ptable: Vec<u8> = Vec::from(ptable);
+        // Startrekker's FLT8 stores each 8-channel pattern as two 4-channel
+        // ones, and counts positions in those.
+        if flt8 {
+            ptable.iter_mut().for_each(|p| *p /= 2);
+        }
+
+        if signed {
+            let mut signature = vec![0u8; 4];
+            f.read_exact(&mut signature)?;
+        } else {
+            // Without a signature, anything could pass for a module. Check
+            // that the header at least makes sense as a Soundtracker one.
+            let valid = (1..=128).contains(&npos)
+                && ptable.iter().all(|p| (*p as usize) < 64);
+            if !valid {
+                return Err(Error::ParseError("unrecognized module format"));
+            }
+        }
+
+        let npatterns = ptable.iter().max().map(|m| *m as usize + 1).unwrap_or(0);
+        let declared = npatterns * MOD_ROWS * channels * 4 + samples.iter().map(|s| s.length * 2).sum::<usize>();
+        if declared > limit {
+            return Err(Error::ParseError("module too large"));
+        }
 
-        let mut signature = vec![0u8; 4];
-        f.read_exact(&mut signature)?;
+        // Mod's Grave (WOW) modules have 8 channels but an M.K. signature.
+        // The only way to tell them apart is that the rest of the file is
+        // exactly as long as 8-channel patterns and the samples would be.
+        let mut rest = vec![];
+        let wow = declared + npatterns * MOD_ROWS * 4 * 4;
+        if mk && npatterns > 0 && wow <= limit {
+            (&mut *f).take(wow as u64 + 1).read_to_end(&mut rest)?;
+            if rest.len() == wow {
+                channels = 8;
+            }
+        }
+        let f = &mut (&rest[..]).chain(f);
 
-        let npatterns = ptable.iter().max().unwrap() + 1;
+        // FLT8 halves are stored one after the other, channels 0-3 first.
+        let halves = if flt8 { 2 } else { 1 };
+        let width = channels / halves;
         let mut patterns: Vec<Pattern> = vec![];
         for _ in 0..npatterns {
-            let mut pattern = Pattern {
-                rows: vec![],
-            };
-
-            for _rid in 0..64 {
-                let mut row = Row {
-                    channels: vec![],
-                };
-                for _cid in 0..4 {
-                    let cell = f.read_u32::<BigEndian>()?;
-                    row.channels.push(Data(cell));
+            let mut pattern = Pattern::empty(MOD_ROWS, channels);
+            for half in 0..halves {
+                for row in pattern.rows.iter_mut() {
+                    for c in row.channels[half * width..(half + 1) * width].iter_mut() {
+                        *c = Data(f.read_u32::<BigEndian>()?);
+                    }
                 }
-                pattern.rows.push(row);
             }
             patterns.push(pattern);
         }
 
         for (i, sample) in samples.iter_mut().enumerate() {
-            let mut data: Vec<i8> = vec![];
-            for _ in 0..(sample.data.len()) {
-                let v = f.read_i8().map_err(|e| {
-                    Error::SampleError { sample: i, inner: Box::new(e.into()) }
-                })?;
-                data.push(v);
+            let mut data: Vec<u8> = vec![];
+            let length = sample.length * 2;
+            (&mut *f).take(length as u64).read_to_end(&mut data).map_err(|e| {
+                Error::SampleError { sample: i, inner: Box::new(e.into()) }
+            })?;
+            // Hacked files often declare more sample data than they
+            // actually contain. Play what is there and pad with silence.
+            if data.len() < length {
+                log::warn!("Sample {} truncated: {} of {} bytes present", i+1, data.len(), length);
+                data.resize(length, 0);
             }
-            sample.set_data(data);
+            sample.set_data(data.into_iter().map(|v| v as i8).collect());
+        }
+
+        // The rest of the player expects all 31 sample slots to exist.
+        while samples.len() < 31 {
+            samples.push(Sample::empty());
         }
 
         Ok(Self {
@@ -98,16 +179,164 @@ impl Module {
             samples: samples.into_iter().map(Arc::new).collect(),
             patterns,
             program: ptable,
+            song_length,
+            channels,
         })
     }
 }
 
-#[derive(Debug)]
+/// Rows per pattern in MOD files.
+const MOD_ROWS: usize = 64;
+
+/// Most pattern and sample data Module::from_reader accepts. Even 32-channel
+/// modules with every pattern and sample slot used in full stay well under.
+pub const MAX_MODULE_SIZE: usize = 16 << 20;
+
+/// Position of the format signature in 31-sample MOD files: after the title,
+/// sample headers, song length and song positions.
+const SIGNATURE_OFFSET: usize = 20 + 31 * 30 + 2 + 128;
+
+/// Number of channels of a MOD file with the given signature, or None if it
+/// isn't a signature this player knows.
+fn signature_channels(signature: &[u8]) -> Option<usize> {
+    let digit = |c: u8| if c.is_ascii_digit() { Some((c - b'0') as usize) } else { None };
+    let channels = match signature {
+        b"M.K." | b"M!K!" | b"M&K!" | b"FLT4" => Some(4),
+        b"FLT8" => Some(8),
+        // 2CHN, 6CHN, 8CHN...
+        [n, b'C', b'H', b'N'] => digit(*n),
+        // 10CH, 16CH, 32CH...
+        [n1, n0, b'C', b'H'] => digit(*n1).zip(digit(*n0)).map(|(n1, n0)| n1 * 10 + n0),
+        _ => None,
+    };
+    channels.filter(|c| (1..=32).contains(c))
+}
+
+/// What to look for with Module::search.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Search {
+    /// Note name as displayed in the pattern view, eg. "C#2".
+    Note(String),
+    Sample(u8),
+    /// Effect command, eg. 'A' for volume slides.
+    Effect(char),
+}
+
+impl Search {
+    fn matches(&self, c: &Data) -> bool {
+        match self {
+            Search::Note(n) => c.period() != 0 && c.snote().eq_ignore_ascii_case(n),
+            Search::Sample(s) => *s != 0 && c.sample_number() == *s,
+            Search::Effect(e) => {
+                let effect = c.effect();
+                !matches!(effect, Effect::None | Effect::Unknown { .. }) && effect.string().starts_with(e.to_ascii_uppercase())
+            },
+        }
+    }
+}
+
+/// A cell found by Module::search. `order` is the first song position
+/// playing the pattern, if any.
+#[derive(Debug, PartialEq)]
+pub struct SearchMatch {
+    pub order: Option<usize>,
+    pub pattern: usize,
+    pub row: usize,
+    pub channel: usize,
+}
+
+impl Module {
+    /// Patterns played by the song, in order.
+    pub fn positions(&self) -> &[u8] {
+        &self.program[..std::cmp::min(self.song_length, self.program.len())]
+    }
+
+    /// Finds all cells matching a query, in pattern, row and channel order.
+    pub fn search(&self, query: &Search) -> Vec<SearchMatch> {
+        let mut res = vec![];
+        for (pi, pattern) in self.patterns.iter().enumerate() {
+            let order = self.positions().iter().position(|p| *p as usize == pi);
+            for (row, r) in pattern.rows.iter().enumerate() {
+                for (channel, c) in r.channels.iter().enumerate() {
+                    if query.matches(c) {
+                        res.push(SearchMatch { order, pattern: pi, row, channel });
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct Pattern {
     pub rows: Vec<Row>,
 }
 
-#[derive(Debug)]
+/// How long a note written in a pattern sounds for.
+#[derive(Debug, PartialEq)]
+pub struct NoteLength {
+    pub row: usize,
+    pub rows: usize,
+}
+
+impl Pattern {
+    pub fn empty(num_rows: usize, channels: usize) -> Self {
+        Self {
+            rows: (0..num_rows).map(|_| Row { channels: (0..channels).map(|_| Data(0)).collect() }).collect(),
+        }
+    }
+
+    /// Number of rows in this pattern. Always 64 for MOD, other formats allow
+    /// patterns of varying length.
+    pub fn num_rows(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Returns the cells of a row, or none for a row past the end of the
+    /// pattern, which is how an empty pattern plays.
+    pub fn cells(&self, row: usize) -> &[Data] {
+        self.rows.get(row).map_or(&[], |r| &r.channels)
+    }
+
+    /// Returns the length of every note on a channel, measured in rows until
+    /// the next note or volume cut on the same channel, or until the pattern
+    /// ends (by running out of rows or by a pattern break).
+    pub fn note_lengths(&self, channel: usize) -> Vec<NoteLength> {
+        let mut end = self.num_rows();
+        for (i, row) in self.rows[..end].iter().enumerate() {
+            let brk = row.channels.iter().any(|c| matches!(c.effect(), Effect::PatternBreak { .. }));
+            if brk {
+                end = i + 1;
+                break;
+            }
+        }
+
+        let mut res: Vec<NoteLength> = vec![];
+        let mut current: Option<usize> = None;
+        for (i, row) in self.rows[..end].iter().enumerate() {
+            let c = match row.channels.get(channel) {
+                Some(c) => c,
+                None => continue,
+            };
+            let cut = matches!(c.effect(), Effect::SetVolume { volume: 0 });
+            if c.period() != 0 || cut {
+                if let Some(start) = current.take() {
+                    res.push(NoteLength { row: start, rows: i - start });
+                }
+            }
+            if c.period() != 0 && !cut {
+                current = Some(i);
+            }
+        }
+        if let Some(start) = current {
+            res.push(NoteLength { row: start, rows: end - start });
+        }
+        res
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct Row {
     pub channels: Vec<Data>,
 }
@@ -117,6 +346,12 @@ pub struct Row {
 pub struct Data(u32);
 
 impl Data {
+    /// Builds a cell from its fields, packed as in MOD files.
+    pub fn new(period: u16, sample: u8, effect: u16) -> Self {
+        let sample = sample as u32;
+        Data(((sample & 0xf0) << 24) | (((period & 0xfff) as u32) << 16) | ((sample & 0xf) << 12) | ((effect & 0xfff) as u32))
+    }
+
     pub fn sample_number(&self) -> u8 {
         let hi = (self.0 >> 28) & 0xF;
         let lo = (self.0 >> 12) & 0xF;
@@ -126,42 +361,82 @@ impl Data {
         ((self.0 >> 16) & 0xfff) as u16
     }
     pub fn snote(&self) -> String {
-        let mut period = self.period();
-        let mut oct = 1;
+        let period = self.period();
         if period == 0 {
             return "...".into()
         }
-        if period  > 856 {
-            period /= 2;
-            oct = 0;
-        } else if period < 113 {
-            period *= 8;
-            oct = 4;
-        } else if period < 226 {
-            period *= 4;
-            oct = 3;
-        } else if period < 453 {
-            period *= 2;
-            oct = 2;
-        }
-        let mul = 856.0f32 / (period as f32);
-        let hs = (mul.log(1.0594630943592953f32) + 0.5).floor() as usize;
+        let semitone = period_semitone(period);
         let notes: [&'static str; 12] = [
             "C-", "C#", "D-", "D#", "E-", "F-", "F#", "G-", "G#", "A-", "A#", "B-",
         ];
-        return format!("{}{}", notes[hs], oct+2);
+        return format!("{}{}", notes[semitone.rem_euclid(12) as usize], semitone.div_euclid(12) + 1);
     }
     pub fn effect(&self) -> Effect {
         Effect::from((self.0 & 0xfff) as u16)
     }
     pub fn note(&self) -> notes::Note {
-        let period = self.period();
-        let freq = (440.0f32 * 254.0f32) / (period as f32);
-        notes::Note::new(freq)
+        period_note(self.period())
     }
 }
 
-#[derive(Debug)]
+/// Cells serialize decoded: note name (or null), sample number, effect
+/// command as shown in the pattern view and the effect it stands for.
+impl Serialize for Data {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let effect = self.effect();
+        let mut s = serializer.serialize_struct("Data", 4)?;
+        s.serialize_field("note", &if self.period() == 0 { None } else { Some(self.snote()) })?;
+        s.serialize_field("sample", &self.sample_number())?;
+        s.serialize_field("command", &effect.string())?;
+        s.serialize_field("effect", &effect)?;
+        s.end()
+    }
+}
+
+/// ProTracker periods of C-1 through B-3, without finetune.
+const PERIOD_TABLE: [u16; 36] = [
+    856, 808, 762, 720, 678, 640, 604, 570, 538, 508, 480, 453,
+    428, 404, 381, 360, 339, 320, 302, 285, 269, 254, 240, 226,
+    214, 202, 190, 180, 170, 160, 151, 143, 135, 127, 120, 113,
+];
+
+/// Semitones from C-1 of the note closest to an Amiga period. Periods
+/// outside of the table are taken to be octaves above or below it.
+fn period_semitone(period: u16) -> i32 {
+    let mut period = period as f32;
+    let mut octave = 0;
+    while period > 880.0 {
+        period /= 2.0;
+        octave -= 1;
+    }
+    while period < 110.0 {
+        period *= 2.0;
+        octave += 1;
+    }
+    let ix = PERIOD_TABLE.iter().enumerate()
+        .min_by(|(_, a), (_, b)| (**a as f32 - period).abs().total_cmp(&(**b as f32 - period).abs()))
+        .map(|(ix, _)| ix).unwrap();
+    ix as i32 + octave * 12
+}
+
+/// Amiga period playing a note with a sample of the given finetune (as
+/// stored in its header). Notes outside of C-1 through B-3 are clamped to
+/// that range, as ProTracker can't play them.
+pub fn period_for_note(note: &notes::Note, finetune: u8) -> u16 {
+    // C-1 is MIDI note 48, A-3 at 440Hz being 69.
+    let ix = (note.midi() - 48).clamp(0, PERIOD_TABLE.len() as i32 - 1) as usize;
+    let period = PERIOD_TABLE[ix] as f32 * 2.0f32.powf(-(finetune_nibble(finetune) as f32) / 96.0);
+    period.round() as u16
+}
+
+/// Note played by an Amiga period, with A-3 (254) at 440Hz. The pitch
+/// follows the period exactly, like the Paula clock divided by it, so that
+/// periods off the table (eg. finetuned ones) play between its notes.
+fn period_note(period: u16) -> notes::Note {
+    notes::Note::new(440.0 * 254.0 / (period as f32))
+}
+
+#[derive(Debug, Serialize)]
 pub enum Effect {
     None,
     Unknown {
@@ -171,9 +446,33 @@ pub enum Effect {
         up: u8,
         down: u8,
     },
+    PortamentoUp {
+        speed: u8,
+    },
+    PortamentoDown {
+        speed: u8,
+    },
+    TonePortamento {
+        speed: u8,
+    },
+    Vibrato {
+        speed: u8,
+        depth: u8,
+    },
+    Tremolo {
+        speed: u8,
+        depth: u8,
+    },
+    /// Start the note `offset * 256` sample points into the sample.
+    SampleOffset {
+        offset: u8,
+    },
     SetVolume {
         volume: u16,
     },
+    PositionJump {
+        position: u8,
+    },
     PatternBreak {
         division: usize,
     },
@@ -183,6 +482,29 @@ pub enum Effect {
     FineVolumeSlideDown {
         down: u8,
     },
+    CoarsePanning {
+        pan: u8,
+    },
+    /// Turn the Amiga's LED low-pass filter on or off.
+    SetFilter {
+        on: bool,
+    },
+    /// Play the row's note with this finetune instead of the sample's.
+    SetFinetune {
+        value: i8,
+    },
+    /// Restart the sample every `ticks` ticks.
+    Retrigger {
+        ticks: u8,
+    },
+    /// Silence the note on tick `tick`.
+    NoteCut {
+        tick: u8,
+    },
+    /// Start the row's note on tick `tick` instead of right away.
+    NoteDelay {
+        tick: u8,
+    },
     SetTicksPerDivision {
         tpd: u16,
     },
@@ -201,19 +523,32 @@ impl Effect {
         let c = (v >> 0) & 0xf;
         let mut z = b * 16 + c;
         match a {
+            0x1 => Effect::PortamentoUp { speed: z as u8 },
+            0x2 => Effect::PortamentoDown { speed: z as u8 },
+            0x3 => Effect::TonePortamento { speed: z as u8 },
+            0x4 => Effect::Vibrato { speed: b as u8, depth: c as u8 },
+            0x7 => Effect::Tremolo { speed: b as u8, depth: c as u8 },
+            0x9 => Effect::SampleOffset { offset: z as u8 },
             0xa => Effect::VolumeSlide { up: b as u8, down: c as u8 },
+            0xb => Effect::PositionJump { position: z as u8 },
             0xc => Effect::SetVolume { volume: z, },
             0xd => Effect::PatternBreak { division: (b * 10 + c) as usize, },
             0xe => match b {
+                0x0 => Effect::SetFilter { on: c & 1 == 0, },
+                0x5 => Effect::SetFinetune { value: finetune_nibble(c as u8), },
+                0x8 => Effect::CoarsePanning { pan: c as u8, },
+                0x9 => Effect::Retrigger { ticks: c as u8, },
                 0xa => Effect::FineVolumeSlideUp { up: c as u8, },
                 0xb => Effect::FineVolumeSlideDown { down: c as u8, },
+                0xc => Effect::NoteCut { tick: c as u8, },
+                0xd => Effect::NoteDelay { tick: c as u8, },
                 _ => Effect::Unknown { val: v, },
             },
             0xf => {
                 if z == 0 {
                     z = 1;
                 }
-                if z <= 32 {
+                if z < 0x20 {
                     Effect::SetTicksPerDivision { tpd: z }
                 } else {
                     Effect::SetBeatsPerMinute { bpm: z }
@@ -226,11 +561,24 @@ impl Effect {
     pub fn string(&self) -> String {
         match self {
             Effect::None => "...".into(),
+            Effect::PortamentoUp { speed } => format!("1{:02X}", speed),
+            Effect::PortamentoDown { speed } => format!("2{:02X}", speed),
+            Effect::TonePortamento { speed } => format!("3{:02X}", speed),
+            Effect::Vibrato { speed, depth } => format!("4{:X}{:X}", speed, depth),
+            Effect::Tremolo { speed, depth } => format!("7{:X}{:X}", speed, depth),
+            Effect::SampleOffset { offset } => format!("9{:02X}", offset),
             Effect::VolumeSlide { up, down } => format!("A{:X}{:X}", up, down),
             Effect::SetVolume { volume } => format!("C{:02X}", volume ),
+            Effect::PositionJump { position } => format!("B{:02X}", position),
             Effect::PatternBreak { division } => format!("D{:02}", division),
             Effect::FineVolumeSlideUp { up } => format!("EA{:X}", up),
             Effect::FineVolumeSlideDown { down } => format!("EB{:X}", down),
+            Effect::SetFinetune { value } => format!("E5{:X}", (*value as u8) & 0xf),
+            Effect::CoarsePanning { pan } => format!("E8{:X}", pan),
+            Effect::SetFilter { on } => format!("E0{}", if *on { 0 } else { 1 }),
+            Effect::Retrigger { ticks } => format!("E9{:X}", ticks),
+            Effect::NoteCut { tick } => format!("EC{:X}", tick),
+            Effect::NoteDelay { tick } => format!("ED{:X}", tick),
             Effect::SetTicksPerDivision { tpd } => format!("F{:02X}", tpd),
             Effect::SetBeatsPerMinute { bpm } => format!("F{:02X}", bpm),
             _ => "???".into(),
@@ -238,7 +586,7 @@ impl Effect {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Sample {
     pub name: String,
     pub length: usize,
@@ -247,7 +595,13 @@ pub struct Sample {
     pub repeat_start: usize,
     pub repeat_length: usize,
 
+    #[serde(skip)]
     pub data: Vec<f32>,
+    /// Right channel of stereo samples, with the same header and length as
+    /// this one. `data` is then the left channel. Samples loaded from MOD
+    /// files are always mono.
+    #[serde(skip)]
+    pub right: Option<Arc<Sample>>,
 }
 
 impl Sample {
@@ -258,50 +612,117 @@ impl Sample {
 
         let length = reader.read_u16::<BigEndian>()? as usize;
         let finetune = reader.read_u8()?;
-        let volume = reader.read_u8()?;
+        // Volumes go up to 64, anything above that is corrupt.
+        let volume = std::cmp::min(reader.read_u8()?, 64);
         let repeat_start = reader.read_u16::<BigEndian>()? as usize;
         let repeat_length = reader.read_u16::<BigEndian>()? as usize;
         Ok(Self {
             name: name.into(),
             length, finetune, volume, repeat_start, repeat_length,
-            data: vec![0.0f32; length * 2],
+            data: vec![],
+            right: None,
         })
     }
 
+    /// An unused sample slot.
+    fn empty() -> Self {
+        Self {
+            name: String::new(),
+            length: 0,
+            finetune: 0,
+            volume: 0,
+            repeat_start: 0,
+            repeat_length: 0,
+            data: vec![],
+            right: None,
+        }
+    }
+
     fn set_data(&mut self, data: Vec<i8>) {
         let converted = data.convert::<f32>();
         self.data = converted.iter().collect();
     }
 
-    pub fn play(self: Arc<Self>, note: notes::Note, sample_rate: u32) -> SamplePlayback<Interpolator<Arc<Self>>> {
+    /// Signed finetune of the sample, in eighths of a semitone.
+    pub fn finetune(&self) -> i8 {
+        finetune_nibble(self.finetune)
+    }
+
+    pub fn play(self: Arc<Self>, note: notes::Note, sample_rate: u32, quality: dsp::Quality, loops: LoopRule) -> SamplePlayback<Interpolator<Arc<Self>>> {
+        let finetune = self.finetune();
+        self.play_finetuned(note, sample_rate, finetune, quality, loops)
+    }
+
+    /// Returns the start and length of the sample's loop in sample points,
+    /// if it loops. Loops reaching past the end of the sample data are moved
+    /// back to end with it, and shortened if the whole sample is shorter.
+    pub fn loop_region(&self, loops: LoopRule) -> Option<(usize, usize)> {
+        if !loops.loops(self.repeat_length) {
+            return None;
+        }
+        let end = std::cmp::min((self.repeat_start + self.repeat_length) * 2, self.data.len());
+        let length = std::cmp::min(self.repeat_length * 2, end);
+        if length == 0 {
+            return None;
+        }
+        Some((end - length, length))
+    }
+
+    /// Plays the sample through once, ignoring its loop, eg. to audition it.
+    pub fn play_once(self: Arc<Self>, note: notes::Note, sample_rate: u32, quality: dsp::Quality) -> sound::OneShot<Interpolator<Arc<Self>>> {
+        let scale = self.scale(note, sample_rate, self.finetune());
+        let length = ((self.data.len() as f32) * scale) as usize;
+        sound::OneShot::new(self.resample(length, quality))
+    }
+
+    /// Ratio of output samples to sample points when playing a note.
+    fn scale(&self, note: notes::Note, sample_rate: u32, finetune: i8) -> f32 {
         let diff = notes::A4.freq() / note.freq();
         let from = (7093789.2f32 / (4.0f32 * 127.0f32)) / diff;
-        let to = sample_rate as f32;
-        let scale = to / from;
+        let from = from * 2.0f32.powf((finetune as f32) / 96.0);
+        sample_rate as f32 / from
+    }
+
+    /// Same as play, but with the given finetune instead of the sample's own.
+    pub fn play_finetuned(self: Arc<Self>, note: notes::Note, sample_rate: u32, finetune: i8, quality: dsp::Quality, loops: LoopRule) -> SamplePlayback<Interpolator<Arc<Self>>> {
+        let scale = self.scale(note, sample_rate, finetune);
         let length = (self.data.len() as f32) * scale;
         let length = length as usize;
 
         let mut repeat = None;
-        if self.repeat_length > 1 {
-            let r_start = (self.repeat_start as f32) * 2.0 * scale;
-            let r_start = std::cmp::min(r_start as usize, length);
-            let r_length = (self.repeat_length as f32) * 2.0 * scale;
-            let r_length = std::cmp::min(r_length as usize, length);
-            repeat = Some((r_start, r_length))
+        if let Some((start, len)) = self.loop_region(loops) {
+            // Both ends are scaled and clamped, so that the loop stays within
+            // the resampled signal regardless of rounding.
+            let to_signal = |points: usize| std::cmp::min(((points as f32) * scale).round() as usize, length);
+            let r_end = to_signal(start + len);
+            let r_start = std::cmp::min(to_signal(start), r_end);
+            if r_end > r_start {
+                repeat = Some((r_start, r_end - r_start))
+            }
         }
 
-
-        let resampled = self.clone().resample(length as usize);
+        let resampled = self.clone().resample(length as usize, quality);
+        let right = self.right.clone().map(|r| r.resample(length, quality));
 
         SamplePlayback {
             signal: resampled,
+            right,
             volume: self.volume,
             repeat,
             state: SamplePlaybackState::Stopped,
+            volume_offset: 0,
+            rate: 1.0,
+            glide: None,
+            scale,
         }
     }
 }
 
+/// Decodes a finetune nibble: 0..7 are positive, 8..15 are -8..-1.
+fn finetune_nibble(v: u8) -> i8 {
+    (((v & 0xf) << 4) as i8) >> 4
+}
+
 impl Signal for Arc<Sample> {
     type Sample = f32;
     fn length(&self) -> usize {
@@ -316,30 +737,94 @@ impl Signal for Arc<Sample> {
 enum SamplePlaybackState {
     Stopped,
     First {
-        ix: usize,
+        ix: f32,
     },
     Repeating {
-        ix: usize,
+        ix: f32,
     },
 }
 
 pub struct SamplePlayback<S: Signal> {
     signal: S,
+    /// Right channel of stereo samples, played in lockstep with `signal`.
+    right: Option<S>,
     repeat: Option<(usize, usize)>,
     state: SamplePlaybackState,
     volume: u8,
+    /// Temporary change to `volume`, eg. from tremolo. The sum is clamped
+    /// to 0..=64 when playing.
+    volume_offset: i16,
+    /// Playback speed relative to the rate the signal was resampled for.
+    /// Playback position is kept fractional so that this can change while
+    /// the sample is playing.
+    rate: f32,
+    /// Ongoing glide: the rate being slid towards, the change per sample and
+    /// the number of samples left.
+    glide: Option<(f32, f32, usize)>,
+    /// Length of the played signal relative to the original sample data.
+    scale: f32,
 }
 
 impl <S: Signal> SamplePlayback<S> {
+    /// Starts playback `offset` sample points into the original sample data,
+    /// clamped to the last sample point.
+    pub fn trigger_start_at(&mut self, offset: usize) {
+        let ix = (offset as f32) * self.scale;
+        let last = self.signal.length().saturating_sub(1) as f32;
+        self.state = SamplePlaybackState::First { ix: ix.clamp(2.0, last.max(2.0)) };
+    }
+
+    /// Starts playback at `from` times the current rate and slides linearly
+    /// back to the current rate over the given number of samples.
+    pub fn glide(&mut self, from: f32, samples: usize) {
+        if samples == 0 {
+            return;
+        }
+        let target = self.rate;
+        self.rate = target * from;
+        self.glide = Some((target, (target - self.rate) / (samples as f32), samples));
+    }
+    /// Makes the sample play through to its end instead of looping, eg.
+    /// for one-shots which aren't stopped by key up.
+    pub fn stop_looping(&mut self) {
+        self.repeat = None;
+    }
+    fn _glide_tick(&mut self) {
+        if let Some((target, step, left)) = self.glide {
+            if left <= 1 {
+                self.rate = target;
+                self.glide = None;
+            } else {
+                self.rate += step;
+                self.glide = Some((target, step, left - 1));
+            }
+        }
+    }
     fn _length(&self) -> usize {
         if let Some((st, le)) = self.repeat {
             return st + le;
         }
         self.signal.length()
     }
+    /// Replaces the played signal with another one, carrying on from the
+    /// current playback position and rate.
+    fn swap(&mut self, other: Self) {
+        self.signal = other.signal;
+        self.right = other.right;
+        self.repeat = other.repeat;
+        self.volume = other.volume;
+        self.scale = other.scale;
+    }
+    /// Moves playback from past the end of the sample back into its loop,
+    /// keeping the fractional position, or stops it if it doesn't loop.
     fn _restart(&mut self) {
-        if let Some((st, _)) = self.repeat {
-            self.state = SamplePlaybackState::Repeating { ix: st };
+        if let Some((st, le)) = self.repeat {
+            let ix = match self.state {
+                SamplePlaybackState::First { ix } | SamplePlaybackState::Repeating { ix } => ix - (le as f32),
+                SamplePlaybackState::Stopped => st as f32,
+            };
+            let ix = if ix >= (st as f32) && ix < ((st + le) as f32) { ix } else { st as f32 };
+            self.state = SamplePlaybackState::Repeating { ix };
         } else {
             self.state = SamplePlaybackState::Stopped;
         }
@@ -347,46 +832,81 @@ impl <S: Signal> SamplePlayback<S> {
     fn _forward(&mut self) {
         match self.state {
             SamplePlaybackState::Stopped => (),
-            SamplePlaybackState::First { ix } => self.state = SamplePlaybackState::First { ix: ix + 1 },
-            SamplePlaybackState::Repeating { ix } => self.state = SamplePlaybackState::Repeating { ix: ix + 1 },
+            SamplePlaybackState::First { ix } => self.state = SamplePlaybackState::First { ix: ix + self.rate },
+            SamplePlaybackState::Repeating { ix } => self.state = SamplePlaybackState::Repeating { ix: ix + self.rate },
         }
     }
     fn _ix(&self) -> usize {
         match self.state {
             SamplePlaybackState::Stopped => 0,
-            SamplePlaybackState::First { ix } => ix,
-            SamplePlaybackState::Repeating { ix } => ix,
+            SamplePlaybackState::First { ix } => ix as usize,
+            SamplePlaybackState::Repeating { ix } => ix as usize,
         }
     }
 }
 
-impl <S: Signal<Sample=f32>> sound::Generator for SamplePlayback<S> {
-    fn next(&mut self) -> f32 {
+impl <S: Signal<Sample=f32>> SamplePlayback<S> {
+    /// Plays the next left and right sample values. Mono samples play the
+    /// same value on both sides.
+    pub fn next_stereo(&mut self) -> (f32, f32) {
         if let SamplePlaybackState::Stopped = self.state {
-            return 0.0;
+            return (0.0, 0.0);
         }
 
-        let ix = self._ix();
+        let mut ix = self._ix();
         let length = self._length();
         if ix >= length {
             self._restart();
+            if let SamplePlaybackState::Stopped = self.state {
+                return (0.0, 0.0);
+            }
+            ix = self._ix();
         }
-        let val = self.signal.get(ix);
+        let left = self.signal.get(ix);
+        let right = self.right.as_ref().map(|r| r.get(ix)).unwrap_or(left);
         self._forward();
-        let volume = (self.volume as f32)/64.0;
+        self._glide_tick();
+        let volume = ((self.volume as i16) + self.volume_offset).clamp(0, 64);
+        let volume = (volume as f32)/64.0;
+
+        (left * volume, right * volume)
+    }
+}
 
-        val * volume
+impl <S: Signal<Sample=f32>> sound::Generator for SamplePlayback<S> {
+    fn next(&mut self) -> f32 {
+        let (left, right) = self.next_stereo();
+        (left + right) / 2.0
     }
 }
 
 impl <S: Signal<Sample=f32>> sound::Enveloped for SamplePlayback<S> {
     fn trigger_start(&mut self) {
-        self.state = SamplePlaybackState::First { ix: 2 };
+        self.state = SamplePlaybackState::First { ix: 2.0 };
     }
     fn trigger_end(&mut self) {
         self.state = SamplePlaybackState::Stopped;
     }
+    fn position(&self) -> Option<f32> {
+        match self.state {
+            SamplePlaybackState::Stopped => None,
+            _ => Some((self._ix() as f32) / (std::cmp::max(self.signal.length(), 1) as f32)),
+        }
+    }
+    fn is_finished(&self) -> bool {
+        matches!(self.state, SamplePlaybackState::Stopped)
+    }
+
+}
 
+/// A note about to start on a channel: the playback of its sample, which
+/// sample and note it is, and the period it was given in the pattern (or 0 to
+/// keep the channel's current one).
+struct PendingNote {
+    generator: SamplePlayback<Interpolator<Arc<Sample>>>,
+    sample: usize,
+    note: notes::Note,
+    period: u16,
 }
 
 struct Channel {
@@ -394,26 +914,387 @@ struct Channel {
     last_sample: Option<usize>,
     last_note: Option<notes::Note>,
     volume_slide: Option<i8>,
+    /// Current Amiga period, possibly slid away from the one the generator
+    /// was started at (base_period).
+    period: u16,
+    base_period: u16,
+    /// Period being slid towards by tone portamento, and the slide speed for
+    /// the current row.
+    porta_target: Option<u16>,
+    porta_speed: Option<u8>,
+    /// Period change per tick from portamento up/down on the current row.
+    period_slide: Option<i16>,
+    /// Vibrato speed and depth for the current row, and the position in the
+    /// vibrato waveform (0..64).
+    vibrato: Option<(u8, u8)>,
+    vibrato_pos: u8,
+    /// Same as above, for tremolo.
+    tremolo: Option<(u8, u8)>,
+    tremolo_pos: u8,
+    /// Last nonzero parameters of effects for which a zero parameter means
+    /// 'continue with the previous value'.
+    memory_volume_slide: Option<i8>,
+    memory_porta_speed: Option<u8>,
+    memory_vibrato: (u8, u8),
+    memory_tremolo: (u8, u8),
+    memory_offset: u8,
+    /// Same as above, for slides only remembered by some players: portamento
+    /// up and down, and fine volume slides up and down. See PlayerCompat.
+    memory_porta: (u8, u8),
+    memory_fine_volume: (u8, u8),
+    /// Retrigger interval and note cut tick for the current row.
+    retrigger: Option<u8>,
+    note_cut: Option<u8>,
+    /// Note held back by a note delay, and the tick on which to start it.
+    delayed_note: Option<(u8, PendingNote)>,
+    /// Pan set by E8x, in place of the player's pan for the channel until
+    /// the song starts over.
+    pan: Option<f32>,
+    output: (f32, f32),
+}
+
+/// First half of ProTracker's vibrato sine, the second half is the same
+/// but negated.
+const VIBRATO_SINE: [i16; 32] = [
+    0, 24, 49, 74, 97, 120, 141, 161, 180, 197, 212, 224, 235, 244, 250, 253,
+    255, 253, 250, 244, 235, 224, 212, 197, 180, 161, 141, 120, 97, 74, 49, 24,
+];
+
+/// Value of the vibrato sine at position `pos` (0..64), scaled by
+/// depth/divisor.
+fn vibrato_sine(pos: u8, depth: u8, divisor: i16) -> i16 {
+    let pos = (pos & 63) as usize;
+    let v = VIBRATO_SINE[pos & 31] * (depth as i16) / divisor;
+    if pos < 32 { v } else { -v }
+}
+
+/// Period offset of a vibrato at position `pos` (0..64) of its waveform.
+fn vibrato_offset(pos: u8, depth: u8) -> i16 {
+    vibrato_sine(pos, depth, 128)
+}
+
+/// Volume offset of a tremolo at position `pos` (0..64) of its waveform.
+/// Tremolo swings twice as far as vibrato for the same depth.
+fn tremolo_offset(pos: u8, depth: u8) -> i16 {
+    vibrato_sine(pos, depth, 64)
 }
 
+/// Range of periods that portamento up/down can slide to, B-3 to C-1.
+const MIN_PERIOD: u16 = 113;
+const MAX_PERIOD: u16 = 856;
+
 impl Channel {
+    /// Starts playing a note, replacing whatever the channel was playing.
+    fn _trigger(&mut self, n: PendingNote) {
+        self.generator = Some(n.generator);
+        self.last_sample = Some(n.sample);
+        self.last_note = Some(n.note);
+        if n.period != 0 {
+            self.period = n.period;
+        }
+        self.base_period = self.period;
+        self.porta_target = None;
+        self.vibrato_pos = 0;
+        self.tremolo_pos = 0;
+    }
+
+    /// Slides the period by `delta`, keeping it within the range of notes
+    /// ProTracker can play.
+    fn _slide_tick(&mut self, delta: i16) {
+        if self.period == 0 {
+            return;
+        }
+        let period = (self.period as i32 + delta as i32).clamp(MIN_PERIOD as i32, MAX_PERIOD as i32);
+        self.period = period as u16;
+        self._retune();
+    }
+
+    /// Slides the period by up to `speed` towards the portamento target.
+    fn _porta_tick(&mut self, speed: u8) {
+        let target = match self.porta_target {
+            Some(t) => t,
+            None => return,
+        };
+        let speed = speed as u16;
+        self.period = if self.period < target {
+            std::cmp::min(self.period + speed, target)
+        } else {
+            std::cmp::max(self.period.saturating_sub(speed), target)
+        };
+        if self.period == target {
+            self.porta_target = None;
+        }
+        self._retune();
+    }
+
+    /// Updates the generator's playback rate to match the current period.
+    fn _retune(&mut self) {
+        self._retune_offset(0);
+    }
+
+    /// Updates the generator's playback rate to match the current period
+    /// shifted by `offset`, without changing the period itself.
+    fn _retune_offset(&mut self, offset: i16) {
+        if let Some(g) = &mut self.generator {
+            let period = (self.period as i32) + (offset as i32);
+            if period > 0 && self.base_period != 0 {
+                g.rate = (self.base_period as f32) / (period as f32);
+            }
+        }
+    }
+
+    fn _tremolo_tick(&mut self, speed: u8, depth: u8) {
+        self.tremolo_pos = (self.tremolo_pos + speed) & 63;
+        if let Some(g) = &mut self.generator {
+            g.volume_offset = tremolo_offset(self.tremolo_pos, depth);
+        }
+    }
+
+    fn _vibrato_tick(&mut self, speed: u8, depth: u8) {
+        self.vibrato_pos = (self.vibrato_pos + speed) & 63;
+        self._retune_offset(vibrato_offset(self.vibrato_pos, depth));
+    }
+
     fn new() -> Self {
         Self {
             generator: None,
             last_sample: None,
             last_note: None,
             volume_slide: None,
+            period: 0,
+            base_period: 0,
+            porta_target: None,
+            porta_speed: None,
+            period_slide: None,
+            vibrato: None,
+            vibrato_pos: 0,
+            tremolo: None,
+            tremolo_pos: 0,
+            memory_volume_slide: None,
+            memory_porta_speed: None,
+            memory_vibrato: (0, 0),
+            memory_tremolo: (0, 0),
+            memory_offset: 0,
+            memory_porta: (0, 0),
+            memory_fine_volume: (0, 0),
+            retrigger: None,
+            note_cut: None,
+            delayed_note: None,
+            pan: None,
+            output: (0.0, 0.0),
+        }
+    }
+}
+
+/// Pan of channel `ix` on the Amiga, where channels go hard left, right,
+/// right, left, and so on.
+fn amiga_pan(ix: usize) -> f32 {
+    match ix % 4 {
+        0 | 3 => -1.0,
+        _ => 1.0,
+    }
+}
+
+/// Playback gain picked per module when it's loaded, so that modules play
+/// at about the same loudness.
+#[derive(Clone, Copy)]
+pub struct AutoGain {
+    pub enabled: bool,
+    /// Loudness to bring modules to, as RMS in dBFS.
+    pub target_db: f32,
+    /// Highest peak level allowed after applying the gain, in dBFS. Keeps
+    /// headroom for modules with a lot of dynamics.
+    pub ceiling_db: f32,
+    /// How much of the start of the module is rendered to measure it.
+    pub seconds: f32,
+}
+
+impl AutoGain {
+    /// Gain is never raised beyond this, so that near-silent intros don't
+    /// turn into noise.
+    const MAX_GAIN: f32 = 16.0;
+
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            target_db: -18.0,
+            ceiling_db: -1.0,
+            seconds: 30.0,
+        }
+    }
+
+    /// Measures a module with an offline stereo render and returns the gain to
+    /// play it at, or 1.0 if disabled. Both the peak and the RMS are taken
+    /// from the louder side, as that's what clips first.
+    pub fn gain(&self, module: &Arc<Module>, sample_rate: f32) -> f32 {
+        if !self.enabled {
+            return 1.0;
+        }
+        let length = (self.seconds * sample_rate) as usize;
+        let mut player = Player::new(module, sample_rate);
+        player.playing = true;
+        let (mut peak, mut sums) = (0.0f32, (0.0f32, 0.0f32));
+        for _ in 0..length {
+            let (l, r) = player.next_stereo();
+            peak = peak.max(l.abs()).max(r.abs());
+            sums = (sums.0 + l * l, sums.1 + r * r);
+        }
+        let rms = (sums.0.max(sums.1) / std::cmp::max(length, 1) as f32).sqrt();
+        if peak == 0.0 || rms == 0.0 {
+            return 1.0;
+        }
+        let db = |db: f32| 10.0f32.powf(db / 20.0);
+        let gain = (db(self.target_db) / rms).min(db(self.ceiling_db) / peak);
+        gain.min(Self::MAX_GAIN)
+    }
+}
+
+/// How the E8x command is interpreted. ProTracker leaves it unused, while
+/// some other trackers use it for coarse panning.
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub enum E8xMode {
+    Ignore,
+    Panning,
+}
+
+/// Which family of players to mimic for the effects listed below, which
+/// differ between trackers. Everything else, including volume clamping,
+/// plays the same in both.
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub enum PlayerCompat {
+    /// E8x is ignored, and 100, 200, EA0 and EB0 do nothing.
+    ProTracker,
+    /// E8x is coarse panning, and 100, 200, EA0 and EB0 repeat the last
+    /// nonzero slide of the same effect.
+    FastTracker,
+}
+
+impl PlayerCompat {
+    fn e8x(&self) -> E8xMode {
+        match self {
+            PlayerCompat::ProTracker => E8xMode::Ignore,
+            PlayerCompat::FastTracker => E8xMode::Panning,
+        }
+    }
+
+    /// Returns the parameter to slide by, for a slide effect whose last
+    /// nonzero parameter is kept in `memory`.
+    fn slide(&self, param: u8, memory: &mut u8) -> u8 {
+        if param != 0 {
+            *memory = param;
+        }
+        match self {
+            PlayerCompat::ProTracker => param,
+            PlayerCompat::FastTracker => *memory,
+        }
+    }
+}
+
+/// What a cell with a sample number but no note does. ProTracker keeps the
+/// current note playing and only picks up the sample's volume (and the sample
+/// itself for the next note), while some trackers retrigger the last note
+/// with the new sample.
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub enum SampleOnlyMode {
+    Update,
+    Retrigger,
+}
+
+/// Which repeat lengths make a sample loop. Sample headers of one-shot
+/// samples have a repeat length of 1 word in ProTracker, but 0 in some older
+/// trackers, which loop samples with a repeat length of 1.
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub enum LoopRule {
+    /// Loop if the repeat length is over 1 word.
+    AboveOne,
+    /// Loop if the repeat length is over 0 words.
+    AboveZero,
+}
+
+impl LoopRule {
+    fn loops(&self, repeat_length: usize) -> bool {
+        match self {
+            LoopRule::AboveOne => repeat_length > 1,
+            LoopRule::AboveZero => repeat_length > 0,
         }
     }
 }
 
+/// What a module channel is playing, as reported by `Player::channel_states`.
+#[derive(Clone,Copy)]
+pub struct ChannelState {
+    /// Whether a sample is sounding on the channel.
+    pub active: bool,
+    /// Note nearest to the channel's current period, including slides and
+    /// vibrato, or None if nothing was played on it yet.
+    pub note: Option<notes::Note>,
+    /// Current volume, 0 to 64, including tremolo.
+    pub volume: u8,
+}
+
+/// One effect applied to a channel, as recorded by the effect log.
+#[derive(Debug,Clone,PartialEq)]
+pub struct EffectLogEntry {
+    pub program: usize,
+    pub row: usize,
+    pub tick: usize,
+    pub channel: usize,
+    /// Effect command and parameters, as shown in the pattern view.
+    pub effect: String,
+    /// Channel volume and Amiga period after the effect was applied.
+    pub volume: u8,
+    pub period: u16,
+}
+
+/// Most rows simulated when seeking or estimating the duration, so that songs
+/// which never loop back (eg. jumping forward and breaking into the same
+/// position) can't hang the player. Enough for 128 positions of 64 rows, four
+/// times over.
+const MAX_SIMULATED_ROWS: usize = 128 * 64 * 4;
+
+/// Cutoff of the Amiga's LED filter, in Hz.
+const LED_FILTER_CUTOFF: f32 = 4000.0;
+
+/// Number of entries kept in the effect log before the oldest are dropped.
+const EFFECT_LOG_LENGTH: usize = 4096;
+
 pub struct Player {
     pub playing: bool,
+    compat: PlayerCompat,
+    pub e8x: E8xMode,
+    pub sample_only: SampleOnlyMode,
+    /// Interpolation used to play back samples at the pitch of their notes.
+    pub quality: dsp::Quality,
+    pub loops: LoopRule,
+    /// Semitones by which every note of the module is shifted when it
+    /// starts playing.
+    pub transpose: i32,
+    /// Eighths of a semitone added to every sample's finetune, for tuning
+    /// the whole module.
+    pub finetune: i32,
+    /// Linear gain applied to the mixed channels, eg. to even out loudness
+    /// between modules. The metronome isn't affected.
+    pub gain: f32,
+    /// Playback speed multiplier, eg. 0.5 for practicing at half speed.
+    /// Only row timing is scaled, so pitch stays the same.
+    pub speed: f32,
+    /// Whether to emulate the Amiga's LED filter, switched by E0x.
+    pub led_filter: bool,
+    /// Whether the module has switched the LED filter on, and the filter
+    /// for each side of the mix.
+    filter_on: bool,
+    filters: [dsp::OnePole; 2],
     pub module: Arc<Module>,
     pub program: usize,
     pub pattern: usize,
     pub row: usize,
     tick: usize,
+    /// Time from the start of the song to the start of the current row, in
+    /// seconds.
+    elapsed: f32,
+    /// Samples of the module played since the start of the song, or since
+    /// the last jump.
+    samples_played: u64,
     native_tpd: u16,
     native_bpm: u16,
 
@@ -423,19 +1304,85 @@ pub struct Player {
     sample_rate: u32,
 
     incoming_break: Option<usize>,
+    incoming_jump: Option<usize>,
+
+    /// Whether to start over once the song ends, either by running past its
+    /// last position or by jumping backwards with Bxx. If not set, the
+    /// player stops and sets `finished` instead.
+    pub looped: bool,
+    /// Set when the song ended without looping. Cleared by `play`.
+    pub finished: bool,
+    /// Samples left to play before pausing again, when playing a short
+    /// grain for scrubbing.
+    grain_left: Option<usize>,
+    /// First and last song position and row of a range to keep playing over
+    /// and over, eg. for practicing a part. Only used if both are set and
+    /// the start isn't past the end.
+    pub loop_start: Option<(usize, usize)>,
+    pub loop_end: Option<(usize, usize)>,
 
     channels: Vec<Channel>,
+    /// Module channels left out of the mix. Notes on them are stopped as soon
+    /// as they start.
+    pub muted: Vec<bool>,
+    /// Stereo position of each module channel, -1.0 (left) through 1.0
+    /// (right). Defaults to the Amiga's hard left, right, right, left.
+    pub pans: Vec<f32>,
+    /// Which module channel plays into each output position.
+    pub channel_map: Vec<usize>,
+    /// Last left and right sample produced at each output position.
+    outputs: Vec<(f32, f32)>,
+    /// Recent peak output level of each module channel, for meters.
+    peaks: Vec<dsp::PeakMeter>,
+
+    /// Number of divisions of metronome clicks played by `play` before the
+    /// module starts.
+    pub count_in: usize,
+    /// Divisions of count-in left, and samples played of the current one.
+    count_in_left: usize,
+    count_in_sample: usize,
+    metronome: synth::Click,
+    /// Rows on which the metronome clicks during playback, counted from the
+    /// start of each pattern and repeating every `click_grid.len()` rows.
+    /// Empty to keep the metronome silent.
+    pub click_grid: Vec<bool>,
+    /// Set when a row was loaded, so that the next sample played can click
+    /// for it.
+    click_due: bool,
+
+    /// Whether to record every effect applied into `effect_log`, for
+    /// comparing playback against other players.
+    pub log_effects: bool,
+    pub effect_log: VecDeque<EffectLogEntry>,
+
+    /// Set while rows are simulated by seeking or estimating the duration,
+    /// so that effects are still applied but nothing is logged or clicked.
+    silent: bool,
 }
 
 impl Player {
     pub fn new(module: &Arc<Module>, sample_rate: f32) -> Self {
         let mut res = Self {
             playing: false,
+            compat: PlayerCompat::ProTracker,
+            e8x: E8xMode::Ignore,
+            sample_only: SampleOnlyMode::Update,
+            quality: dsp::Quality::Normal,
+            loops: LoopRule::AboveOne,
+            transpose: 0,
+            finetune: 0,
+            gain: 1.0,
+            speed: 1.0,
+            led_filter: true,
+            filter_on: false,
+            filters: [dsp::OnePole::low_pass(sample_rate, LED_FILTER_CUTOFF); 2],
             module: module.clone(),
             program: 0,
             pattern: 0,
             row: 0,
             tick: 0,
+            elapsed: 0.0,
+            samples_played: 0,
             native_tpd: 6,
             native_bpm: 125,
             division_left: 0,
@@ -443,92 +1390,567 @@ impl Player {
             sample_rate: sample_rate as u32,
 
             incoming_break: None,
-
-            channels: (0..4).map(|_| Channel::new()).collect(),
+            incoming_jump: None,
+
+            looped: true,
+            finished: false,
+            grain_left: None,
+            loop_start: None,
+            loop_end: None,
+
+            channels: (0..module.channels).map(|_| Channel::new()).collect(),
+            muted: vec![false; module.channels],
+            pans: (0..module.channels).map(amiga_pan).collect(),
+            channel_map: (0..module.channels).collect(),
+            outputs: vec![(0.0, 0.0); module.channels],
+            peaks: vec![dsp::PeakMeter::new(sample_rate as u32, 0.3); module.channels],
+
+            count_in: 0,
+            count_in_left: 0,
+            count_in_sample: 0,
+            metronome: synth::Click::new(sample_rate as u32, 1000.0),
+            click_grid: vec![],
+            click_due: false,
+
+            log_effects: false,
+            effect_log: VecDeque::new(),
+
+            silent: false,
         };
-        res._division_left_reset();
-        res._tick_left_reset();
-        res._load_row();
+        res._reset();
         res
     }
 
-    fn _dpm(&self) -> f32 {
-        (24.0 * (self.native_bpm as f32)) / (self.native_tpd as f32)
+    /// Starts playback, preceded by the count-in if one is set.
+    pub fn play(&mut self) {
+        if self.finished {
+            self._reset();
+        }
+        self.playing = true;
+        self.grain_left = None;
+        self.count_in_left = self.count_in;
+        self.count_in_sample = 0;
     }
 
-    fn _tick_left_reset(&mut self) {
-        let in_division = (60.0 / self._dpm()) * (self.sample_rate as f32);
-        let in_tick = in_division / (self.native_tpd as f32);
-        self.tick_left = in_tick as usize;
+    /// Plays `seconds` of the song from the current position, without the
+    /// count-in, then pauses.
+    pub fn play_grain(&mut self, seconds: f32) {
+        self.playing = true;
+        self.count_in_left = 0;
+        self.grain_left = Some((seconds * self.sample_rate as f32) as usize);
     }
 
-    fn _division_left_reset(&mut self) {
-        self.division_left = ((60.0 / self._dpm()) * (self.sample_rate as f32)) as usize;
+    /// Carries on playing from the current position, without the count-in,
+    /// eg. after scrubbing.
+    pub fn resume(&mut self) {
+        self.playing = true;
+        self.grain_left = None;
+        self.count_in_left = 0;
     }
 
-    fn _load_row(&mut self) {
-        for (i, c) in self.module.patterns[self.pattern].rows[self.row].channels.iter().enumerate() {
-            if c.period() == 0 && c.sample_number() == 0 {
-                continue
-            }
-            let note = if c.period() == 0 {
-                match self.channels[i].last_note {
-                    Some(n) => n,
-                    None => continue,
-                }
-            } else {
-                c.note()
-            };
+    /// Stops playback and moves back to the beginning of the song.
+    pub fn stop(&mut self) {
+        self.playing = false;
+        self._reset();
+    }
 
-            let mut sample = c.sample_number() as usize;
-            if sample == 0 {
-                sample = self.channels[i].last_sample.unwrap_or(0);
-            }
-            if sample == 0 {
-                continue
+    /// Returns the number of count-in divisions left before the module
+    /// starts.
+    pub fn counting_in(&self) -> usize {
+        self.count_in_left
+    }
+
+    /// Plays one sample of the count-in, clicking at the start of every
+    /// division.
+    fn _count_in_next(&mut self) -> f32 {
+        if self.count_in_sample == 0 {
+            self.metronome.trigger();
+        }
+        self.count_in_sample += 1;
+        if self.count_in_sample >= self._division_samples() {
+            self.count_in_sample = 0;
+            self.count_in_left -= 1;
+        }
+        self.metronome.next() * 0.3
+    }
+
+    /// Moves back to the beginning of the song with default tempo and silent
+    /// channels.
+    fn _reset(&mut self) {
+        self.program = 0;
+        self.pattern = self.module.positions()[0] as usize;
+        self.row = 0;
+        self.elapsed = 0.0;
+        self.samples_played = 0;
+        self.native_tpd = 6;
+        self.native_bpm = 125;
+        self.incoming_break = None;
+        self.incoming_jump = None;
+        self.finished = false;
+        self.filter_on = false;
+        for c in self.channels.iter_mut() {
+            *c = Channel::new();
+        }
+        self._division_left_reset();
+        self._tick_left_reset();
+        self._load_row();
+    }
+
+    /// Moves playback to `t` seconds into the song. Every row up to that
+    /// point is simulated from the start, so tempo changes and channel state
+    /// are accounted for. Times past the end of the song leave the player at
+    /// the loop point.
+    pub fn seek_seconds(&mut self, t: f32) {
+        self._reset();
+        self._simulate_silent(|p| p.elapsed + 60.0 / p._dpm() > t);
+        self._division_left_reset();
+        self._tick_left_reset();
+        self.samples_played = (self.elapsed * self.sample_rate as f32) as u64;
+    }
+
+    /// Moves playback to a row of a song position, simulating the song from
+    /// the start like seek_seconds. Positions that are never reached leave
+    /// the player at the loop point.
+    pub fn seek_position(&mut self, program: usize, row: usize) {
+        self._reset();
+        self._simulate_silent(|p| (p.program, p.row) == (program, row));
+        self._division_left_reset();
+        self._tick_left_reset();
+        self.samples_played = (self.elapsed * self.sample_rate as f32) as u64;
+    }
+
+    /// Jumps straight to a row of a song position, without simulating the
+    /// song before it like seek_position does: tempo and channel effect
+    /// memory carry over from where playback was. Notes still playing are
+    /// stopped, so that they don't ring out over the new position. The song
+    /// time is still that of the row as reached by playing the song.
+    pub fn seek(&mut self, program: usize, row: usize) {
+        self.program = std::cmp::min(program, self.module.song_length - 1);
+        self.pattern = self.module.positions()[self.program] as usize;
+        self.row = std::cmp::min(row, self.module.patterns[self.pattern].num_rows().saturating_sub(1));
+        self.incoming_break = None;
+        self.incoming_jump = None;
+        self.finished = false;
+        self.elapsed = self._time_at(self.program, self.row);
+        self.samples_played = (self.elapsed * self.sample_rate as f32) as u64;
+        for c in self.channels.iter_mut() {
+            c.generator = None;
+        }
+        self._division_left_reset();
+        self._tick_left_reset();
+        self._load_row();
+    }
+
+    /// Time from the start of the song to a row of a song position, in
+    /// seconds, found by simulating the song like seek_position does.
+    fn _time_at(&self, program: usize, row: usize) -> f32 {
+        let mut player = Player::new(&self.module, self.sample_rate as f32);
+        player.set_compat(self.compat);
+        player.speed = self.speed;
+        player._reset();
+        player._simulate_silent(|p| (p.program, p.row) == (program, row));
+        player.elapsed
+    }
+
+    /// Estimates how long the song plays for until it loops, in seconds, by
+    /// running through it from the start with the player's compatibility
+    /// settings. Jumps and breaks are followed, tempo changes accounted for.
+    pub fn duration_estimate(&self) -> f32 {
+        let mut player = Player::new(&self.module, self.sample_rate as f32);
+        player.set_compat(self.compat);
+        player.speed = self.speed;
+        player._reset();
+        let mut end = 0.0;
+        player._simulate_silent(|p| {
+            end = p.elapsed + 60.0 / p._dpm();
+            false
+        });
+        end
+    }
+
+    /// Returns the time from the start of the song to the current row, in
+    /// seconds.
+    pub fn position_seconds(&self) -> f32 {
+        self.elapsed
+    }
+
+    /// Returns how long the module has been playing for, in seconds, counted
+    /// in samples played since the start of the song. Seeks move it to the
+    /// song time of the row sought to. Unlike position_seconds, this moves
+    /// on within rows.
+    pub fn elapsed_seconds(&self) -> f32 {
+        (self.samples_played as f64 / self.sample_rate as f64) as f32
+    }
+
+    /// Simulates rows until `done` returns true or the song loops back,
+    /// applying every effect on the way, including tempo changes, without
+    /// logging effects or clicking the metronome. The row arrived at is
+    /// loaded as usual, so that it clicks once playback resumes.
+    fn _simulate_silent<F: FnMut(&Self) -> bool>(&mut self, mut done: F) {
+        self.silent = true;
+        for _ in 0..MAX_SIMULATED_ROWS {
+            if done(self) || self._simulate_row() {
+                break;
             }
+        }
+        self.silent = false;
+        self.click_due = true;
+    }
 
-            let mut sp = self.module.samples[sample-1].clone().play(note, self.sample_rate);
+    /// Runs all ticks of the current row without rendering any audio, then
+    /// moves on to the next row. Returns true if the song looped back.
+    fn _simulate_row(&mut self) -> bool {
+        for _ in 0..self.native_tpd {
+            self._next_tick();
+        }
+        let (program, row) = (self.program, self.row);
+        self._next_division();
+        self.finished || self.program < program || (self.program == program && self.row <= row)
+    }
+
+    /// Renders the first `length` samples of a module offline, without
+    /// disturbing any player used for live playback.
+    pub fn render(module: &Arc<Module>, sample_rate: f32, length: usize) -> Vec<f32> {
+        let mut player = Player::new(module, sample_rate);
+        player.playing = true;
+        (0..length).map(|_| player.next()).collect()
+    }
+
+    /// Returns a stopped player of the same module with the same playback
+    /// settings, for rendering without disturbing this one. Positions, loop
+    /// points and the metronome aren't carried over.
+    pub fn offline(&self) -> Self {
+        let mut player = Player::new(&self.module, self.sample_rate as f32);
+        player.compat = self.compat;
+        player.e8x = self.e8x;
+        player.sample_only = self.sample_only;
+        player.quality = self.quality;
+        player.loops = self.loops;
+        player.transpose = self.transpose;
+        player.finetune = self.finetune;
+        player.gain = self.gain;
+        player.speed = self.speed;
+        player.led_filter = self.led_filter;
+        player.muted = self.muted.clone();
+        player.pans = self.pans.clone();
+        player.channel_map = self.channel_map.clone();
+        player
+    }
+
+    /// Plays the song once from the start into a stereo WAV file at the given
+    /// sample rate. Meant for an `offline` copy of the player, as it's
+    /// rendered in full before being written.
+    pub fn render_to_wav(mut self, path: &std::path::Path, trim: wav::SilenceTrim, sample_rate: u32) -> Result<()> {
+        self.looped = false;
+        self._reset();
+        self.playing = true;
+        let mut buf = vec![];
+        while !self.finished {
+            let (l, r) = self.next_stereo();
+            buf.push(l);
+            buf.push(r);
+        }
+
+        let buf = trim.apply(buf, 2);
+        let buf = if sample_rate != self.sample_rate {
+            dsp::resample_stereo(&buf, self.sample_rate, sample_rate)
+        } else {
+            buf
+        };
+        let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+        wav::write(&mut f, &buf, 2, sample_rate)?;
+        Ok(())
+    }
+
+    /// Returns how long the given number of rows takes at the current tempo,
+    /// in ticks and in milliseconds.
+    pub fn rows_duration(&self, rows: usize) -> (usize, f32) {
+        let ticks = rows * (self.native_tpd as usize);
+        let ms = (rows as f32) * 60000.0 / self._dpm();
+        (ticks, ms)
+    }
+
+    /// Whether the module currently has the LED filter switched on.
+    pub fn filter_on(&self) -> bool {
+        self.filter_on
+    }
+
+    pub fn compat(&self) -> PlayerCompat {
+        self.compat
+    }
+
+    /// Switches the behaviours listed on PlayerCompat to those of the given
+    /// kind of player. Individual settings like `e8x` can still be changed
+    /// afterwards.
+    pub fn set_compat(&mut self, compat: PlayerCompat) {
+        self.compat = compat;
+        self.e8x = compat.e8x();
+    }
+
+    /// Returns the current tempo as set by the module: beats per minute,
+    /// ticks per division and the resulting divisions per minute.
+    pub fn tempo(&self) -> (u16, u16, f32) {
+        (self.native_bpm, self.native_tpd, self._dpm())
+    }
+
+    /// Returns the playback position of every channel playing a sample, as
+    /// (sample index, position) pairs.
+    pub fn sample_positions(&self) -> Vec<(usize, f32)> {
+        self.channels.iter().filter_map(|c| {
+            let sample = c.last_sample?;
+            let position = c.generator.as_ref()?.position()?;
+            Some((sample - 1, position))
+        }).collect()
+    }
+
+    /// Returns what each module channel is currently playing.
+    pub fn channel_states(&self) -> Vec<ChannelState> {
+        self.channels.iter().map(|c| {
+            let generator = c.generator.as_ref();
+            ChannelState {
+                active: generator.and_then(|g| g.position()).is_some(),
+                note: if c.period != 0 { Some(period_note(c.period)) } else { None },
+                volume: generator.map(|g| ((g.volume as i16) + g.volume_offset).clamp(0, 64) as u8).unwrap_or(0),
+            }
+        }).collect()
+    }
+
+    /// Returns the recent peak output level of each module channel, after
+    /// panning and gain.
+    pub fn channel_peaks(&self) -> Vec<f32> {
+        self.peaks.iter().map(|p| p.level()).collect()
+    }
+
+    /// Mutes every channel but `ix`.
+    pub fn solo(&mut self, ix: usize) {
+        for (i, m) in self.muted.iter_mut().enumerate() {
+            *m = i != ix;
+        }
+    }
+
+    pub fn unmute_all(&mut self) {
+        self.muted.iter_mut().for_each(|m| *m = false);
+    }
+
+    fn _dpm(&self) -> f32 {
+        (24.0 * (self.native_bpm as f32)) / (self.native_tpd as f32) * self.speed.max(0.01)
+    }
+
+    fn _tick_left_reset(&mut self) {
+        let in_division = (60.0 / self._dpm()) * (self.sample_rate as f32);
+        let in_tick = in_division / (self.native_tpd as f32);
+        self.tick_left = in_tick as usize;
+    }
+
+    fn _division_samples(&self) -> usize {
+        ((60.0 / self._dpm()) * (self.sample_rate as f32)) as usize
+    }
+
+    fn _division_left_reset(&mut self) {
+        self.division_left = self._division_samples();
+    }
+
+    fn _load_row(&mut self) {
+        for c in self.channels.iter_mut() {
+            c.delayed_note = None;
+        }
+        for (i, c) in self.module.patterns[self.pattern].cells(self.row).iter().enumerate() {
+            if c.period() == 0 && c.sample_number() == 0 {
+                continue
+            }
+            let channel = &mut self.channels[i];
+            if let (Effect::TonePortamento { .. }, Some(g)) = (c.effect(), &mut channel.generator) {
+                if c.period() != 0 {
+                    // Slide towards the new note instead of triggering it. A
+                    // sample number swaps in the new sample's data and volume,
+                    // but playback carries on from the current position.
+                    let sn = c.sample_number() as usize;
+                    if sn != 0 {
+                        let note = period_note(channel.base_period).mod_semitones(self.transpose);
+                        g.swap(self.module.samples[sn-1].clone().play(note, self.sample_rate, self.quality, self.loops));
+                        channel.last_sample = Some(sn);
+                    }
+                    channel.porta_target = Some(c.period());
+                    channel.last_note = Some(c.note());
+                    continue;
+                }
+            }
+            if c.period() == 0 && self.sample_only == SampleOnlyMode::Update {
+                let sn = c.sample_number() as usize;
+                let channel = &mut self.channels[i];
+                channel.last_sample = Some(sn);
+                if let Some(g) = &mut channel.generator {
+                    g.volume = std::cmp::min(self.module.samples[sn-1].volume, 64);
+                }
+                continue;
+            }
+            let note = if c.period() == 0 {
+                match self.channels[i].last_note {
+                    Some(n) => n,
+                    None => continue,
+                }
+            } else {
+                c.note()
+            };
+
+            let mut sample = c.sample_number() as usize;
+            if sample == 0 {
+                sample = self.channels[i].last_sample.unwrap_or(0);
+            }
+            if sample == 0 {
+                continue
+            }
+
+            let s = self.module.samples[sample-1].clone();
+            let played = note.mod_semitones(self.transpose);
+            let finetune = match c.effect() {
+                Effect::SetFinetune { value } => value,
+                _ => s.finetune(),
+            };
+            let finetune = (finetune as i32 + self.finetune).clamp(i8::MIN as i32, i8::MAX as i32) as i8;
+            let mut sp = s.play_finetuned(played, self.sample_rate, finetune, self.quality, self.loops);
             sp.trigger_start();
-            self.channels[i].generator = Some(sp);
-            self.channels[i].last_sample = Some(sample);
-            self.channels[i].last_note = Some(note);
+            if let Effect::SampleOffset { offset } = c.effect() {
+                let channel = &mut self.channels[i];
+                if offset != 0 {
+                    channel.memory_offset = offset;
+                }
+                sp.trigger_start_at((channel.memory_offset as usize) * 256);
+            }
+            let pending = PendingNote { generator: sp, sample, note, period: c.period() };
+            let channel = &mut self.channels[i];
+            match c.effect() {
+                Effect::NoteDelay { tick } if tick != 0 => channel.delayed_note = Some((tick, pending)),
+                _ => channel._trigger(pending),
+            }
         }
         for c in self.channels.iter_mut() {
+            c.retrigger = None;
+            c.note_cut = None;
             c.volume_slide = None;
+            c.porta_speed = None;
+            c.period_slide = None;
+            if c.vibrato.take().is_some() {
+                c._retune();
+            }
+            if c.tremolo.take().is_some() {
+                if let Some(g) = &mut c.generator {
+                    g.volume_offset = 0;
+                }
+            }
         }
         self.tick = 0;
+        self.click_due = true;
         log::info!("{}, {}", self.pattern, self.row);
         self._apply_enter_effects();
     }
 
+    /// Records the effect of the current row on channel `i` into the effect
+    /// log, along with the state of the channel it resulted in.
+    fn _log_effect(&mut self, i: usize) {
+        let effect = self.module.patterns[self.pattern].cells(self.row)[i].effect();
+        let channel = &self.channels[i];
+        if self.effect_log.len() >= EFFECT_LOG_LENGTH {
+            self.effect_log.pop_front();
+        }
+        self.effect_log.push_back(EffectLogEntry {
+            program: self.program,
+            row: self.row,
+            tick: self.tick,
+            channel: i,
+            effect: effect.string(),
+            volume: channel.generator.as_ref().map(|g| g.volume).unwrap_or(0),
+            period: channel.period,
+        });
+    }
+
+    /// Returns the user loop range, if one is set and valid. Seeking ignores
+    /// it, so that any position can be reached.
+    fn _loop_range(&self) -> Option<((usize, usize), (usize, usize))> {
+        if self.silent {
+            return None;
+        }
+        match (self.loop_start, self.loop_end) {
+            (Some(start), Some(end)) if start <= end && start.0 < self.module.song_length => Some((start, end)),
+            _ => None,
+        }
+    }
+
     fn _next_division(&mut self) {
+        self.elapsed += 60.0 / self._dpm();
         self._division_left_reset();
-        let (next_row, advance_pattern) = if let Some(d) = self.incoming_break {
-            self.incoming_break = None;
+        if let Some((start, end)) = self._loop_range() {
+            let here = (self.program, self.row);
+            if here >= end || here < start {
+                // Jumps and breaks on the last row are dropped along with
+                // the rest of the song past it.
+                self.incoming_break = None;
+                self.incoming_jump = None;
+                self.program = start.0;
+                self.pattern = self.module.positions()[self.program] as usize;
+                self.row = std::cmp::min(start.1, self.module.patterns[self.pattern].num_rows().saturating_sub(1));
+                self._load_row();
+                return;
+            }
+        }
+        // As in ProTracker, a jump and a break on the same row go to the
+        // break's row in the jump's position.
+        let jump = self.incoming_jump.take();
+        let (next_row, advance_pattern) = if let Some(d) = self.incoming_break.take() {
             (d, true)
+        } else if jump.is_some() {
+            (0, true)
         } else {
-            if self.row >= 63 {
+            if self.row + 1 >= self.module.patterns[self.pattern].num_rows() {
                 (0, true)
             } else {
                 (self.row+1, false)
             }
         };
-        self.row = next_row;
         if advance_pattern {
-            self.program += 1;
-            if self.program >= self.module.program.len() {
-                self.program = 0;
+            let next = jump.unwrap_or(self.program + 1);
+            let end = next >= self.module.song_length || next <= self.program;
+            if end && !self.looped {
+                self.finished = true;
+                self.playing = false;
+                return;
+            }
+            self.program = if next >= self.module.song_length { 0 } else { next };
+            if end && self.program == 0 {
+                self.elapsed = 0.0;
             }
-            self.pattern = self.module.program[self.program] as usize;
+            self.pattern = self.module.positions()[self.program] as usize;
         }
+        // A break might point past the end of a shorter pattern.
+        self.row = std::cmp::min(next_row, self.module.patterns[self.pattern].num_rows().saturating_sub(1));
         self._load_row();
     }
 
     fn _next_tick(&mut self) {
         self._tick_left_reset();
         if self.tick != 0 {
-            for c in self.channels.iter_mut() {
+            let tick = self.tick;
+            for i in 0..self.channels.len() {
+                let c = &mut self.channels[i];
+                let active = c.volume_slide.is_some() || c.porta_speed.is_some() || c.period_slide.is_some()
+                    || c.vibrato.is_some() || c.tremolo.is_some() || c.retrigger.is_some() || c.note_cut.is_some()
+                    || c.delayed_note.is_some();
+                if matches!(c.delayed_note, Some((t, _)) if t as usize == tick) {
+                    let (_, pending) = c.delayed_note.take().unwrap();
+                    c._trigger(pending);
+                }
+                if let Some(ticks) = c.retrigger {
+                    if tick.is_multiple_of(ticks as usize) {
+                        if let Some(g) = &mut c.generator {
+                            g.trigger_start();
+                        }
+                    }
+                }
+                if c.note_cut == Some(tick as u8) {
+                    if let Some(g) = &mut c.generator {
+                        g.volume = 0;
+                    }
+                }
                 if let Some(slide) = c.volume_slide {
                     if let Some(g) = &mut c.generator {
                         let mut volume = g.volume as i32;
@@ -543,26 +1965,84 @@ impl Player {
                         g.volume = volume as u8;
                     }
                 }
+                if let Some(speed) = c.porta_speed {
+                    c._porta_tick(speed);
+                }
+                if let Some(delta) = c.period_slide {
+                    c._slide_tick(delta);
+                }
+                if let Some((speed, depth)) = c.vibrato {
+                    c._vibrato_tick(speed, depth);
+                }
+                if let Some((speed, depth)) = c.tremolo {
+                    c._tremolo_tick(speed, depth);
+                }
+                if active && self.log_effects && !self.silent {
+                    self._log_effect(i);
+                }
             }
         }
         self.tick += 1;
     }
 
     fn _apply_enter_effects(&mut self) {
-        for (i, c) in self.module.patterns[self.pattern].rows[self.row].channels.iter().enumerate() {
+        let module = self.module.clone();
+        for (i, c) in module.patterns[self.pattern].cells(self.row).iter().enumerate() {
             let effect = c.effect();
+            let logged = !matches!(effect, Effect::None);
             match effect {
                 Effect::VolumeSlide { up, down } => {
+                    let channel = &mut self.channels[i];
                     if up == 0 && down != 0 {
-                        self.channels[i].volume_slide = Some(-(down as i8));
+                        channel.volume_slide = Some(-(down as i8));
                     }
                     if down == 0 && up != 0 {
-                        self.channels[i].volume_slide = Some(up as i8);
+                        channel.volume_slide = Some(up as i8);
+                    }
+                    if up == 0 && down == 0 {
+                        channel.volume_slide = channel.memory_volume_slide;
+                    }
+                    channel.memory_volume_slide = channel.volume_slide.or(channel.memory_volume_slide);
+                },
+                Effect::Vibrato { speed, depth } => {
+                    let channel = &mut self.channels[i];
+                    let (ms, md) = channel.memory_vibrato;
+                    let speed = if speed == 0 { ms } else { speed };
+                    let depth = if depth == 0 { md } else { depth };
+                    channel.memory_vibrato = (speed, depth);
+                    channel.vibrato = Some((speed, depth));
+                },
+                Effect::Tremolo { speed, depth } => {
+                    let channel = &mut self.channels[i];
+                    let (ms, md) = channel.memory_tremolo;
+                    let speed = if speed == 0 { ms } else { speed };
+                    let depth = if depth == 0 { md } else { depth };
+                    channel.memory_tremolo = (speed, depth);
+                    channel.tremolo = Some((speed, depth));
+                },
+                Effect::PortamentoUp { speed } => {
+                    let channel = &mut self.channels[i];
+                    let speed = self.compat.slide(speed, &mut channel.memory_porta.0);
+                    channel.period_slide = Some(-(speed as i16));
+                },
+                Effect::PortamentoDown { speed } => {
+                    let channel = &mut self.channels[i];
+                    let speed = self.compat.slide(speed, &mut channel.memory_porta.1);
+                    channel.period_slide = Some(speed as i16);
+                },
+                Effect::TonePortamento { speed } => {
+                    let channel = &mut self.channels[i];
+                    if speed != 0 {
+                        channel.memory_porta_speed = Some(speed);
                     }
+                    channel.porta_speed = channel.memory_porta_speed;
                 },
                 Effect::PatternBreak { division } => {
                     self.incoming_break = Some(division);
                 },
+                Effect::PositionJump { position } => {
+                    self.incoming_jump = Some(position as usize);
+                },
                 Effect::SetBeatsPerMinute { bpm } => {
                     self.native_bpm = bpm;
                 },
@@ -578,15 +2058,35 @@ impl Player {
                     }
                 }
                 Effect::FineVolumeSlideUp { up } => {
-                    if let Some(v) = &mut self.channels[i].generator {
-                        v.volume += up;
-                        if v.volume > 64 {
-                            v.volume = 64;
+                    let channel = &mut self.channels[i];
+                    let up = self.compat.slide(up, &mut channel.memory_fine_volume.0);
+                    if let Some(v) = &mut channel.generator {
+                        v.volume = ((v.volume as i32) + (up as i32)).clamp(0, 64) as u8;
+                    }
+                }
+                Effect::CoarsePanning { pan } if self.e8x == E8xMode::Panning => {
+                    self.channels[i].pan = Some((pan as f32) / 15.0 * 2.0 - 1.0);
+                }
+                Effect::SetFilter { on } => {
+                    self.filter_on = on;
+                }
+                Effect::Retrigger { ticks } if ticks != 0 => {
+                    self.channels[i].retrigger = Some(ticks);
+                }
+                Effect::NoteCut { tick } => {
+                    let channel = &mut self.channels[i];
+                    if tick == 0 {
+                        if let Some(g) = &mut channel.generator {
+                            g.volume = 0;
                         }
+                    } else {
+                        channel.note_cut = Some(tick);
                     }
                 }
                 Effect::FineVolumeSlideDown { down } => {
-                    if let Some(v) = &mut self.channels[i].generator {
+                    let channel = &mut self.channels[i];
+                    let down = self.compat.slide(down, &mut channel.memory_fine_volume.1);
+                    if let Some(v) = &mut channel.generator {
                         if down > v.volume {
                             v.volume = 0;
                         } else {
@@ -596,15 +2096,33 @@ impl Player {
                 }
                 _ => (),
             }
+            if logged && self.log_effects && !self.silent {
+                self._log_effect(i);
+            }
         }
     }
 }
 
-impl sound::Generator for Player {
-    fn next(&mut self) -> f32 {
+impl Player {
+    /// Plays the next left and right sample values of the module.
+    pub fn next_stereo(&mut self) -> (f32, f32) {
         if self.playing == false {
-            return 0.0;
+            return (0.0, 0.0);
+        }
+        if self.count_in_left > 0 {
+            let v = self._count_in_next();
+            return (v, v);
         }
+        match self.grain_left {
+            Some(0) => {
+                self.grain_left = None;
+                self.playing = false;
+                return (0.0, 0.0);
+            },
+            Some(n) => self.grain_left = Some(n - 1),
+            None => (),
+        }
+        self.samples_played += 1;
         if self.tick_left == 0 {
             self._next_tick();
         } else {
@@ -615,12 +2133,1547 @@ impl sound::Generator for Player {
         } else {
             self.division_left -= 1;
         }
-        let mut v: f32 = 0.0;
-        for c in self.channels.iter_mut() {
-            if let Some(g) = &mut c.generator {
-                v += g.next() * 0.3;
+        if self.click_due {
+            self.click_due = false;
+            if !self.click_grid.is_empty() && self.click_grid[self.row % self.click_grid.len()] {
+                self.metronome.trigger();
             }
         }
-        v
+        for (i, c) in self.channels.iter_mut().enumerate() {
+            if self.muted.get(i).copied().unwrap_or(false) {
+                if let Some(g) = &mut c.generator {
+                    g.trigger_end();
+                }
+            }
+            let pan = c.pan.unwrap_or_else(|| self.pans.as_slice().get(i).copied().unwrap_or(0.0));
+            c.output = match &mut c.generator {
+                Some(g) => {
+                    let (l, r) = g.next_stereo();
+                    let (gl, gr) = dsp::pan_gains(pan);
+                    (l * gl * 0.3, r * gr * 0.3)
+                },
+                None => (0.0, 0.0),
+            };
+            if let Some(p) = self.peaks.get_mut(i) {
+                p.feed(c.output.0.abs().max(c.output.1.abs()) * self.gain);
+            }
+        }
+        for (pos, src) in self.channel_map.iter().enumerate() {
+            self.outputs[pos] = self.channels.get(*src).map(|c| c.output).unwrap_or((0.0, 0.0));
+        }
+        let (l, r) = self.outputs.iter().fold((0.0, 0.0), |(l, r), o| (l + o.0, r + o.1));
+        let (l, r) = if self.led_filter && self.filter_on {
+            (self.filters[0].process(l), self.filters[1].process(r))
+        } else {
+            (l, r)
+        };
+        let click = self.metronome.next() * 0.3;
+        (l * self.gain + click, r * self.gain + click)
+    }
+}
+
+/// Mono output sums both sides, so that channels panned hard to one side,
+/// as they are by default, play at the level they have there.
+impl sound::Generator for Player {
+    fn next(&mut self) -> f32 {
+        let (l, r) = self.next_stereo();
+        l + r
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(period: u16, sample: u8, effect: u16) -> Data {
+        Data::new(period, sample, effect)
+    }
+
+    fn test_sample(data: Vec<f32>, volume: u8) -> Arc<Sample> {
+        Arc::new(Sample {
+            name: "test".into(),
+            length: data.len() / 2,
+            finetune: 0,
+            volume,
+            repeat_start: 0,
+            repeat_length: 0,
+            data,
+            right: None,
+        })
+    }
+
+    /// Builds a single-pattern module from the given rows, padding the
+    /// pattern to 64 rows with empty cells.
+    fn test_module(samples: Vec<Arc<Sample>>, rows: Vec<Vec<Data>>) -> Arc<Module> {
+        let mut rows = rows.into_iter().map(|channels| Row { channels }).collect::<Vec<_>>();
+        while rows.len() < 64 {
+            rows.push(Row { channels: (0..4).map(|_| Data(0)).collect() });
+        }
+        Arc::new(Module {
+            title: "test".into(),
+            samples,
+            patterns: vec![Pattern { rows }],
+            program: vec![0],
+            song_length: 1,
+            channels: 4,
+        })
     }
-}
\ No newline at end of file
+
+    /// Builds a module of three positions playing patterns 0, 1 and 2, with
+    /// the given cells on the first row of pattern 1.
+    fn jump_module(cells: Vec<Data>) -> Arc<Module> {
+        let mut patterns = vec![Pattern::empty(64, 4), Pattern::empty(64, 4), Pattern::empty(64, 4)];
+        patterns[1].rows[0].channels = cells;
+        Arc::new(Module {
+            title: "test".into(),
+            samples: vec![test_sample(vec![0.0; 100], 64)],
+            patterns,
+            program: vec![0, 1, 2],
+            song_length: 3,
+            channels: 4,
+        })
+    }
+
+    #[test]
+    fn test_position_jump() {
+        let module = jump_module(vec![cell(0, 0, 0xB02), Data(0), Data(0), Data(0)]);
+        let mut player = Player::new(&module, 44100.0);
+        player.seek_position(1, 0);
+        player._next_division();
+        assert_eq!((player.program, player.row), (2, 0));
+
+        // Jump and break together.
+        let module = jump_module(vec![cell(0, 0, 0xB02), cell(0, 0, 0xD05), Data(0), Data(0)]);
+        let mut player = Player::new(&module, 44100.0);
+        player.seek_position(1, 0);
+        player._next_division();
+        assert_eq!((player.program, player.row), (2, 5));
+    }
+
+    #[test]
+    fn test_song_end() {
+        // Running off the end.
+        let module = jump_module(vec![Data(0), Data(0), Data(0), Data(0)]);
+        let mut player = Player::new(&module, 44100.0);
+        player.seek_position(2, 63);
+        player._next_division();
+        assert_eq!((player.program, player.row, player.finished), (0, 0, false));
+
+        player.looped = false;
+        player.seek_position(2, 63);
+        player.playing = true;
+        player._next_division();
+        assert!(player.finished);
+        assert!(!player.playing);
+        assert_eq!(player.next(), 0.0);
+
+        // Jumping backwards.
+        let module = jump_module(vec![cell(0, 0, 0xB00), Data(0), Data(0), Data(0)]);
+        let mut player = Player::new(&module, 44100.0);
+        player.seek_position(1, 0);
+        player._next_division();
+        assert_eq!((player.program, player.finished), (0, false));
+
+        player.looped = false;
+        player.seek_position(1, 0);
+        player._next_division();
+        assert_eq!((player.program, player.finished), (1, true));
+        player.play();
+        assert_eq!((player.program, player.row, player.finished), (0, 0, false));
+    }
+
+    #[test]
+    fn test_seek_seconds() {
+        let mut patterns = vec![Pattern::empty(64, 4), Pattern::empty(64, 4)];
+        // Three ticks per row at 125 BPM: 60ms per row.
+        patterns[0].rows[0].channels[0] = cell(0, 0, 0xF03);
+        let module = Arc::new(Module {
+            title: "test".into(),
+            samples: vec![test_sample(vec![0.0; 100], 64)],
+            patterns,
+            program: vec![0, 1],
+            song_length: 2,
+            channels: 4,
+        });
+        let mut player = Player::new(&module, 44100.0);
+
+        assert!((player.duration_estimate() - 7.68).abs() < 0.01);
+
+        player.seek_seconds(1.0);
+        assert_eq!((player.program, player.pattern, player.row), (0, 0, 16));
+        assert!((player.position_seconds() - 0.96).abs() < 0.001);
+
+        // Each pattern takes 3.84s.
+        player.seek_seconds(4.03);
+        assert_eq!((player.program, player.pattern, player.row), (1, 1, 3));
+
+        player.seek_seconds(1000.0);
+        assert_eq!((player.program, player.pattern, player.row), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_seek() {
+        let mut patterns = vec![Pattern::empty(64, 4), Pattern::empty(64, 4)];
+        patterns[0].rows[0].channels[1] = cell(428, 1, 0);
+        patterns[1].rows[10].channels[0] = cell(214, 1, 0);
+        let module = Arc::new(Module {
+            title: "test".into(),
+            samples: vec![test_sample(vec![1.0; 100], 64)],
+            patterns,
+            program: vec![0, 1],
+            song_length: 2,
+            channels: 4,
+        });
+        let mut player = Player::new(&module, 44100.0);
+        player.play();
+        assert!(player.next() > 0.0);
+        assert!(player.channels[1].generator.is_some());
+
+        player.seek(1, 10);
+        assert_eq!((player.program, player.pattern, player.row), (1, 1, 10));
+        // Song time agrees with seeking by simulating the song.
+        let mut simulated = Player::new(&module, 44100.0);
+        simulated.seek_position(1, 10);
+        assert!(player.position_seconds() > 0.0);
+        assert_eq!(player.position_seconds(), simulated.position_seconds());
+        assert_eq!(player.elapsed_seconds(), simulated.elapsed_seconds());
+        // The note on the first row is cut, the one on the new row plays.
+        assert!(player.channels[1].generator.is_none());
+        assert_eq!(player.channels[0].period, 214);
+        assert!(player.next() > 0.0);
+
+        // Out of range positions and rows are clamped.
+        player.seek(5, 100);
+        assert_eq!((player.program, player.row), (1, 63));
+    }
+
+    #[test]
+    fn test_elapsed_seconds() {
+        let module = jump_module((0..4).map(|_| Data(0)).collect());
+        let mut player = Player::new(&module, 44100.0);
+        player.play();
+        for _ in 0..22050 {
+            player.next();
+        }
+        assert!((player.elapsed_seconds() - 0.5).abs() < 1e-4);
+
+        // Jumping to a row counts from the start of the song too.
+        player.seek(2, 0);
+        assert!((player.elapsed_seconds() - 15.36).abs() < 0.01);
+        player.next();
+        player.seek_position(1, 0);
+        assert!((player.elapsed_seconds() - 7.68).abs() < 0.01);
+        player.stop();
+        assert_eq!((player.elapsed_seconds(), player.playing), (0.0, false));
+    }
+
+    #[test]
+    fn test_duration_estimate() {
+        // Every row takes 120ms.
+        let empty = || (0..4).map(|_| Data(0)).collect::<Vec<_>>();
+        let player = Player::new(&jump_module(empty()), 44100.0);
+        assert!((player.duration_estimate() - 192.0 * 0.12).abs() < 0.01);
+
+        let mut cells = empty();
+        cells[0] = cell(0, 0, 0xB02);
+        let player = Player::new(&jump_module(cells), 44100.0);
+        assert!((player.duration_estimate() - 129.0 * 0.12).abs() < 0.01);
+
+        let mut cells = empty();
+        cells[0] = cell(0, 0, 0xD16);
+        let player = Player::new(&jump_module(cells), 44100.0);
+        assert!((player.duration_estimate() - 113.0 * 0.12).abs() < 0.01);
+
+        // Jumping back to the first row of the same position loops.
+        let mut cells = empty();
+        cells[0] = cell(0, 0, 0xB01);
+        let player = Player::new(&jump_module(cells), 44100.0);
+        assert!((player.duration_estimate() - 65.0 * 0.12).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_loop_range() {
+        let mut cells: Vec<Data> = (0..4).map(|_| Data(0)).collect();
+        // A jump out of the range is ignored in favour of looping.
+        cells[0] = cell(0, 0, 0xB00);
+        let module = jump_module(cells);
+        let mut player = Player::new(&module, 44100.0);
+        player.loop_start = Some((0, 60));
+        player.loop_end = Some((1, 0));
+        player.seek_position(0, 62);
+        let mut seen = vec![];
+        for _ in 0..20 {
+            seen.push((player.program, player.row));
+            player._simulate_row();
+        }
+        assert_eq!(&seen[..7], &[(0, 62), (0, 63), (1, 0), (0, 60), (0, 61), (0, 62), (0, 63)]);
+        assert!(seen.iter().all(|p| *p >= (0, 60) && *p <= (1, 0)));
+
+        // Starting outside of the range jumps into it.
+        player.seek(2, 5);
+        player._simulate_row();
+        assert_eq!((player.program, player.row), (0, 60));
+
+        // An inverted range is ignored.
+        player.loop_start = Some((2, 0));
+        player.seek_position(0, 62);
+        player._simulate_row();
+        player._simulate_row();
+        assert_eq!((player.program, player.row), (1, 0));
+    }
+
+    #[test]
+    fn test_empty_pattern() {
+        let module = Arc::new(Module {
+            title: "test".into(),
+            samples: vec![],
+            patterns: vec![Pattern::empty(0, 4), Pattern::empty(64, 4)],
+            program: vec![0, 1],
+            song_length: 2,
+            channels: 4,
+        });
+        let mut player = Player::new(&module, 44100.0);
+        player.seek(0, 10);
+        assert_eq!((player.program, player.row), (0, 0));
+        // The empty pattern plays as a single row.
+        assert!((player.duration_estimate() - 65.0 * 0.12).abs() < 0.01);
+        player.play();
+        while player.program == 0 {
+            player.next();
+        }
+        assert_eq!((player.program, player.row), (1, 0));
+        assert!(module.patterns[0].note_lengths(0).is_empty());
+    }
+
+    #[test]
+    fn test_channel_states() {
+        let module = test_module(vec![test_sample(vec![1.0; 100], 64)], vec![
+            vec![cell(428, 1, 0), cell(214, 1, 0xC20), Data(0), Data(0)],
+        ]);
+        let mut player = Player::new(&module, 44100.0);
+        player.play();
+        player.next();
+        let states = player.channel_states();
+        assert_eq!(states.len(), 4);
+        assert!(states[0].active && states[1].active && !states[2].active);
+        assert_eq!(states[0].note.map(|n| n.midi()), Some(cell(428, 1, 0).note().midi()));
+        assert_eq!(states[1].note.map(|n| n.midi()), Some(cell(214, 1, 0).note().midi()));
+        assert!(states[2].note.is_none());
+        assert_eq!((states[0].volume, states[1].volume, states[2].volume), (64, 32, 0));
+
+        // The sample is over long before the next row.
+        for _ in 0..1000 {
+            player.next();
+        }
+        let states = player.channel_states();
+        assert!(!states[0].active);
+        assert!(states[0].note.is_some());
+    }
+
+    #[test]
+    fn test_transpose() {
+        let module = test_module(vec![test_sample(vec![1.0; 4000], 64)], vec![
+            vec![cell(428, 1, 0), cell(214, 1, 0), cell(320, 1, 0), cell(678, 1, 0)],
+        ]);
+        let lengths = |transpose: i32| {
+            let mut player = Player::new(&module, 44100.0);
+            player.transpose = transpose;
+            player._reset();
+            player.channels.iter().map(|c| c.generator.as_ref().unwrap().signal.length() as f32).collect::<Vec<f32>>()
+        };
+        let (plain, up) = (lengths(0), lengths(12));
+        // Playing at twice the rate goes through the resampled sample in
+        // half the time.
+        for (p, u) in plain.iter().zip(up.iter()) {
+            assert!((p / u - 2.0).abs() < 0.01);
+        }
+        let down = lengths(-12);
+        for (p, d) in plain.iter().zip(down.iter()) {
+            assert!((d / p - 2.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_seek_applies_tempo() {
+        let mut rows: Vec<Vec<Data>> = (0..4).map(|_| (0..4).map(|_| Data(0)).collect()).collect();
+        rows[2][1] = cell(0, 0, 0xF20);
+        let module = test_module(vec![test_sample(vec![0.0; 100], 64)], rows);
+        let mut player = Player::new(&module, 44100.0);
+        player.log_effects = true;
+
+        player.seek_position(0, 5);
+        assert_eq!(player.row, 5);
+        assert_eq!(player.tempo().0, 32);
+        assert!(player.effect_log.is_empty());
+
+        player.seek_seconds(0.0);
+        assert_eq!(player.tempo().0, 125);
+        // Each row of the first three takes 120ms, the rest 468.75ms.
+        player.seek_seconds(0.4);
+        assert_eq!((player.row, player.tempo().0), (2, 32));
+        assert!(player.effect_log.is_empty());
+    }
+
+    /// Builds a 50 word sample with the given loop, its data ramping up from
+    /// 0 to 99.
+    fn loop_sample(repeat_start: usize, repeat_length: usize) -> Arc<Sample> {
+        Arc::new(Sample {
+            name: "loop".into(),
+            length: 50,
+            finetune: 0,
+            volume: 64,
+            repeat_start,
+            repeat_length,
+            data: (0..100).map(|i| i as f32).collect(),
+            right: None,
+        })
+    }
+
+    #[test]
+    fn test_sample_loop() {
+        let sample = loop_sample(10, 20);
+        let mut sp = sample.clone().play(notes::A4, 44100, dsp::Quality::Fast, LoopRule::AboveOne);
+        let len = sp.signal.length();
+        let (st, le) = sp.repeat.unwrap();
+        assert_eq!(st, (20.0 * sp.scale).round() as usize);
+        assert_eq!(st + le, (60.0 * sp.scale).round() as usize);
+        assert!(st + le <= len);
+
+        // The sample data is a ramp, so the values played are the positions
+        // in the original data.
+        sp.trigger_start();
+        let played = (0..len * 3).map(|_| sp.next()).collect::<Vec<f32>>();
+        assert!(played.as_slice().iter().all(|v| *v < 60.0));
+        // After reaching the loop end, playback wraps to the loop start, not
+        // to the start of the sample.
+        let wrap = played.windows(2).position(|w| w[1] < w[0]).unwrap();
+        assert!((played[wrap + 1] - 20.0).abs() <= 1.0);
+        assert!(played[wrap + 1..].iter().all(|v| *v >= 19.0));
+
+        let mut sp = sample.clone().play(notes::A4, 44100, dsp::Quality::Fast, LoopRule::AboveOne);
+        sp.stop_looping();
+        sp.trigger_start();
+        (0..len).for_each(|_| { sp.next(); });
+        assert!(sp.is_finished());
+
+        // Played once, the loop is ignored.
+        let mut once = sample.play_once(notes::A4, 44100, dsp::Quality::Fast);
+        let played = (0..len * 3).map(|_| once.next()).collect::<Vec<f32>>();
+        assert!(played[..len].windows(2).all(|w| w[1] >= w[0]));
+        assert!(played[len..].iter().all(|v| *v == 0.0));
+        assert!(once.is_finished());
+    }
+
+    #[test]
+    fn test_loop_rule() {
+        for (length, above_one, above_zero) in [
+            (0, None, None),
+            (1, None, Some((20, 2))),
+            (2, Some((20, 4)), Some((20, 4))),
+        ] {
+            assert_eq!(loop_sample(10, length).loop_region(LoopRule::AboveOne), above_one);
+            assert_eq!(loop_sample(10, length).loop_region(LoopRule::AboveZero), above_zero);
+        }
+
+        // Loops past the end of the sample are moved back inside it.
+        assert_eq!(loop_sample(45, 10).loop_region(LoopRule::AboveOne), Some((80, 20)));
+        assert_eq!(loop_sample(300, 10).loop_region(LoopRule::AboveOne), Some((80, 20)));
+        assert_eq!(loop_sample(300, 100).loop_region(LoopRule::AboveOne), Some((0, 100)));
+        let mut sp = loop_sample(300, 10).play(notes::A4, 44100, dsp::Quality::Fast, LoopRule::AboveOne);
+        let (st, le) = sp.repeat.unwrap();
+        assert!(le > 0 && st + le <= sp.signal.length());
+        sp.trigger_start();
+        for _ in 0..sp.signal.length() * 2 {
+            sp.next();
+        }
+        assert!(sp.position().is_some());
+    }
+
+    #[test]
+    fn test_period_table() {
+        assert_eq!(cell(428, 0, 0).snote(), "C-2");
+        assert_eq!(cell(113, 0, 0).snote(), "B-3");
+        assert_eq!(cell(856, 0, 0).snote(), "C-1");
+        assert_eq!(cell(453, 0, 0).snote(), "B-1");
+        assert_eq!(cell(1712, 0, 0).snote(), "C-0");
+        assert_eq!(cell(57, 0, 0).snote(), "B-4");
+        assert_eq!(cell(0, 0, 0).snote(), "...");
+        // Finetuned periods land on the closest note.
+        assert_eq!(cell(431, 0, 0).snote(), "C-2");
+        assert!((cell(254, 0, 0).note().freq() - 440.0).abs() < 1e-3);
+        assert!((cell(127, 0, 0).note().freq() - 880.0).abs() < 1e-3);
+        // Pitch follows the period itself, not the note it's named after.
+        assert!((cell(428, 0, 0).note().freq() - 261.121).abs() < 1e-2);
+        assert!(cell(431, 0, 0).note().freq() < cell(428, 0, 0).note().freq());
+    }
+
+    #[test]
+    fn test_period_for_note() {
+        let c2 = notes::Note::new(261.63);
+        assert_eq!(period_for_note(&c2, 0), 428);
+        assert_eq!(period_for_note(&notes::A4, 0), 254);
+        // Out of range notes clamp to the ends of the table.
+        assert_eq!(period_for_note(&c2.octave_down().octave_down(), 0), 856);
+        assert_eq!(period_for_note(&c2.octave_up().octave_up(), 0), 113);
+        // Same as ProTracker's finetuned tables. A finetune of 8 is -8.
+        assert_eq!(period_for_note(&c2, 7), 407);
+        assert_eq!(period_for_note(&c2, 8), 453);
+        for period in PERIOD_TABLE {
+            let c = cell(period, 0, 0);
+            assert_eq!(period_for_note(&c.note(), 0), period);
+            assert_eq!(cell(period_for_note(&c.note(), 0), 0, 0).snote(), c.snote());
+        }
+    }
+
+    #[test]
+    fn test_search_sample() {
+        let mut patterns = vec![Pattern::empty(64, 4), Pattern::empty(64, 4), Pattern::empty(64, 4)];
+        patterns[0].rows[3].channels[1] = cell(428, 2, 0);
+        patterns[0].rows[3].channels[2] = cell(428, 1, 0);
+        patterns[2].rows[10].channels[0] = cell(0, 2, 0xC20);
+        patterns[2].rows[63].channels[3] = cell(214, 2, 0);
+        let module = Module {
+            title: "test".into(),
+            samples: vec![test_sample(vec![0.0; 100], 64)],
+            patterns,
+            program: vec![2, 0, 2],
+            song_length: 3,
+            channels: 4,
+        };
+        assert_eq!(module.search(&Search::Sample(2)), vec![
+            SearchMatch { order: Some(1), pattern: 0, row: 3, channel: 1 },
+            SearchMatch { order: Some(0), pattern: 2, row: 10, channel: 0 },
+            SearchMatch { order: Some(0), pattern: 2, row: 63, channel: 3 },
+        ]);
+        assert_eq!(module.search(&Search::Sample(5)), vec![]);
+        assert_eq!(module.search(&Search::Effect('c')).len(), 1);
+    }
+
+    #[test]
+    fn test_play_grain() {
+        let module = test_module(vec![test_sample(vec![1.0; 20000], 64)], vec![
+            vec![cell(428, 1, 0), Data(0), Data(0), Data(0)],
+        ]);
+        let mut player = Player::new(&module, 1000.0);
+        player.count_in = 4;
+        player.play_grain(0.05);
+        let played = (0..100).map(|_| player.next()).collect::<Vec<f32>>();
+        assert!(played[..50].iter().all(|v| *v != 0.0));
+        assert!(played[50..].iter().all(|v| *v == 0.0));
+        assert!(!player.playing);
+        // Playing normally afterwards doesn't stop after a grain.
+        player.count_in = 0;
+        player.play();
+        assert!((0..100).all(|_| player.next() != 0.0));
+    }
+
+    #[test]
+    fn test_led_filter() {
+        assert!(matches!(Effect::from(0xE00), Effect::SetFilter { on: true }));
+        assert!(matches!(Effect::from(0xE01), Effect::SetFilter { on: false }));
+        assert_eq!(Effect::from(0xE01).string(), "E01");
+
+        // Energy of a buzzy high note, played with the given effect.
+        let energy = |effect: u16, emulate: bool| {
+            let buzz = (0..20000).map(|i| if i % 2 == 0 { 1.0 } else { -1.0 }).collect();
+            let module = test_module(vec![test_sample(buzz, 64)], vec![
+                vec![cell(113, 1, effect), Data(0), Data(0), Data(0)],
+            ]);
+            let mut player = Player::new(&module, 44100.0);
+            player.led_filter = emulate;
+            player.playing = true;
+            (0..4000).map(|_| player.next().powi(2)).sum::<f32>()
+        };
+        let off = energy(0xE01, true);
+        assert!(off > 0.0);
+        assert!(energy(0xE00, true) < off * 0.3);
+        assert_eq!(energy(0xE00, false), off);
+        // Off until the module says otherwise.
+        assert_eq!(energy(0, true), off);
+    }
+
+    #[test]
+    fn test_fine_volume_slide_overflow() {
+        let module = test_module(vec![test_sample(vec![0.0; 100], 250)], vec![
+            vec![cell(428, 1, 0), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0xEAA), Data(0), Data(0), Data(0)],
+        ]);
+        let mut player = Player::new(&module, 44100.0);
+        player._simulate_row();
+        assert_eq!(player.channels[0].generator.as_ref().unwrap().volume, 64);
+
+        let mut bytes = module_bytes();
+        bytes[20 + 25] = 250;
+        let module = Module::from_reader(&mut &bytes[..]).unwrap();
+        assert_eq!(module.samples[0].volume, 64);
+    }
+
+    #[test]
+    fn test_volume_slide_memory() {
+        let module = test_module(vec![test_sample(vec![1.0; 20000], 32)], vec![
+            vec![cell(428, 1, 0xA02), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0xA00), Data(0), Data(0), Data(0)],
+        ]);
+        let mut player = Player::new(&module, 44100.0);
+        // A02 slides the volume down by 2 on each of the five non-first ticks.
+        for _ in 0..6 {
+            player._next_tick();
+        }
+        assert_eq!(player.channels[0].generator.as_ref().unwrap().volume, 22);
+        player._next_division();
+        assert_eq!(player.channels[0].volume_slide, Some(-2));
+        for _ in 0..6 {
+            player._next_tick();
+        }
+        assert_eq!(player.channels[0].generator.as_ref().unwrap().volume, 12);
+    }
+
+    /// Serializes a small but valid module: one pattern with a few notes and
+    /// two short samples.
+    fn module_bytes() -> Vec<u8> {
+        let mut b = vec![];
+        b.extend_from_slice(b"fuzz\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
+        for i in 0..31 {
+            b.extend_from_slice(&[0u8; 22]);
+            let (length, repeat) = if i < 2 { (8u16, 2u16) } else { (0, 0) };
+            b.extend_from_slice(&length.to_be_bytes());
+            b.extend_from_slice(&[0, 64]);
+            b.extend_from_slice(&repeat.to_be_bytes());
+            b.extend_from_slice(&repeat.to_be_bytes());
+        }
+        b.extend_from_slice(&[1, 127]);
+        b.extend_from_slice(&[0u8; 128]);
+        b.extend_from_slice(b"M.K.");
+        for row in 0..64u32 {
+            for ch in 0..4u32 {
+                let c = cell(if (row + ch) % 4 == 0 { 428 } else { 0 }, (ch % 2 + 1) as u8, 0xC20);
+                b.extend_from_slice(&c.0.to_be_bytes());
+            }
+        }
+        for i in 0..32 {
+            b.push((i * 8) as u8);
+        }
+        b
+    }
+
+    #[test]
+    fn test_write_json() {
+        let mut bytes = module_bytes();
+        bytes[20..24].copy_from_slice(b"kick");
+        let module = Module::from_reader(&mut &bytes[..]).unwrap();
+        let mut buf = vec![];
+        module.write_json(&mut buf).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+
+        assert_eq!(json["title"], "fuzz");
+        assert_eq!(json["channels"], 4);
+        assert_eq!(json["program"].as_array().unwrap().len(), 128);
+        assert_eq!(json["samples"][0]["name"], "kick");
+        assert_eq!(json["samples"][0]["length"], 8);
+        assert!(json["samples"][0].get("data").is_none());
+
+        let row = &json["patterns"][0]["rows"][0]["channels"];
+        assert_eq!(row[0]["note"], "C-2");
+        assert_eq!(row[0]["sample"], 1);
+        assert_eq!(row[0]["command"], "C20");
+        assert_eq!(row[0]["effect"]["SetVolume"]["volume"], 32);
+        assert!(row[1]["note"].is_null());
+        assert_eq!(json["patterns"][0]["rows"].as_array().unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_truncated_sample_data() {
+        let mut bytes = module_bytes();
+        // Sample 1 claims 500 words, but only 500 bytes follow.
+        bytes[20 + 22] = (500u16 >> 8) as u8;
+        bytes[20 + 23] = (500u16 & 0xff) as u8;
+        bytes.truncate(bytes.len() - 32);
+        bytes.extend_from_slice(&[64u8; 500]);
+        let module = Module::from_reader(&mut &bytes[..]).unwrap();
+        let data = &module.samples[0].data;
+        assert_eq!(data.len(), 1000);
+        assert!(data[..500].iter().all(|v| *v == data[0]));
+        assert!(data[500..].iter().all(|v| *v == data[999]));
+        assert!(data[0] > data[999]);
+        assert_eq!(module.samples[1].data.len(), 16);
+    }
+
+    #[test]
+    fn test_speed() {
+        let module = test_module(vec![test_sample(vec![1.0; 20000], 64)], vec![
+            vec![cell(428, 1, 0), Data(0), Data(0), Data(0)],
+        ]);
+        let normal = Player::new(&module, 44100.0);
+        let mut slow = Player::new(&module, 44100.0);
+        slow.speed = 0.5;
+        slow.seek(0, 0);
+        assert_eq!(slow._division_samples(), normal._division_samples() * 2);
+        assert_eq!(slow.tempo().2 * 2.0, normal.tempo().2);
+
+        // Same note, same pitch.
+        let len = |p: &Player| p.channels[0].generator.as_ref().unwrap().signal.length();
+        assert_eq!(slow.channels[0].period, normal.channels[0].period);
+        assert_eq!(len(&slow), len(&normal));
+
+        // The song takes twice as long.
+        let ratio = slow.duration_estimate() / normal.duration_estimate();
+        assert!((ratio - 2.0).abs() < 0.01, "{}", ratio);
+    }
+
+    #[test]
+    fn test_auto_gain() {
+        // A square wave playing throughout the measured time.
+        let square = (0..2000).map(|i| if (i / 10) % 2 == 0 { 1.0 } else { -1.0 }).collect::<Vec<f32>>();
+        let module = |volume: u8| {
+            let mut sample = Sample::empty();
+            sample.data = square.clone();
+            sample.length = square.len() / 2;
+            sample.volume = volume;
+            sample.repeat_length = sample.length;
+            test_module(vec![Arc::new(sample)], vec![vec![cell(428, 1, 0), Data(0), Data(0), Data(0)]])
+        };
+        let (quiet, loud) = (module(8), module(64));
+
+        let mut auto = AutoGain::new();
+        auto.seconds = 1.0;
+        assert_eq!(auto.gain(&quiet, 44100.0), 1.0);
+        auto.enabled = true;
+        let (gq, gl) = (auto.gain(&quiet, 44100.0), auto.gain(&loud, 44100.0));
+        assert!(gq > gl * 4.0, "{} {}", gq, gl);
+
+        // Played back with their gains, both end up near the target on the
+        // side the channel is panned to.
+        for (module, gain) in [(&quiet, gq), (&loud, gl)] {
+            let mut player = Player::new(module, 44100.0);
+            player.gain = gain;
+            player.playing = true;
+            let out = (0..44100).map(|_| player.next_stereo()).collect::<Vec<(f32, f32)>>();
+            let rms = (out.iter().map(|(l, r)| (l * l).max(r * r)).sum::<f32>() / 44100.0).sqrt();
+            assert!((20.0 * rms.log10() - auto.target_db).abs() < 1.0, "{}", rms);
+        }
+
+        // The same note on a left and a right channel is no louder on either
+        // side, even though the mono sum of them is twice as loud. On two
+        // left channels, it is.
+        let both = |other: usize| {
+            let mut channels = vec![cell(428, 1, 0), Data(0), Data(0), Data(0)];
+            channels[other] = cell(428, 1, 0);
+            test_module(loud.samples.clone(), vec![channels])
+        };
+        assert!((auto.gain(&both(1), 44100.0) - gl).abs() < 1e-3 * gl);
+        assert!((auto.gain(&both(3), 44100.0) - gl / 2.0).abs() < 1e-3 * gl);
+
+        // Silence is left alone.
+        let silent = test_module(vec![test_sample(vec![0.0; 100], 64)], vec![vec![cell(428, 1, 0), Data(0), Data(0), Data(0)]]);
+        assert_eq!(auto.gain(&silent, 44100.0), 1.0);
+    }
+
+    #[test]
+    fn test_finetune() {
+        assert_eq!(finetune_nibble(0x7), 7);
+        assert_eq!(finetune_nibble(0x8), -8);
+        assert_eq!(finetune_nibble(0xf), -1);
+
+        let mut bytes = module_bytes();
+        // Sample 1 gets finetune +7, sample 2 (same length) keeps 0.
+        bytes[20 + 24] = 7;
+        let module = Module::from_reader(&mut &bytes[..]).unwrap();
+        assert_eq!(module.samples[0].finetune(), 7);
+        let length = |s: &Arc<Sample>, ft: i8| s.clone().play_finetuned(notes::A4, 44100, ft, dsp::Quality::Normal, LoopRule::AboveOne).signal.length();
+        let tuned = module.samples[0].clone().play(notes::A4, 44100, dsp::Quality::Normal, LoopRule::AboveOne).signal.length();
+        let plain = module.samples[1].clone().play(notes::A4, 44100, dsp::Quality::Normal, LoopRule::AboveOne).signal.length();
+        // Higher pitch means fewer output samples.
+        assert!(tuned < plain);
+        assert_eq!(tuned, length(&module.samples[1], 7));
+        assert!(length(&module.samples[1], -8) > plain);
+
+        // E5x overrides the sample's finetune for the row's note.
+        let module = test_module(vec![test_sample(vec![1.0; 20000], 64)], vec![
+            vec![cell(428, 1, 0xE58), Data(0), Data(0), Data(0)],
+            vec![cell(428, 1, 0), Data(0), Data(0), Data(0)],
+        ]);
+        let mut player = Player::new(&module, 44100.0);
+        let len = |p: &Player| p.channels[0].generator.as_ref().unwrap().signal.length();
+        let detuned = len(&player);
+        player._next_division();
+        assert!(detuned > len(&player));
+
+        // 0xF is an eighth of a semitone down.
+        let mut bytes = module_bytes();
+        bytes[20 + 24] = 0x0f;
+        let module = Module::from_reader(&mut &bytes[..]).unwrap();
+        assert_eq!(module.samples[0].finetune(), -1);
+        let down = module.samples[0].clone().play(notes::A4, 44100, dsp::Quality::Normal, LoopRule::AboveOne).signal.length();
+        assert_eq!(down, length(&module.samples[1], -1));
+        let long = test_sample(vec![1.0; 20000], 64);
+        let ratio = length(&long, -1) as f32 / length(&long, 0) as f32;
+        assert!((ratio - 2.0f32.powf(1.0 / 96.0)).abs() < 0.001, "{}", ratio);
+
+        // The module-wide finetune adds to the sample's.
+        let module = test_module(vec![test_sample(vec![1.0; 20000], 64)], vec![
+            vec![cell(428, 1, 0), Data(0), Data(0), Data(0)],
+        ]);
+        let plain = len(&Player::new(&module, 44100.0));
+        let mut player = Player::new(&module, 44100.0);
+        player.finetune = -1;
+        player.seek(0, 0);
+        let down = len(&player);
+        let ratio = down as f32 / plain as f32;
+        assert!((ratio - 2.0f32.powf(1.0 / 96.0)).abs() < 0.001, "{}", ratio);
+    }
+
+    #[test]
+    fn test_signature_channels() {
+        assert_eq!(signature_channels(b"M.K."), Some(4));
+        assert_eq!(signature_channels(b"6CHN"), Some(6));
+        assert_eq!(signature_channels(b"FLT8"), Some(8));
+        assert_eq!(signature_channels(b"16CH"), Some(16));
+        assert_eq!(signature_channels(b"0CHN"), None);
+        assert_eq!(signature_channels(b"\0\0\0\0"), None);
+
+        // Same as module_bytes, but with 8 channels per row.
+        let bytes = module_bytes();
+        let mut b = bytes[..SIGNATURE_OFFSET].to_vec();
+        b.extend_from_slice(b"8CHN");
+        for row in 0..64u32 {
+            for ch in 0..8u32 {
+                b.extend_from_slice(&cell(if ch == row % 8 { 428 } else { 0 }, 1, 0).0.to_be_bytes());
+            }
+        }
+        b.extend_from_slice(&bytes[SIGNATURE_OFFSET + 4 + 1024..]);
+        let module = Arc::new(Module::from_reader(&mut &b[..]).unwrap());
+        assert_eq!(module.channels, 8);
+        assert_eq!(module.samples[0].data.len(), 16);
+        let row = &module.patterns[0].rows[7];
+        assert_eq!(row.channels.len(), 8);
+        assert_eq!(row.channels[7].period(), 428);
+        assert_eq!(row.channels[6].period(), 0);
+
+        let mut player = Player::new(&module, 44100.0);
+        assert_eq!(player.channel_map.len(), 8);
+        player.seek_position(0, 7);
+        assert!(player.channels[7].generator.is_some());
+    }
+
+    #[test]
+    fn test_flt8() {
+        // Two positions, at the second pair of 4-channel patterns.
+        let bytes = module_bytes();
+        let mut b = bytes[..SIGNATURE_OFFSET].to_vec();
+        b[20 + 31 * 30] = 2;
+        b[20 + 31 * 30 + 2 + 1] = 2;
+        b.extend_from_slice(b"FLT8");
+        // Each half has a note on a row and channel of its own.
+        for half in 0..4u32 {
+            for row in 0..64u32 {
+                for ch in 0..4u32 {
+                    b.extend_from_slice(&cell(if row == half && ch == half { 428 } else { 0 }, 1, 0).0.to_be_bytes());
+                }
+            }
+        }
+        b.extend_from_slice(&bytes[SIGNATURE_OFFSET + 4 + 1024..]);
+        let module = Module::from_reader(&mut &b[..]).unwrap();
+        assert_eq!(module.channels, 8);
+        assert_eq!(module.patterns.len(), 2);
+        assert_eq!(module.positions(), &[0, 1]);
+        assert_eq!(module.samples[0].data.len(), 16);
+        let notes = module.patterns.iter().flat_map(|p| p.rows.iter().enumerate().flat_map(|(r, row)| {
+            row.channels.iter().enumerate().filter(|(_, c)| c.period() != 0).map(move |(c, _)| (r, c))
+        })).collect::<Vec<_>>();
+        assert_eq!(notes, vec![(0, 0), (1, 5), (2, 2), (3, 7)]);
+    }
+
+    #[test]
+    fn test_wow() {
+        // module_bytes with 8 channels per row, but still signed M.K.
+        let bytes = module_bytes();
+        let mut b = bytes[..SIGNATURE_OFFSET + 4].to_vec();
+        for row in 0..64u32 {
+            for ch in 0..8u32 {
+                b.extend_from_slice(&cell(if ch == row % 8 { 428 } else { 0 }, 1, 0).0.to_be_bytes());
+            }
+        }
+        b.extend_from_slice(&bytes[SIGNATURE_OFFSET + 4 + 1024..]);
+        let module = Module::from_reader(&mut &b[..]).unwrap();
+        assert_eq!(module.channels, 8);
+        let row = &module.patterns[0].rows[5];
+        assert_eq!(row.channels[5].period(), 428);
+        assert_eq!(row.channels[4].period(), 0);
+        assert_eq!(module.samples[0].data.len(), 16);
+        assert!(module.samples[0].data[1] > module.samples[0].data[0]);
+        assert_eq!(module.samples[1].data.len(), 16);
+
+        // Anything but an exact fit is an ordinary 4-channel module.
+        let module = Module::from_reader(&mut &bytes[..]).unwrap();
+        assert_eq!(module.channels, 4);
+        let mut b = b.clone();
+        b.push(0);
+        let module = Module::from_reader(&mut &b[..]).unwrap();
+        assert_eq!(module.channels, 4);
+        b[SIGNATURE_OFFSET..SIGNATURE_OFFSET + 4].copy_from_slice(b"M!K!");
+        b.pop();
+        let module = Module::from_reader(&mut &b[..]).unwrap();
+        assert_eq!(module.channels, 4);
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let mut bytes = module_bytes();
+        // Every sample at the longest length the header allows.
+        for i in 0..31 {
+            bytes[20 + i * 30 + 22] = 0xff;
+            bytes[20 + i * 30 + 23] = 0xff;
+        }
+        // Uses all 128 patterns.
+        bytes[20 + 31 * 30 + 2] = 127;
+        let declared = 128 * 1024 + 31 * 0xffff * 2;
+        match Module::from_reader_limited(&mut &bytes[..], declared - 1) {
+            Err(Error::ParseError(_)) => (),
+            _ => panic!("expected a parse error"),
+        }
+        // Within the limit, the missing data is reported as usual.
+        assert!(matches!(Module::from_reader_limited(&mut &bytes[..], declared), Err(Error::IOError(_))));
+        assert!(declared <= MAX_MODULE_SIZE);
+    }
+
+    #[test]
+    fn test_render_to_wav() {
+        let module = jump_module(vec![cell(428, 1, 0), Data(0), Data(0), Data(0)]);
+        let mut player = Player::new(&module, 8000.0);
+        player.seek_position(1, 10);
+        player.playing = true;
+        let path = std::env::temp_dir().join(format!("track-test-{}.wav", std::process::id()));
+        player.offline().render_to_wav(&path, wav::SilenceTrim::new(), 8000).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        // Three patterns of 64 rows at 960 samples each (plus the one that
+        // moves on to the next row), in 16-bit stereo.
+        assert_eq!(len, 44 + 3 * 64 * 961 * 4);
+
+        // Same, at twice the rate.
+        player.offline().render_to_wav(&path, wav::SilenceTrim::new(), 16000).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(len, 44 + 2 * 3 * 64 * 961 * 4);
+        assert_eq!((player.program, player.row), (1, 10));
+        assert!(player.playing && player.looped && !player.finished);
+
+        // The copy keeps the player's settings.
+        player.muted = vec![true; 4];
+        player.offline().render_to_wav(&path, wav::SilenceTrim::new(), 8000).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(data[44..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_song_length() {
+        let mut bytes = module_bytes();
+        // Two positions, out of the 128 in the table.
+        bytes[20 + 31 * 30] = 2;
+        let module = Module::from_reader(&mut &bytes[..]).unwrap();
+        assert_eq!(module.song_length, 2);
+        assert_eq!(module.positions(), &[0, 0]);
+
+        let mut patterns = vec![Pattern::empty(64, 4), Pattern::empty(64, 4)];
+        patterns[1].rows[0].channels[0] = cell(0, 0, 0xF01);
+        let module = Arc::new(Module {
+            title: "test".into(),
+            samples: vec![test_sample(vec![0.0; 100], 64)],
+            patterns,
+            program: vec![0, 0, 1, 1],
+            song_length: 2,
+            channels: 4,
+        });
+        let mut player = Player::new(&module, 44100.0);
+        player.looped = false;
+        while !player.finished {
+            player._simulate_row();
+            assert_eq!(player.pattern, 0);
+        }
+        assert_eq!(player.program, 1);
+        assert!((Player::new(&module, 44100.0).duration_estimate() - 2.0 * 64.0 * 0.12).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_soundtracker() {
+        // Same as module_bytes, but with only 15 sample headers and no
+        // signature.
+        let bytes = module_bytes();
+        let headers = 20 + 15 * 30;
+        let mut st = bytes[..headers].to_vec();
+        st.extend_from_slice(&bytes[20 + 31 * 30..SIGNATURE_OFFSET]);
+        st.extend_from_slice(&bytes[SIGNATURE_OFFSET + 4..]);
+        let module = Module::from_reader(&mut &st[..]).unwrap();
+        assert_eq!(module.title, "fuzz");
+        assert_eq!(module.samples.len(), 31);
+        assert_eq!(module.samples[0].data.len(), 16);
+        assert_eq!(module.samples[15].data.len(), 0);
+        assert_eq!(module.patterns[0].rows[0].channels[0].period(), 428);
+
+        // Sample data missing, as in many files found in the wild.
+        let module = Module::from_reader(&mut &st[..headers + 130 + 1024]).unwrap();
+        assert_eq!(module.samples[0].data.len(), 16);
+        // Cut off in the middle of the pattern data.
+        assert!(Module::from_reader(&mut &st[..headers + 130 + 500]).is_err());
+
+        // Neither layout makes sense.
+        let mut bad = st.clone();
+        bad[headers + 2] = 200;
+        assert!(matches!(Module::from_reader(&mut &bad[..]), Err(Error::ParseError(_))));
+    }
+
+    #[test]
+    fn test_from_reader_fuzz() {
+        let valid = module_bytes();
+        let module = Module::from_reader(&mut &valid[..]).unwrap();
+        assert_eq!(module.title, "fuzz");
+        assert_eq!(module.patterns.len(), 1);
+
+        // Xorshift, so that failures are reproducible.
+        let mut state = 0x2545f491u32;
+        let mut rand = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        for _ in 0..200 {
+            // Random flips of a valid module.
+            let mut bytes = valid.clone();
+            for _ in 0..(rand() % 16 + 1) {
+                let ix = (rand() as usize) % bytes.len();
+                bytes[ix] = rand() as u8;
+            }
+            let _ = Module::from_reader(&mut &bytes[..]);
+            // Truncated modules.
+            let len = (rand() as usize) % bytes.len();
+            let _ = Module::from_reader(&mut &bytes[..len]);
+            // Pure noise.
+            let noise = (0..(rand() % 4096)).map(|_| rand() as u8).collect::<Vec<u8>>();
+            let _ = Module::from_reader(&mut &noise[..]);
+        }
+        // Pattern numbers that would overflow when counted.
+        let mut bytes = valid.clone();
+        bytes[20 + 31 * 30 + 2] = 255;
+        assert!(Module::from_reader(&mut &bytes[..]).is_err());
+    }
+
+    #[test]
+    fn test_portamento_up_down() {
+        let module = test_module(vec![test_sample(vec![1.0; 20000], 64)], vec![
+            vec![cell(428, 1, 0x104), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0x104), Data(0), Data(0), Data(0)],
+            vec![cell(428, 0, 0), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0), Data(0), Data(0), Data(0)],
+            vec![cell(120, 0, 0x210), Data(0), Data(0), Data(0)],
+            vec![cell(850, 0, 0x110), Data(0), Data(0), Data(0)],
+            vec![cell(850, 0, 0x220), Data(0), Data(0), Data(0)],
+        ]);
+        let mut player = Player::new(&module, 44100.0);
+        let mut periods = vec![];
+        for _ in 0..7 {
+            for _ in 0..6 {
+                player._next_tick();
+            }
+            periods.push(player.channels[0].period);
+            player._next_division();
+        }
+        assert_eq!(periods, vec![408, 388, 428, 428, 200, 770, 856]);
+        assert!((player.channels[0].generator.as_ref().unwrap().rate - 850.0 / 856.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_compat_tempo_split() {
+        // Both families of players take F20 and above as BPM.
+        let module = test_module(vec![test_sample(vec![0.0; 100], 64)], vec![
+            vec![cell(0, 0, 0xF20), Data(0), Data(0), Data(0)],
+        ]);
+        let mut player = Player::new(&module, 44100.0);
+        assert_eq!(player.compat(), PlayerCompat::ProTracker);
+        assert_eq!(player.tempo(), (32, 6, player.tempo().2));
+
+        player.set_compat(PlayerCompat::FastTracker);
+        assert_eq!(player.e8x, E8xMode::Panning);
+        player.seek_seconds(0.0);
+        assert_eq!(player.tempo(), (32, 6, player.tempo().2));
+    }
+
+    #[test]
+    fn test_compat_slide_memory() {
+        let module = test_module(vec![test_sample(vec![1.0; 20000], 32)], vec![
+            vec![cell(428, 1, 0x104), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0x100), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0xEA4), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0xEA0), Data(0), Data(0), Data(0)],
+        ]);
+        let rows = |compat: PlayerCompat| {
+            let mut player = Player::new(&module, 44100.0);
+            player.set_compat(compat);
+            player.seek_seconds(0.0);
+            let mut res = vec![];
+            for _ in 0..4 {
+                for _ in 0..6 {
+                    player._next_tick();
+                }
+                let channel = &player.channels[0];
+                res.push((channel.period, channel.generator.as_ref().unwrap().volume));
+                player._next_division();
+            }
+            res
+        };
+        assert_eq!(rows(PlayerCompat::ProTracker), vec![(408, 32), (408, 32), (408, 36), (408, 36)]);
+        assert_eq!(rows(PlayerCompat::FastTracker), vec![(408, 32), (388, 32), (388, 36), (388, 40)]);
+    }
+
+    #[test]
+    fn test_vibrato_offset() {
+        assert_eq!(vibrato_offset(0, 15), 0);
+        assert_eq!(vibrato_offset(32, 15), 0);
+        assert_eq!(vibrato_offset(16, 8), 15);
+        for pos in 0..32 {
+            assert_eq!(vibrato_offset(pos + 32, 15), -vibrato_offset(pos, 15));
+            assert_eq!(vibrato_offset(16 - pos / 2, 15), vibrato_offset(16 + pos / 2, 15));
+        }
+    }
+
+    #[test]
+    fn test_sample_offset() {
+        // 20000 sample points, played back at 428 (about 3.3x slower than
+        // the 44.1kHz output rate).
+        let module = test_module(vec![test_sample(vec![1.0; 20000], 64)], vec![
+            vec![cell(428, 1, 0x904), Data(0), Data(0), Data(0)],
+            vec![cell(428, 0, 0x900), Data(0), Data(0), Data(0)],
+            vec![cell(428, 0, 0x9FF), Data(0), Data(0), Data(0)],
+        ]);
+        let mut player = Player::new(&module, 44100.0);
+        let ix = |p: &Player| p.channels[0].generator.as_ref().unwrap()._ix();
+        let scale = player.channels[0].generator.as_ref().unwrap().scale;
+        assert!(scale > 3.0);
+        assert_eq!(ix(&player), (1024.0 * scale) as usize);
+        player._next_division();
+        assert_eq!(ix(&player), (1024.0 * scale) as usize);
+        // Past the end of the sample.
+        player._next_division();
+        let length = player.channels[0].generator.as_ref().unwrap().signal.length();
+        assert_eq!(ix(&player), length - 1);
+    }
+
+    #[test]
+    fn test_tremolo() {
+        let module = test_module(vec![test_sample(vec![1.0; 20000], 32)], vec![
+            vec![cell(428, 1, 0x788), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0x700), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0), Data(0), Data(0), Data(0)],
+        ]);
+        let mut player = Player::new(&module, 44100.0);
+        let mut offsets = vec![];
+        for _ in 0..2 {
+            for _ in 0..6 {
+                player._next_tick();
+                offsets.push(player.channels[0].generator.as_ref().unwrap().volume_offset);
+            }
+            // The base volume stays untouched.
+            assert_eq!(player.channels[0].generator.as_ref().unwrap().volume, 32);
+            player._next_division();
+        }
+        // Speed 8 passes the peaks of the sine at positions 16 and 48.
+        assert_eq!(*offsets.iter().max().unwrap(), 255 * 8 / 64);
+        assert_eq!(*offsets.iter().min().unwrap(), -255 * 8 / 64);
+        // The offset is dropped on the row without tremolo.
+        let g = player.channels[0].generator.as_ref().unwrap();
+        assert_eq!((g.volume, g.volume_offset), (32, 0));
+    }
+
+    #[test]
+    fn test_retrigger_cut_delay() {
+        let module = test_module(vec![test_sample(vec![1.0; 20000], 64)], vec![
+            vec![cell(428, 1, 0xE92), cell(428, 1, 0xEC3), cell(428, 1, 0xED4), Data(0)],
+            vec![cell(0, 0, 0), Data(0), Data(0), Data(0)],
+        ]);
+        let mut player = Player::new(&module, 44100.0);
+        let mut positions = vec![];
+        let mut volumes = vec![];
+        let mut delayed = vec![];
+        for _ in 0..6 {
+            player._next_tick();
+            let g = player.channels[0].generator.as_mut().unwrap();
+            positions.push(g._ix());
+            for _ in 0..100 {
+                g.next();
+            }
+            volumes.push(player.channels[1].generator.as_ref().unwrap().volume);
+            delayed.push(player.channels[2].generator.is_some());
+        }
+        // Restarted on ticks 2 and 4.
+        assert_eq!(positions, vec![2, 102, 2, 102, 2, 102]);
+        assert_eq!(volumes, vec![64, 64, 64, 0, 0, 0]);
+        assert_eq!(delayed, vec![false, false, false, false, true, true]);
+        assert_eq!(player.channels[2].period, 428);
+    }
+
+    #[test]
+    fn test_effect_log() {
+        let module = test_module(vec![test_sample(vec![1.0; 20000], 64)], vec![
+            vec![cell(428, 1, 0xC20), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0xA20), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0), Data(0), Data(0), Data(0)],
+        ]);
+        let mut player = Player::new(&module, 44100.0);
+        // Nothing gets recorded unless asked for.
+        assert!(player.effect_log.is_empty());
+        player.log_effects = true;
+        player._reset();
+        for _ in 0..3 {
+            for _ in 0..6 {
+                player._next_tick();
+            }
+            player._next_division();
+        }
+        let log = player.effect_log.iter()
+            .map(|e| (e.row, e.tick, e.channel, e.effect.as_str(), e.volume, e.period))
+            .collect::<Vec<_>>();
+        assert_eq!(log, vec![
+            (0, 0, 0, "C20", 32, 428),
+            (1, 0, 0, "A20", 32, 428),
+            (1, 1, 0, "A20", 34, 428),
+            (1, 2, 0, "A20", 36, 428),
+            (1, 3, 0, "A20", 38, 428),
+            (1, 4, 0, "A20", 40, 428),
+            (1, 5, 0, "A20", 42, 428),
+        ]);
+    }
+
+    #[test]
+    fn test_vibrato_returns_to_pitch() {
+        let module = test_module(vec![test_sample(vec![1.0; 20000], 64)], vec![
+            vec![cell(428, 1, 0x48F), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0x400), Data(0), Data(0), Data(0)],
+        ]);
+        let mut player = Player::new(&module, 44100.0);
+        let rate = |p: &Player| p.channels[0].generator.as_ref().unwrap().rate;
+        for _ in 0..3 {
+            player._next_tick();
+        }
+        // Two vibrato ticks in: position 16, the top of the sine.
+        assert!((rate(&player) - 428.0 / (428.0 + 29.0)).abs() < 1e-6);
+        for _ in 0..3 {
+            player._next_tick();
+        }
+        player._next_division();
+        // 400 carries on with the remembered speed and depth.
+        assert_eq!(player.channels[0].vibrato, Some((8, 15)));
+        player._next_division();
+        assert_eq!(player.channels[0].period, 428);
+        assert_eq!(rate(&player), 1.0);
+    }
+
+    #[test]
+    fn test_count_in() {
+        let module = test_module(vec![test_sample(vec![0.0; 100], 64)], vec![]);
+        let mut player = Player::new(&module, 44100.0);
+        player.count_in = 4;
+        player.play();
+        // 125 BPM, 6 ticks per division: 5292 samples per division.
+        for _ in 0..(4 * 5292) {
+            player.next();
+        }
+        assert_eq!(player.counting_in(), 0);
+        assert_eq!((player.row, player.tick), (0, 0));
+        for _ in 0..5292 {
+            player.next();
+        }
+        assert_eq!(player.row, 0);
+        player.next();
+        assert_eq!(player.row, 1);
+    }
+
+    #[test]
+    fn test_click_grid() {
+        let module = jump_module(vec![]);
+        let mut player = Player::new(&module, 8000.0);
+        // Downbeats only, with the grid spanning a whole pattern.
+        player.click_grid = vec![false; 64];
+        player.click_grid[0] = true;
+        player.play();
+        let mut clicked = vec![];
+        // 960 samples per row at 8kHz, through all three patterns and into
+        // the first one again.
+        for _ in 0..(960 * (3 * 64 + 2)) {
+            let v = player.next();
+            let position = (player.program, player.row);
+            if v != 0.0 && clicked.last() != Some(&position) {
+                clicked.push(position);
+            }
+        }
+        assert_eq!(clicked, vec![(0, 0), (1, 0), (2, 0), (0, 0)]);
+    }
+
+    #[test]
+    fn test_glide() {
+        let sample = test_sample(vec![0.5; 20000], 64);
+        let mut sp = sample.play(notes::A4, 44100, dsp::Quality::Normal, LoopRule::AboveOne);
+        sp.trigger_start();
+        sp.glide(0.5, 100);
+        assert_eq!(sp.rate, 0.5);
+        for _ in 0..50 {
+            sp.next();
+        }
+        assert!((sp.rate - 0.75).abs() < 1e-4);
+        for _ in 0..50 {
+            sp.next();
+        }
+        assert_eq!(sp.rate, 1.0);
+        assert!(sp.glide.is_none());
+    }
+
+    /// Plays a note with sample 1 on row 0, runs it for a while and then
+    /// loads the given cell on row 1. Returns the channel's playback position
+    /// and volume afterwards, and the sample remembered for later notes.
+    fn load_after_note(c: Data, mode: SampleOnlyMode) -> (usize, u8, Option<usize>) {
+        let module = test_module(
+            vec![test_sample(vec![0.5; 20000], 64), test_sample(vec![1.0; 20000], 32)],
+            vec![
+                vec![cell(428, 1, 0), Data(0), Data(0), Data(0)],
+                vec![c, Data(0), Data(0), Data(0)],
+            ],
+        );
+        let mut player = Player::new(&module, 44100.0);
+        player.sample_only = mode;
+        for _ in 0..100 {
+            player.channels[0].generator.as_mut().unwrap().next();
+        }
+        player.row = 1;
+        player._load_row();
+        let channel = &player.channels[0];
+        let g = channel.generator.as_ref().unwrap();
+        (g._ix(), g.volume, channel.last_sample)
+    }
+
+    #[test]
+    fn test_sample_and_period_retriggers() {
+        assert_eq!(load_after_note(cell(428, 2, 0), SampleOnlyMode::Update), (2, 32, Some(2)));
+    }
+
+    #[test]
+    fn test_period_only_retriggers() {
+        assert_eq!(load_after_note(cell(214, 0, 0), SampleOnlyMode::Update), (2, 64, Some(1)));
+    }
+
+    #[test]
+    fn test_sample_only_updates() {
+        assert_eq!(load_after_note(cell(0, 2, 0), SampleOnlyMode::Update), (102, 32, Some(2)));
+        assert_eq!(load_after_note(cell(0, 2, 0), SampleOnlyMode::Retrigger), (2, 32, Some(2)));
+    }
+
+    #[test]
+    fn test_note_lengths() {
+        let module = test_module(vec![test_sample(vec![0.0; 100], 64)], vec![
+            vec![cell(428, 1, 0), Data(0), Data(0), Data(0)],
+            vec![Data(0), Data(0), Data(0), Data(0)],
+            vec![Data(0), Data(0), Data(0), Data(0)],
+            vec![Data(0), Data(0), Data(0), Data(0)],
+            vec![cell(381, 1, 0), Data(0), Data(0), Data(0)],
+            vec![Data(0), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0xc00), Data(0), Data(0), Data(0)],
+        ]);
+        let pattern = &module.patterns[0];
+        assert_eq!(pattern.note_lengths(0), vec![
+            NoteLength { row: 0, rows: 4 },
+            NoteLength { row: 4, rows: 2 },
+        ]);
+        assert_eq!(pattern.note_lengths(1), vec![]);
+
+        let player = Player::new(&module, 44100.0);
+        // 125 BPM, 6 ticks per division: 120ms per row.
+        let (ticks, ms) = player.rows_duration(4);
+        assert_eq!(ticks, 24);
+        assert!((ms - 480.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_e8x_panning() {
+        let module = test_module(
+            vec![test_sample(vec![0.0; 100], 64)],
+            vec![vec![cell(428, 1, 0xe84), Data(0), Data(0), Data(0)]],
+        );
+        let player = Player::new(&module, 44100.0);
+        assert_eq!(player.channels[0].pan, None);
+
+        let mut player = Player::new(&module, 44100.0);
+        player.e8x = E8xMode::Panning;
+        player._load_row();
+        let pan = player.channels[0].pan.unwrap();
+        assert!(pan > -1.0 && pan < 0.0);
+        assert!((pan - (8.0 / 15.0 - 1.0)).abs() < 0.001);
+        // The user's pans are left alone, and apply again once the song
+        // starts over.
+        assert_eq!(player.pans[0], -1.0);
+        let module = test_module(
+            vec![test_sample(vec![0.0; 100], 64)],
+            vec![vec![Data(0), Data(0), Data(0), Data(0)], vec![cell(428, 1, 0xe84), Data(0), Data(0), Data(0)]],
+        );
+        let mut player = Player::new(&module, 44100.0);
+        player.e8x = E8xMode::Panning;
+        player.seek(0, 1);
+        assert!(player.channels[0].pan.is_some());
+        player.stop();
+        assert_eq!(player.channels[0].pan, None);
+    }
+
+    #[test]
+    fn test_porta_sample_swap() {
+        let module = test_module(
+            vec![test_sample(vec![0.5; 20000], 64), test_sample(vec![1.0; 20000], 32)],
+            vec![
+                vec![cell(428, 1, 0), Data(0), Data(0), Data(0)],
+                vec![cell(381, 2, 0x308), Data(0), Data(0), Data(0)],
+            ],
+        );
+        let mut player = Player::new(&module, 44100.0);
+        player.playing = true;
+        while player.row == 0 {
+            player.next();
+        }
+        let ix = player.channels[0].generator.as_ref().unwrap()._ix();
+        assert!(ix > 1000);
+        {
+            let c = &player.channels[0];
+            let g = c.generator.as_ref().unwrap();
+            assert_eq!(g.volume, 32);
+            assert_eq!(c.period, 428);
+            assert_eq!(c.porta_target, Some(381));
+            assert_eq!(c.last_sample, Some(2));
+        }
+
+        // Run a few ticks into the row.
+        for _ in 0..2000 {
+            player.next();
+        }
+        let c = &player.channels[0];
+        let g = c.generator.as_ref().unwrap();
+        assert!(g._ix() > ix + 1000);
+        assert!(c.period < 428 && c.period > 381);
+        assert!(g.rate > 1.0);
+        assert_eq!(c.base_period, 428);
+    }
+
+    #[test]
+    fn test_tone_portamento() {
+        let module = test_module(vec![test_sample(vec![1.0; 20000], 64)], vec![
+            vec![cell(428, 1, 0), Data(0), Data(0), Data(0)],
+            vec![cell(320, 0, 0x308), Data(0), Data(0), Data(0)],
+            // 300 keeps sliding with the remembered speed.
+            vec![cell(0, 0, 0x300), Data(0), Data(0), Data(0)],
+            vec![cell(214, 0, 0x300), Data(0), Data(0), Data(0)],
+            // Overshooting stops at the target.
+            vec![cell(300, 0, 0x320), Data(0), Data(0), Data(0)],
+            vec![cell(0, 0, 0x320), Data(0), Data(0), Data(0)],
+        ]);
+        let mut player = Player::new(&module, 44100.0);
+        for _ in 0..500 {
+            player.channels[0].generator.as_mut().unwrap().next();
+        }
+        let mut periods = vec![];
+        for _ in 0..6 {
+            for _ in 0..6 {
+                player._next_tick();
+            }
+            periods.push(player.channels[0].period);
+            player._next_division();
+        }
+        assert_eq!(periods, vec![428, 388, 348, 308, 300, 300]);
+        let c = &player.channels[0];
+        assert_eq!(c.porta_target, None);
+        assert_eq!(c.base_period, 428);
+        // The note was never retriggered.
+        assert!(c.generator.as_ref().unwrap()._ix() >= 500);
+    }
+
+    #[test]
+    fn test_channel_map_swap() {
+        let module = test_module(
+            vec![test_sample(vec![1.0; 20000], 64)],
+            vec![vec![Data(0), cell(428, 1, 0), Data(0), Data(0)]],
+        );
+        // At the default pans, the channel plays on the right.
+        let mut player = Player::new(&module, 44100.0);
+        player.playing = true;
+        for _ in 0..100 {
+            player.next();
+        }
+        assert!(player.outputs[1].1 > 0.0);
+        assert_eq!(player.outputs[2].1, 0.0);
+
+        player.channel_map = vec![0, 2, 1, 3];
+        let v = player.next();
+        assert_eq!(player.outputs[1].1, 0.0);
+        assert!(player.outputs[2].1 > 0.0);
+        // Mono output at the level of the side it plays on.
+        assert_eq!(player.outputs[2].0, 0.0);
+        assert_eq!(v, player.outputs[2].1);
+    }
+
+    #[test]
+    fn test_mute_solo() {
+        let module = test_module(
+            vec![test_sample(vec![1.0; 20000], 64)],
+            vec![vec![cell(428, 1, 0), cell(428, 1, 0), Data(0), Data(0)]],
+        );
+        let mut player = Player::new(&module, 44100.0);
+        player.pans = vec![0.0; 4];
+        player.playing = true;
+        player.next();
+        assert!(player.outputs[0].0 > 0.0 && player.outputs[1].0 > 0.0);
+
+        player.solo(1);
+        assert_eq!(player.muted, vec![true, false, true, true]);
+        player.next();
+        assert_eq!(player.outputs[0].0, 0.0);
+        assert!(player.outputs[1].0 > 0.0);
+        // The muted note is gone for good, not just left out of the mix.
+        player.unmute_all();
+        player.next();
+        assert_eq!(player.outputs[0].0, 0.0);
+        assert!(player.channels[0].generator.as_ref().unwrap().position().is_none());
+        assert!(player.outputs[1].0 > 0.0);
+    }
+
+    #[test]
+    fn test_amiga_panning() {
+        let module = test_module(
+            vec![test_sample(vec![1.0; 20000], 64)],
+            vec![vec![cell(428, 1, 0), Data(0), Data(0), cell(428, 1, 0)]],
+        );
+        let mut player = Player::new(&module, 44100.0);
+        assert_eq!(player.pans, vec![-1.0, 1.0, 1.0, -1.0]);
+        player.playing = true;
+        let (l, r) = player.next_stereo();
+        assert!((l - 0.6).abs() < 1e-6);
+        assert_eq!(r, 0.0);
+
+        player.pans = vec![0.0; 4];
+        let (l, r) = player.next_stereo();
+        assert_eq!(l, r);
+        player.pans[3] = 1.0;
+        let (l, r) = player.next_stereo();
+        assert!((l - 0.3).abs() < 1e-6);
+        assert!((r - 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_stereo_sample() {
+        let right = test_sample(vec![-0.5; 20000], 64);
+        let sample = Sample {
+            right: Some(right),
+            ..Arc::try_unwrap(test_sample(vec![0.5; 20000], 64)).unwrap()
+        };
+        let module = test_module(vec![Arc::new(sample)], vec![vec![cell(428, 1, 0), Data(0), Data(0), Data(0)]]);
+        let mut player = Player::new(&module, 44100.0);
+        player.pans = vec![0.0; 4];
+        player.playing = true;
+        let (l, r) = (0..100).map(|_| player.next_stereo()).last().unwrap();
+        assert!((l - 0.15).abs() < 1e-6);
+        assert!((r + 0.15).abs() < 1e-6);
+        // Mono output mixes both sides down.
+        assert_eq!(player.next(), 0.0);
+    }
+}
@@ -1,5 +1,7 @@
 use std::{collections::BTreeMap};
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
 use crate::notes::{Note,NoteApprox};
+use crate::dsp;
 
 pub trait Generator {
     fn next(&mut self) -> f32;
@@ -18,28 +20,69 @@ pub trait Envelope {
     fn trigger_start(&mut self);
     fn trigger_end(&mut self);
     fn next(&mut self, delta: f32) -> Option<f32>;
+    /// Current stage of the envelope and the time spent in it, for
+    /// envelopes made of stages.
+    fn stage(&self) -> Option<(ADSRState, f32)> {
+        None
+    }
+    /// Whether the envelope has run its course and stays silent until
+    /// triggered again.
+    fn is_finished(&self) -> bool {
+        false
+    }
 }
 
-enum ADSRState {
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub enum ADSRState {
     Inactive,
-    AttackDecay,
+    Attack,
+    Hold,
+    Decay,
     Sustain,
     Release,
 }
 
+/// Attack, hold, decay, sustain, release envelope.
 pub struct ADSR {
+    /// Time spent in the current state.
     t: f32,
     state: ADSRState,
 
     p: ADSRParams,
 }
 
-#[derive(Clone)]
+#[derive(Clone,PartialEq)]
 pub struct ADSRParams {
     pub a: f32,
+    /// Time spent at full level between the attack and the decay.
+    pub hold: f32,
     pub d: f32,
     pub s_level: f32,
     pub r: f32,
+    pub curve: AdsrCurve,
+}
+
+/// Shape of the attack, decay and release ramps.
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub enum AdsrCurve {
+    Linear,
+    /// Moves quickly at the start of each ramp and settles towards its end,
+    /// like an analog envelope charging a capacitor.
+    Exponential,
+}
+
+impl AdsrCurve {
+    /// Steepness of the exponential curve.
+    const K: f32 = 5.0;
+
+    /// Maps progress through a ramp (0.0 to 1.0) to how far the level has
+    /// moved towards the ramp's target.
+    fn shape(&self, v: f32) -> f32 {
+        match self {
+            AdsrCurve::Linear => v,
+            AdsrCurve::Exponential => (1.0 - (-Self::K * v).exp()) / (1.0 - (-Self::K).exp()),
+        }
+    }
 }
 
 impl ADSR {
@@ -59,48 +102,82 @@ fn lerp(a: f32, b: f32, v: f32) -> f32 {
 impl Envelope for ADSR {
     fn trigger_start(&mut self) {
         self.t = 0.0;
-        self.state = ADSRState::AttackDecay;
+        self.state = ADSRState::Attack;
     }
     fn trigger_end(&mut self) {
         self.t = 0.0;
         self.state = ADSRState::Release;
     }
     fn next(&mut self, delta: f32) -> Option<f32> {
-        let t = self.t;
         let p = &self.p;
-        match self.state {
-            ADSRState::Inactive => return None,
-            ADSRState::AttackDecay => {
-                self.t += delta;
-                if t < p.a {
-                    let v = t/ p.a;
-                    return Some(lerp(0.0, 1.0, v));
-                }
-                let t = t - p.a;
-                if t < p.d {
-                    let v = t / p.d;
-                    return Some(lerp(1.0, p.s_level, v));
-                }
-                self.state = ADSRState::Sustain;
-                return Some(p.s_level);
-            },
-            ADSRState::Sustain => return Some(p.s_level),
-            ADSRState::Release => {
-                self.t += delta;
-                if t >= p.r {
+        // Stages which are over pass their leftover time on to the next one,
+        // so that they all keep their length regardless of `delta`.
+        loop {
+            let t = self.t;
+            match self.state {
+                ADSRState::Inactive => return None,
+                ADSRState::Attack if t < p.a => {
+                    self.t += delta;
+                    return Some(lerp(0.0, 1.0, p.curve.shape(t / p.a)));
+                },
+                ADSRState::Attack => {
+                    self.t -= p.a;
+                    self.state = ADSRState::Hold;
+                },
+                ADSRState::Hold if t < p.hold => {
+                    self.t += delta;
+                    return Some(1.0);
+                },
+                ADSRState::Hold => {
+                    self.t -= p.hold;
+                    self.state = ADSRState::Decay;
+                },
+                ADSRState::Decay if t < p.d => {
+                    self.t += delta;
+                    return Some(lerp(1.0, p.s_level, p.curve.shape(t / p.d)));
+                },
+                ADSRState::Decay => self.state = ADSRState::Sustain,
+                ADSRState::Sustain => return Some(p.s_level),
+                ADSRState::Release if t < p.r => {
+                    self.t += delta;
+                    return Some(lerp(p.s_level, 0.0, p.curve.shape(t / p.r)));
+                },
+                ADSRState::Release => {
                     self.state = ADSRState::Inactive;
                     return None;
-                }
-                let v = t / p.r;
-                return Some(lerp(p.s_level, 0.0, v));
-            },
+                },
+            }
         }
     }
+    fn stage(&self) -> Option<(ADSRState, f32)> {
+        Some((self.state, self.t))
+    }
+    fn is_finished(&self) -> bool {
+        self.state == ADSRState::Inactive
+    }
 }
 
 pub trait Enveloped: Generator {
     fn trigger_start(&mut self);
     fn trigger_end(&mut self);
+    /// How far through its source material a voice is, 0.0 to 1.0, for
+    /// voices playing back a fixed sample.
+    fn position(&self) -> Option<f32> {
+        None
+    }
+    /// Stage of the voice's envelope, see Envelope::stage.
+    fn envelope_stage(&self) -> Option<(ADSRState, f32)> {
+        None
+    }
+    /// Whether the voice is done playing, having been released or having
+    /// run out of sound, so that it can be dropped.
+    fn is_finished(&self) -> bool {
+        false
+    }
+    /// Stereo position of the voice, -1.0 (left) through 1.0 (right).
+    fn pan(&self) -> f32 {
+        0.0
+    }
 }
 
 pub struct EnvelopedGenerator<G: Generator, E: Envelope> {
@@ -126,40 +203,649 @@ impl<G: Generator, E: Envelope> Enveloped for EnvelopedGenerator<G, E> {
     fn trigger_end(&mut self) {
         self.e.trigger_end();
     }
+    fn envelope_stage(&self) -> Option<(ADSRState, f32)> {
+        self.e.stage()
+    }
+    fn is_finished(&self) -> bool {
+        self.e.is_finished()
+    }
+}
+
+/// Processes a stream of samples, eg. a filter or a delay.
+pub trait Processor {
+    fn process(&mut self, v: f32) -> f32;
+}
+
+pub type DynProcessor = Box<dyn Processor + Send + Sync>;
+
+/// Effects applied one after another.
+pub struct ProcessChain(pub Vec<DynProcessor>);
+
+impl Processor for ProcessChain {
+    fn process(&mut self, v: f32) -> f32 {
+        self.0.iter_mut().fold(v, |v, p| p.process(v))
+    }
+}
+
+#[derive(PartialEq,Clone,Copy,Debug)]
+pub struct EqBand {
+    pub freq: f32,
+    pub gain_db: f32,
+}
+
+/// Three band equalizer: low shelf, mid peak and high shelf. Bands can be
+/// changed at any time, the filters pick up new settings on the next sample.
+pub struct Equalizer {
+    sample_rate: f32,
+    pub bands: [EqBand; 3],
+    applied: Option<[EqBand; 3]>,
+    filters: [dsp::Biquad; 3],
+}
+
+impl Equalizer {
+    pub fn new(sample_rate: u32) -> Self {
+        let flat = dsp::Biquad::peaking(sample_rate as f32, 1000.0, 0.0, 1.0);
+        Self {
+            sample_rate: sample_rate as f32,
+            bands: [
+                EqBand { freq: 200.0, gain_db: 0.0 },
+                EqBand { freq: 1000.0, gain_db: 0.0 },
+                EqBand { freq: 5000.0, gain_db: 0.0 },
+            ],
+            applied: None,
+            filters: [flat, flat, flat],
+        }
+    }
+}
+
+impl Processor for Equalizer {
+    fn process(&mut self, v: f32) -> f32 {
+        if self.applied != Some(self.bands) {
+            let sr = self.sample_rate;
+            let [low, mid, high] = self.bands;
+            self.filters[0].set_coefficients(&dsp::Biquad::low_shelf(sr, low.freq, low.gain_db));
+            self.filters[1].set_coefficients(&dsp::Biquad::peaking(sr, mid.freq, mid.gain_db, 0.7));
+            self.filters[2].set_coefficients(&dsp::Biquad::high_shelf(sr, high.freq, high.gain_db));
+            self.applied = Some(self.bands);
+        }
+        self.filters.iter_mut().fold(v, |v, f| f.process(v))
+    }
+}
+
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub enum FilterKind {
+    /// Low-pass, passing frequencies below the cutoff.
+    Low,
+    /// High-pass, passing frequencies above the cutoff.
+    High,
+    /// Band-pass, passing frequencies around the cutoff.
+    Band,
+}
+
+/// Resonant filter of one of the FilterKinds, with the cutoff (or center
+/// frequency for band-pass) and Q fixed at creation.
+pub struct Filter(dsp::Biquad);
+
+impl Filter {
+    pub fn new(kind: FilterKind, sample_rate: u32, cutoff: f32, q: f32) -> Self {
+        let sr = sample_rate as f32;
+        // Keep the cutoff below Nyquist, where the coefficients break down.
+        let cutoff = cutoff.clamp(1.0, sr * 0.49);
+        let q = q.max(0.01);
+        Self(match kind {
+            FilterKind::Low => dsp::Biquad::low_pass(sr, cutoff, q),
+            FilterKind::High => dsp::Biquad::high_pass(sr, cutoff, q),
+            FilterKind::Band => dsp::Biquad::band_pass(sr, cutoff, q),
+        })
+    }
+}
+
+impl Processor for Filter {
+    fn process(&mut self, v: f32) -> f32 {
+        self.0.process(v)
+    }
+}
+
+/// Longest time the delays on the mix can be set to, in seconds.
+pub const MAX_DELAY_TIME: f32 = 2.0;
+
+/// Feedback delay line. The line is sized for the time the delay is created
+/// with, and `time` can be changed while running to anything up to that.
+/// Returns only the delayed (wet) signal unless `dry` is raised.
+pub struct Delay {
+    line: Vec<f32>,
+    pos: usize,
+    sample_rate: u32,
+    /// Delay time, in seconds.
+    pub time: f32,
+    pub feedback: f32,
+    /// Levels of the delayed and the input signals in the output.
+    pub wet: f32,
+    pub dry: f32,
+}
+
+impl Delay {
+    pub fn new(sample_rate: u32, seconds: f32, feedback: f32) -> Self {
+        let length = std::cmp::max((sample_rate as f32 * seconds) as usize, 1);
+        Self {
+            line: vec![0.0; length],
+            pos: 0,
+            sample_rate,
+            time: seconds,
+            feedback,
+            wet: 1.0,
+            dry: 0.0,
+        }
+    }
+}
+
+impl Processor for Delay {
+    fn process(&mut self, v: f32) -> f32 {
+        let len = self.line.len();
+        let delay = ((self.time * self.sample_rate as f32) as usize).clamp(1, len);
+        let delayed = self.line[(self.pos + len - delay) % len];
+        self.line[self.pos] = v + delayed * self.feedback;
+        self.pos = (self.pos + 1) % len;
+        v * self.dry + delayed * self.wet
+    }
+}
+
+/// Schroeder reverb: parallel comb filters into series allpass filters,
+/// returning only the reverberated (wet) signal.
+pub struct Reverb {
+    combs: Vec<Delay>,
+    allpasses: Vec<(Vec<f32>, usize)>,
+}
+
+impl Reverb {
+    pub fn new(sample_rate: u32) -> Self {
+        // Freeverb's tunings, given at 44.1kHz.
+        let seconds = |samples: usize| samples as f32 / 44100.0;
+        let samples = |s: usize| std::cmp::max((seconds(s) * sample_rate as f32) as usize, 1);
+        Self {
+            combs: [1116, 1188, 1277, 1356].iter().map(|s| Delay::new(sample_rate, seconds(*s), 0.84)).collect(),
+            allpasses: [556, 441].iter().map(|s| (vec![0.0; samples(*s)], 0)).collect(),
+        }
+    }
+}
+
+impl Processor for Reverb {
+    fn process(&mut self, v: f32) -> f32 {
+        let n = self.combs.len() as f32;
+        let mut out = self.combs.iter_mut().map(|c| c.process(v)).sum::<f32>() / n;
+        for (line, pos) in self.allpasses.iter_mut() {
+            let delayed = line[*pos];
+            line[*pos] = out + delayed * 0.5;
+            *pos = (*pos + 1) % line.len();
+            out = delayed - out;
+        }
+        out
+    }
+}
+
+/// How much of a bus is sent to each of the shared effects.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct SendLevels {
+    pub delay: f32,
+    pub reverb: f32,
+}
+
+/// Effects shared by all buses, fed by their sends. The result is meant to
+/// be mixed back into the output next to the dry buses.
+pub struct SendBus {
+    pub delay: Delay,
+    pub reverb: Reverb,
+}
+
+impl SendBus {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            delay: Delay {
+                time: 0.3,
+                ..Delay::new(sample_rate, MAX_DELAY_TIME, 0.4)
+            },
+            reverb: Reverb::new(sample_rate),
+        }
+    }
+
+    /// Processes one sample of every bus, given with its send levels, and
+    /// returns the wet signal of all effects.
+    pub fn process(&mut self, buses: &[(f32, SendLevels)]) -> f32 {
+        let delay = buses.iter().map(|(v, s)| v * s.delay).sum();
+        let reverb = buses.iter().map(|(v, s)| v * s.reverb).sum();
+        self.delay.process(delay) + self.reverb.process(reverb)
+    }
+}
+
+/// Shared flag which, when set, makes processed generators pass their input
+/// through untouched. Processor state is kept while bypassed, so effects can
+/// be A/B compared without being torn down.
+pub type Bypass = Arc<AtomicBool>;
+
+pub fn process<G: Generator, P: Processor>(generator: G, processor: P, bypass: Bypass) -> ProcessedGenerator<G, P> {
+    ProcessedGenerator {
+        g: generator,
+        p: processor,
+        bypass,
+    }
+}
+
+pub struct ProcessedGenerator<G: Generator, P: Processor> {
+    g: G,
+    p: P,
+    bypass: Bypass,
+}
+
+impl<G: Generator, P: Processor> Generator for ProcessedGenerator<G, P> {
+    fn next(&mut self) -> f32 {
+        let v = self.g.next();
+        if self.bypass.load(Ordering::Relaxed) {
+            return v;
+        }
+        self.p.process(v)
+    }
+}
+
+impl<G: Enveloped, P: Processor> Enveloped for ProcessedGenerator<G, P> {
+    fn trigger_start(&mut self) {
+        self.g.trigger_start();
+    }
+    fn trigger_end(&mut self) {
+        self.g.trigger_end();
+    }
+    fn envelope_stage(&self) -> Option<(ADSRState, f32)> {
+        self.g.envelope_stage()
+    }
+    fn is_finished(&self) -> bool {
+        self.g.is_finished()
+    }
+}
+
+/// Makes a voice ignore being released, so that it plays to its end
+/// regardless of how long its key was held, like a drum machine's one-shot
+/// samples. Voices which loop then never end, so they should be kept from
+/// looping.
+pub fn ungated<G: Enveloped>(generator: G) -> Ungated<G> {
+    Ungated(generator)
+}
+
+pub struct Ungated<G: Enveloped>(G);
+
+impl<G: Enveloped> Generator for Ungated<G> {
+    fn next(&mut self) -> f32 {
+        self.0.next()
+    }
+}
+
+impl<G: Enveloped> Enveloped for Ungated<G> {
+    fn trigger_start(&mut self) {
+        self.0.trigger_start();
+    }
+    fn trigger_end(&mut self) {}
+    fn position(&self) -> Option<f32> {
+        self.0.position()
+    }
+    fn envelope_stage(&self) -> Option<(ADSRState, f32)> {
+        self.0.envelope_stage()
+    }
+    fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+    fn pan(&self) -> f32 {
+        self.0.pan()
+    }
+}
+
+/// Places a voice in the stereo field, see Enveloped::pan.
+pub fn pan<G: Enveloped>(generator: G, pan: f32) -> Panned<G> {
+    Panned {
+        g: generator,
+        pan: pan.clamp(-1.0, 1.0),
+    }
+}
+
+pub struct Panned<G: Enveloped> {
+    g: G,
+    pan: f32,
+}
+
+impl<G: Enveloped> Generator for Panned<G> {
+    fn next(&mut self) -> f32 {
+        self.g.next()
+    }
+}
+
+impl<G: Enveloped> Enveloped for Panned<G> {
+    fn trigger_start(&mut self) {
+        self.g.trigger_start();
+    }
+    fn trigger_end(&mut self) {
+        self.g.trigger_end();
+    }
+    fn position(&self) -> Option<f32> {
+        self.g.position()
+    }
+    fn envelope_stage(&self) -> Option<(ADSRState, f32)> {
+        self.g.envelope_stage()
+    }
+    fn is_finished(&self) -> bool {
+        self.g.is_finished()
+    }
+    fn pan(&self) -> f32 {
+        self.pan
+    }
+}
+
+/// Fades from a voice over to one replacing it, eg. after the notegen
+/// changed while the voice's key was held.
+struct Crossfade {
+    old: Option<DynEnveloped>,
+    new: DynEnveloped,
+    /// Samples into the crossfade, out of `length`.
+    done: usize,
+    length: usize,
+}
+
+impl Generator for Crossfade {
+    fn next(&mut self) -> f32 {
+        let new = self.new.next();
+        let old = match &mut self.old {
+            Some(old) => old.next(),
+            None => return new,
+        };
+        let t = self.done as f32 / self.length as f32;
+        self.done += 1;
+        if self.done >= self.length {
+            self.old = None;
+        }
+        old * (1.0 - t) + new * t
+    }
+}
+
+impl Enveloped for Crossfade {
+    fn trigger_start(&mut self) {
+        self.new.trigger_start();
+    }
+    fn trigger_end(&mut self) {
+        if let Some(old) = &mut self.old {
+            old.trigger_end();
+        }
+        self.new.trigger_end();
+    }
+    fn position(&self) -> Option<f32> {
+        self.new.position()
+    }
+    fn envelope_stage(&self) -> Option<(ADSRState, f32)> {
+        self.new.envelope_stage()
+    }
+    fn is_finished(&self) -> bool {
+        self.new.is_finished() && self.old.as_ref().map(|o| o.is_finished()).unwrap_or(true)
+    }
+    fn pan(&self) -> f32 {
+        self.new.pan()
+    }
+}
+
+/// Plays a signal through once, from the moment it's created, and then stays
+/// silent until triggered again.
+pub struct OneShot<S: dsp::Signal<Sample=f32>> {
+    signal: S,
+    /// Next index into the signal, or None once stopped.
+    ix: Option<usize>,
+}
+
+impl<S: dsp::Signal<Sample=f32>> OneShot<S> {
+    pub fn new(signal: S) -> Self {
+        Self {
+            signal,
+            ix: Some(0),
+        }
+    }
+}
+
+impl<S: dsp::Signal<Sample=f32>> Generator for OneShot<S> {
+    fn next(&mut self) -> f32 {
+        let ix = match self.ix {
+            Some(ix) if ix < self.signal.length() => ix,
+            _ => {
+                self.ix = None;
+                return 0.0;
+            },
+        };
+        self.ix = Some(ix + 1);
+        self.signal.get(ix)
+    }
+}
+
+impl<S: dsp::Signal<Sample=f32>> Enveloped for OneShot<S> {
+    fn trigger_start(&mut self) {
+        self.ix = Some(0);
+    }
+    fn trigger_end(&mut self) {
+        self.ix = None;
+    }
+    fn position(&self) -> Option<f32> {
+        self.ix.map(|ix| (ix as f32) / (std::cmp::max(self.signal.length(), 1) as f32))
+    }
+    fn is_finished(&self) -> bool {
+        !matches!(self.ix, Some(ix) if ix < self.signal.length())
+    }
 }
 
 pub type DynEnveloped = Box<dyn Enveloped + Send + Sync>;
 pub type NoteGen = Box<dyn Fn(Note) -> DynEnveloped + Send + Sync>;
 
+/// Sums stereo buses and mono sources, each scaled by its own gain. Sources
+/// play in the middle, and are dropped once finished.
+#[derive(Default)]
+pub struct Mixer {
+    pub sources: Vec<(DynEnveloped, f32)>,
+}
+
+impl Mixer {
+    pub fn add(&mut self, gen: DynEnveloped, gain: f32) {
+        self.sources.push((gen, gain));
+    }
+
+    /// Mixes one frame of each of the `buses`, given with their gains,
+    /// together with the next frame of the sources.
+    pub fn mix(&mut self, buses: &[((f32, f32), f32)]) -> (f32, f32) {
+        self.sources.retain(|(g, _)| !g.is_finished());
+        let mid = self.next();
+        buses.iter().fold((mid, mid), |(l, r), ((bl, br), gain)| (l + bl * gain, r + br * gain))
+    }
+}
+
+impl Generator for Mixer {
+    fn next(&mut self) -> f32 {
+        self.sources.iter_mut().map(|(g, gain)| g.next() * *gain).sum()
+    }
+}
+
+/// Which voice gets cut off to make room for a new note once the polyphony
+/// limit is reached.
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub enum StealPolicy {
+    Oldest,
+    Lowest,
+    Highest,
+}
+
+/// Response of voice volume to note-on velocity.
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub enum VelocityCurve {
+    Linear,
+    /// Soft playing stays quieter than with Linear, for controllers which
+    /// reach high velocities too easily.
+    Exponential,
+    /// Every note plays at full volume.
+    Fixed,
+}
+
+impl VelocityCurve {
+    /// Steepness of the exponential curve.
+    const K: f32 = 4.0;
+
+    /// Maps a velocity (0.0 to 1.0, eg. MIDI velocity divided by 127) to a
+    /// voice gain.
+    pub fn gain(&self, velocity: f32) -> f32 {
+        let v = velocity.clamp(0.0, 1.0);
+        match self {
+            VelocityCurve::Linear => v,
+            VelocityCurve::Exponential => ((Self::K * v).exp() - 1.0) / (Self::K.exp() - 1.0),
+            VelocityCurve::Fixed => 1.0,
+        }
+    }
+}
+
 pub struct PolyphonicGenerator {
     note_gen: Option<NoteGen>,
     generators: BTreeMap<NoteApprox, DynEnveloped>,
     pub scopes: BTreeMap<NoteApprox, Vec<f32>>, 
     scope_ix: usize,
+    scope_length: usize,
+    /// Maximum number of voices, or 0 for no limit.
+    pub max_voices: usize,
+    pub steal: StealPolicy,
+    /// Order in which the current voices were started.
+    started: BTreeMap<NoteApprox, u64>,
+    start_counter: u64,
+    /// How much aftertouch pressure swings the volume of a voice, 0.0 to
+    /// ignore it, 1.0 to go from silence at no pressure to full volume at
+    /// full pressure.
+    pub aftertouch_depth: f32,
+    /// Gain applied to each voice, changed by aftertouch while it sounds.
+    gains: BTreeMap<NoteApprox, f32>,
+    pub velocity_curve: VelocityCurve,
+    /// Gain of each voice from the velocity it was started with.
+    velocities: BTreeMap<NoteApprox, f32>,
+    /// Envelope stage of each voice as of the last sample, and the scope
+    /// indices at which it entered the stages worth marking.
+    stages: BTreeMap<NoteApprox, ADSRState>,
+    stage_marks: BTreeMap<NoteApprox, Vec<(usize, ADSRState)>>,
+    /// Notes of the voices whose keys are still held.
+    held: BTreeMap<NoteApprox, Note>,
+    /// Length of the crossfade, in samples, with which held voices are
+    /// replaced by ones from a new notegen. 0 leaves them playing as they
+    /// were started.
+    pub crossfade: usize,
 }
 
 impl PolyphonicGenerator {
     pub fn new() -> Self {
+        Self::new_with_max_voices(0)
+    }
+
+    /// Creates a generator which steals a voice whenever a note is started
+    /// with max_voices already playing, or never if max_voices is 0.
+    pub fn new_with_max_voices(max_voices: usize) -> Self {
         Self {
             note_gen: None,
             generators: BTreeMap::new(),
             scopes: BTreeMap::new(),
             scope_ix: 0,
+            scope_length: 512,
+            max_voices,
+            steal: StealPolicy::Oldest,
+            started: BTreeMap::new(),
+            start_counter: 0,
+            aftertouch_depth: 0.5,
+            gains: BTreeMap::new(),
+            velocity_curve: VelocityCurve::Linear,
+            velocities: BTreeMap::new(),
+            stages: BTreeMap::new(),
+            stage_marks: BTreeMap::new(),
+            held: BTreeMap::new(),
+            crossfade: 0,
+        }
+    }
+
+    /// Picks the voice to steal according to the steal policy.
+    fn _victim(&self) -> Option<NoteApprox> {
+        match self.steal {
+            StealPolicy::Oldest => self.started.iter().min_by_key(|(_, t)| **t).map(|(k, _)| *k),
+            StealPolicy::Lowest => self.generators.keys().next().copied(),
+            StealPolicy::Highest => self.generators.keys().next_back().copied(),
+        }
+    }
+
+    fn _remove(&mut self, nap: &NoteApprox) {
+        self.generators.remove(nap);
+        self.scopes.remove(nap);
+        self.started.remove(nap);
+        self.gains.remove(nap);
+        self.velocities.remove(nap);
+        self.stages.remove(nap);
+        self.stage_marks.remove(nap);
+        self.held.remove(nap);
+    }
+
+    /// Maps a MIDI aftertouch pressure (0 to 127) to a voice gain.
+    fn _aftertouch_gain(&self, pressure: u8) -> f32 {
+        let depth = self.aftertouch_depth.clamp(0.0, 1.0);
+        let pressure = (std::cmp::min(pressure, 127) as f32) / 127.0;
+        1.0 - depth + depth * pressure
+    }
+
+    pub fn scope_length(&self) -> usize {
+        self.scope_length
+    }
+
+    /// Sets the number of samples kept in each voice's scope buffer.
+    pub fn set_scope_length(&mut self, length: usize) {
+        let length = std::cmp::max(length, 1);
+        self.scope_length = length;
+        for scope in self.scopes.values_mut() {
+            scope.resize(length, 0.0);
+        }
+        for marks in self.stage_marks.values_mut() {
+            marks.retain(|(ix, _)| *ix < length);
+        }
+        if self.scope_ix >= length {
+            self.scope_ix = 0;
         }
     }
 
+    /// Sets what new voices are built by. Voices of held keys crossfade to
+    /// new ones if a crossfade length is set.
     pub fn set_notegen(&mut self, ng: NoteGen) {
+        if self.crossfade > 0 {
+            for (nap, note) in self.held.iter() {
+                if let Some(old) = self.generators.remove(nap) {
+                    let mut new = ng(*note);
+                    new.trigger_start();
+                    self.generators.insert(*nap, Box::new(Crossfade { old: Some(old), new, done: 0, length: self.crossfade }));
+                }
+            }
+        }
         self.note_gen = Some(ng);
     }
 
+    /// Starts a note at full velocity.
+    #[cfg(test)]
     pub fn start(&mut self, n: Note) {
+        self.start_velocity(n, 1.0);
+    }
+
+    /// Starts a note, with its volume scaled by the velocity (0.0 to 1.0)
+    /// through the velocity curve.
+    pub fn start_velocity(&mut self, n: Note, velocity: f32) {
         let nap: NoteApprox = n.into();
         if self.generators.contains_key(&nap) {
-            self.generators.remove(&nap);
-            self.scopes.remove(&nap);
+            self._remove(&nap);
+        }
+        while self.max_voices != 0 && self.generators.len() >= self.max_voices {
+            match self._victim() {
+                Some(victim) => self._remove(&victim),
+                None => break,
+            }
         }
 
-        self.scopes.insert(nap, vec![0.0; 512]);
+        self.scopes.insert(nap, vec![0.0; self.scope_length]);
+        self.started.insert(nap, self.start_counter);
+        self.start_counter += 1;
+        self.velocities.insert(nap, self.velocity_curve.gain(velocity));
+        self.held.insert(nap, n);
 
         if let Some(f) = self.note_gen.as_ref() {
             let gen = f(n);
@@ -167,6 +853,38 @@ impl PolyphonicGenerator {
         }
     }
 
+    /// Returns the playback positions of all voices which report one.
+    pub fn positions(&self) -> Vec<f32> {
+        self.generators.values().filter_map(|g| g.position()).collect()
+    }
+
+    /// Returns the current envelope stage of a voice, if it has one, and
+    /// where in its scope buffer it ended its attack and decay and started
+    /// its release, as positions from 0.0 to 1.0.
+    pub fn envelope_marks(&self, nap: &NoteApprox) -> (Option<ADSRState>, Vec<(f32, ADSRState)>) {
+        let marks = self.stage_marks.get(nap).map(|m| {
+            m.iter().map(|(ix, s)| ((*ix as f32) / (self.scope_length as f32), *s)).collect()
+        }).unwrap_or_default();
+        (self.stages.get(nap).copied(), marks)
+    }
+
+    /// Applies polyphonic aftertouch to the voice playing `n`, if any.
+    pub fn poly_aftertouch(&mut self, n: Note, pressure: u8) {
+        let nap: NoteApprox = n.into();
+        if self.generators.contains_key(&nap) {
+            let gain = self._aftertouch_gain(pressure);
+            self.gains.insert(nap, gain);
+        }
+    }
+
+    /// Applies channel aftertouch to all voices currently playing.
+    pub fn channel_aftertouch(&mut self, pressure: u8) {
+        let gain = self._aftertouch_gain(pressure);
+        for nap in self.generators.keys() {
+            self.gains.insert(*nap, gain);
+        }
+    }
+
     pub fn stop(&mut self, n: Note) {
         let nap: NoteApprox = n.into();
         if !self.generators.contains_key(&nap) {
@@ -174,23 +892,514 @@ impl PolyphonicGenerator {
         }
 
         self.generators.get_mut(&nap).unwrap().trigger_end();
+        self.held.remove(&nap);
     }
 }
 
-impl Generator for PolyphonicGenerator {
-    fn next(&mut self) -> f32 {
-        if self.scope_ix >= 512 {
+impl PolyphonicGenerator {
+    /// Plays the next left and right sample values of all voices, each placed
+    /// according to its pan.
+    pub fn next_stereo(&mut self) -> (f32, f32) {
+        let finished = self.generators.iter().filter(|(_, g)| g.is_finished()).map(|(k, _)| *k).collect::<Vec<_>>();
+        for k in finished.iter() {
+            self._remove(k);
+        }
+        if self.scope_ix >= self.scope_length {
             self.scope_ix = 0;
         }
         let ix = self.scope_ix;
         self.scope_ix += 1;
-        let mut res = 0.0f32;
+        let (mut l, mut r) = (0.0f32, 0.0f32);
         for (k, g) in self.generators.iter_mut() {
-            let v =  g.next();
+            let gain = self.gains.get(k).copied().unwrap_or(1.0) * self.velocities.get(k).copied().unwrap_or(1.0);
+            let v = g.next() * gain;
             self.scopes.get_mut(k).unwrap()[ix] = v;
-            res += v * 0.3;
+            let (gl, gr) = dsp::pan_gains(g.pan());
+            l += v * gl * 0.3;
+            r += v * gr * 0.3;
+
+            let marks = self.stage_marks.entry(*k).or_default();
+            // Overwritten in the scope, so no longer shown.
+            marks.retain(|(i, _)| *i != ix);
+            if let Some((stage, _)) = g.envelope_stage() {
+                let marked = matches!(stage, ADSRState::Hold | ADSRState::Decay | ADSRState::Sustain | ADSRState::Release);
+                if self.stages.insert(*k, stage) != Some(stage) && marked {
+                    marks.push((ix, stage));
+                }
+            }
         }
 
-        res
+        (l, r)
     }
-}
\ No newline at end of file
+}
+
+impl Generator for PolyphonicGenerator {
+    fn next(&mut self) -> f32 {
+        let (l, r) = self.next_stereo();
+        (l + r) / 2.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Constant(f32);
+
+    impl Generator for Constant {
+        fn next(&mut self) -> f32 {
+            self.0
+        }
+    }
+
+    impl Enveloped for Constant {
+        fn trigger_start(&mut self) {}
+        fn trigger_end(&mut self) {}
+    }
+
+    struct Double;
+
+    impl Processor for Double {
+        fn process(&mut self, v: f32) -> f32 {
+            v * 2.0
+        }
+    }
+
+    #[test]
+    fn test_adsr_stage() {
+        let mut adsr = ADSR::new(&ADSRParams { a: 0.1, hold: 0.0, d: 0.1, s_level: 0.5, r: 0.1, curve: AdsrCurve::Linear });
+        assert_eq!(adsr.stage(), Some((ADSRState::Inactive, 0.0)));
+        adsr.trigger_start();
+        let mut step = |n: usize| {
+            (0..n).for_each(|_| { adsr.next(0.01); });
+            adsr.stage().unwrap()
+        };
+        let (stage, t) = step(5);
+        assert_eq!(stage, ADSRState::Attack);
+        assert!((t - 0.05).abs() < 1e-4);
+        assert_eq!(step(10).0, ADSRState::Decay);
+        assert_eq!(step(10).0, ADSRState::Sustain);
+        assert_eq!(step(100).0, ADSRState::Sustain);
+        adsr.trigger_end();
+        assert_eq!(adsr.stage(), Some((ADSRState::Release, 0.0)));
+        adsr.next(0.01);
+        assert_eq!(adsr.stage().unwrap().0, ADSRState::Release);
+        (0..20).for_each(|_| { adsr.next(0.01); });
+        assert_eq!(adsr.stage().unwrap().0, ADSRState::Inactive);
+
+        // Voices mark the stage changes in their scope.
+        let mut poly = PolyphonicGenerator::new();
+        poly.set_scope_length(100);
+        poly.set_notegen(Box::new(|_| {
+            let p = ADSRParams { a: 0.1, hold: 0.0, d: 0.1, s_level: 0.5, r: 0.1, curve: AdsrCurve::Linear };
+            Box::new(envelope(Constant(1.0), ADSR::new(&p), 100))
+        }));
+        let note = Note::new(440.0);
+        poly.start(note);
+        (0..30).for_each(|_| { poly.next(); });
+        poly.stop(note);
+        (0..5).for_each(|_| { poly.next(); });
+        let (stage, marks) = poly.envelope_marks(&note.into());
+        assert_eq!(stage, Some(ADSRState::Release));
+        let stages = marks.iter().map(|(_, s)| *s).collect::<Vec<ADSRState>>();
+        // Without a hold, the attack ends straight into the decay.
+        assert_eq!(stages, vec![ADSRState::Decay, ADSRState::Sustain, ADSRState::Release]);
+        assert!(marks.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn test_adsr_hold() {
+        let mut adsr = ADSR::new(&ADSRParams { a: 0.1, hold: 0.2, d: 0.1, s_level: 0.5, r: 0.1, curve: AdsrCurve::Linear });
+        adsr.trigger_start();
+        // 10ms steps: attack for 10, hold for 20, decay for 10, then sustain.
+        let out = (0..60).map(|_| adsr.next(0.01).unwrap()).collect::<Vec<f32>>();
+        assert!(out[..10].windows(2).all(|w| w[1] > w[0]));
+        assert!(out[10..30].iter().all(|v| (v - 1.0).abs() < 1e-4), "{:?}", &out[8..32]);
+        assert!(out[31] < 1.0 && out[31] > 0.5);
+        assert!(out[41..].iter().all(|v| *v == 0.5));
+
+        adsr.trigger_end();
+        assert_eq!(adsr.next(0.01), Some(0.5));
+        assert!((0..20).any(|_| adsr.next(0.01).is_none()));
+    }
+
+    #[test]
+    fn test_adsr_curve() {
+        // Levels at 0%, 25%, 50%, 75% of the attack, then the decay's
+        // midpoint.
+        let levels = |curve: AdsrCurve| {
+            let mut adsr = ADSR::new(&ADSRParams { a: 1.0, hold: 0.0, d: 1.0, s_level: 0.5, r: 1.0, curve });
+            adsr.trigger_start();
+            let out = (0..4).map(|_| adsr.next(0.25).unwrap()).collect::<Vec<f32>>();
+            (0..2).for_each(|_| { adsr.next(0.25); });
+            (out, adsr.next(0.25).unwrap())
+        };
+        let (linear, linear_decay) = levels(AdsrCurve::Linear);
+        let (exp, exp_decay) = levels(AdsrCurve::Exponential);
+        assert_eq!(linear, vec![0.0, 0.25, 0.5, 0.75]);
+        assert!((linear_decay - 0.75).abs() < 1e-4);
+        // Concave: above the straight line, rising fastest at the start.
+        assert_eq!(exp[0], 0.0);
+        assert!(exp[2] > 0.5);
+        assert!(exp[1] - exp[0] > exp[2] - exp[1] && exp[2] - exp[1] > exp[3] - exp[2]);
+        // Decay falls faster early on too.
+        assert!(exp_decay < linear_decay);
+        // Both end up at the ramp targets.
+        assert!((AdsrCurve::Exponential.shape(1.0) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_send_bus() {
+        // Energy of the wet signal for a burst of input on the first bus.
+        let wet = |synth: SendLevels| {
+            let mut bus = SendBus::new(44100);
+            let tracker = SendLevels { delay: 0.5, reverb: 0.5 };
+            (0..44100).map(|i| {
+                let v = if i < 1000 { 1.0 } else { 0.0 };
+                bus.process(&[(v, synth), (0.0, tracker)]).powi(2)
+            }).sum::<f32>()
+        };
+        let none = SendLevels::default();
+        assert_eq!(wet(none), 0.0);
+        let low = SendLevels { delay: 0.2, reverb: 0.0 };
+        let high = SendLevels { delay: 0.8, reverb: 0.0 };
+        assert!(wet(high) > wet(low) && wet(low) > 0.0);
+        let low = SendLevels { delay: 0.0, reverb: 0.2 };
+        let high = SendLevels { delay: 0.0, reverb: 0.8 };
+        assert!(wet(high) > wet(low) && wet(low) > 0.0);
+
+        // The delay comes back after its delay time, not before.
+        let mut delay = Delay::new(1000, 0.1, 0.0);
+        let out = (0..200).map(|i| delay.process(if i == 0 { 1.0 } else { 0.0 })).collect::<Vec<f32>>();
+        assert_eq!(out.as_slice().iter().position(|v| *v != 0.0), Some(100));
+    }
+
+    #[test]
+    fn test_echo() {
+        let mut echo = Delay::new(1000, MAX_DELAY_TIME, 0.5);
+        echo.time = 0.1;
+        (echo.wet, echo.dry) = (0.5, 1.0);
+        let out = (0..350).map(|i| echo.process(if i == 0 { 1.0 } else { 0.0 })).collect::<Vec<f32>>();
+        let echoes = out.as_slice().iter().enumerate().filter(|(_, v)| **v != 0.0).map(|(i, v)| (i, *v)).collect::<Vec<_>>();
+        assert_eq!(echoes, vec![(0, 1.0), (100, 0.5), (200, 0.25), (300, 0.125)]);
+
+        // Times past the maximum are capped to it.
+        let mut echo = Delay::new(1000, MAX_DELAY_TIME, 0.5);
+        echo.time = MAX_DELAY_TIME * 2.0;
+        let out = (0..3000).map(|i| echo.process(if i == 0 { 1.0 } else { 0.0 })).collect::<Vec<f32>>();
+        assert_eq!(out.as_slice().iter().position(|v| *v != 0.0), Some(2000));
+    }
+
+    #[test]
+    fn test_one_shot() {
+        let mut o = OneShot::new(vec![1.0, 2.0, 3.0]);
+        let out = (0..6).map(|_| o.next()).collect::<Vec<f32>>();
+        assert_eq!(out, vec![1.0, 2.0, 3.0, 0.0, 0.0, 0.0]);
+        assert_eq!(o.position(), None);
+
+        o.trigger_start();
+        assert_eq!(o.next(), 1.0);
+        o.trigger_end();
+        assert_eq!(o.next(), 0.0);
+    }
+
+    /// RMS of a sine of the given frequency after passing through an
+    /// equalizer or filter, skipping the filters' settling time.
+    fn eq_rms<P: Processor>(eq: &mut P, freq: f32) -> f32 {
+        let sr = 44100.0;
+        let out = (0..8820).map(|i| eq.process((2.0 * std::f32::consts::PI * freq * (i as f32) / sr).sin())).collect::<Vec<f32>>();
+        (out[4410..].iter().map(|v| v * v).sum::<f32>() / 4410.0).sqrt()
+    }
+
+    #[test]
+    fn test_eq_high_shelf() {
+        let mut flat = Equalizer::new(44100);
+        let mut boosted = Equalizer::new(44100);
+        boosted.bands[2].gain_db = 12.0;
+        let (flat_high, boosted_high) = (eq_rms(&mut flat, 12000.0), eq_rms(&mut boosted, 12000.0));
+        assert!((flat_high - 0.707).abs() < 0.01);
+        assert!(boosted_high > flat_high * 3.0);
+        // Lows are left alone.
+        assert!((eq_rms(&mut boosted, 100.0) - 0.707).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut hp = Filter::new(FilterKind::High, 44100, 200.0, 0.707);
+        let dc = (0..44100).map(|_| hp.process(1.0)).last().unwrap();
+        assert!(dc.abs() < 1e-3, "{}", dc);
+        let mut hp = Filter::new(FilterKind::High, 44100, 200.0, 0.707);
+        assert!((eq_rms(&mut hp, 5000.0) - 0.707).abs() < 0.01);
+
+        let mut lp = Filter::new(FilterKind::Low, 44100, 1000.0, 0.707);
+        assert!((eq_rms(&mut lp, 100.0) - 0.707).abs() < 0.01);
+        let mut lp = Filter::new(FilterKind::Low, 44100, 1000.0, 0.707);
+        assert!(eq_rms(&mut lp, 10000.0) < 0.02);
+
+        // Band-pass around 1kHz: unity at the center, falling off on both
+        // sides, most strongly with a high Q.
+        let bp_rms = |freq: f32, q: f32| eq_rms(&mut Filter::new(FilterKind::Band, 44100, 1000.0, q), freq);
+        let freqs = [250.0, 500.0, 900.0, 1000.0, 1100.0, 2000.0, 4000.0];
+        let levels = freqs.map(|f| bp_rms(f, 2.0));
+        let peak = levels.iter().enumerate().fold(0, |p, (i, l)| if *l > levels[p] { i } else { p });
+        assert_eq!(freqs[peak], 1000.0);
+        assert!((levels[peak] - 0.707).abs() < 0.01);
+        assert!(levels[0] < 0.1 && levels[6] < 0.1);
+        assert!(bp_rms(2000.0, 8.0) < bp_rms(2000.0, 2.0));
+    }
+
+    #[test]
+    fn test_bypass() {
+        let bypass = Bypass::default();
+        let mut g = process(Constant(0.25), ProcessChain(vec![Box::new(Double), Box::new(Double)]), bypass.clone());
+        assert_eq!(g.next(), 1.0);
+        bypass.store(true, Ordering::Relaxed);
+        assert_eq!(g.next(), 0.25);
+        bypass.store(false, Ordering::Relaxed);
+        assert_eq!(g.next(), 1.0);
+    }
+
+    /// Starts A4, A3, A5 and E5 in that order on a three voice generator and
+    /// returns the frequencies left playing.
+    fn steal_survivors(steal: StealPolicy) -> Vec<NoteApprox> {
+        let mut poly = PolyphonicGenerator::new_with_max_voices(3);
+        poly.steal = steal;
+        poly.set_notegen(Box::new(|_| Box::new(Constant(1.0))));
+        for freq in [440.0, 220.0, 880.0, 660.0] {
+            poly.start(Note::new(freq));
+            assert!(poly.generators.len() <= 3);
+        }
+        poly.generators.keys().copied().collect()
+    }
+
+    fn approx(freqs: &[f32]) -> Vec<NoteApprox> {
+        freqs.iter().map(|f| Note::new(*f).into()).collect()
+    }
+
+    #[test]
+    fn test_steal_oldest() {
+        assert_eq!(steal_survivors(StealPolicy::Oldest), approx(&[220.0, 660.0, 880.0]));
+    }
+
+    #[test]
+    fn test_steal_lowest() {
+        assert_eq!(steal_survivors(StealPolicy::Lowest), approx(&[440.0, 660.0, 880.0]));
+    }
+
+    #[test]
+    fn test_steal_highest() {
+        assert_eq!(steal_survivors(StealPolicy::Highest), approx(&[220.0, 440.0, 660.0]));
+    }
+
+    #[test]
+    fn test_max_voices() {
+        let mut poly = PolyphonicGenerator::new_with_max_voices(4);
+        poly.set_notegen(Box::new(|_| Box::new(Constant(1.0))));
+        for i in 0..16 {
+            poly.start(Note::new(110.0 * (i + 1) as f32));
+            assert!(poly.generators.len() <= 4 && poly.started.len() <= 4);
+            assert_eq!(poly.generators.len(), poly.scopes.len());
+        }
+        // The four most recent notes survive.
+        assert_eq!(poly.generators.keys().copied().collect::<Vec<_>>(), approx(&[1430.0, 1540.0, 1650.0, 1760.0]));
+
+        let mut poly = PolyphonicGenerator::new();
+        poly.set_notegen(Box::new(|_| Box::new(Constant(1.0))));
+        for i in 0..16 {
+            poly.start(Note::new(110.0 * (i + 1) as f32));
+        }
+        assert_eq!(poly.generators.len(), 16);
+    }
+
+    #[test]
+    fn test_aftertouch() {
+        let mut poly = PolyphonicGenerator::new();
+        poly.aftertouch_depth = 1.0;
+        poly.set_notegen(Box::new(|_| Box::new(Constant(1.0))));
+        let (a, b) = (Note::new(440.0), Note::new(220.0));
+        poly.start(a);
+        poly.start(b);
+        assert!((poly.next() - 0.6).abs() < 1e-6);
+
+        poly.poly_aftertouch(a, 0);
+        assert!((poly.next() - 0.3).abs() < 1e-6);
+        poly.channel_aftertouch(127);
+        assert!((poly.next() - 0.6).abs() < 1e-6);
+
+        // Retriggering a note starts it over at full volume.
+        poly.poly_aftertouch(b, 0);
+        poly.start(b);
+        assert!((poly.next() - 0.6).abs() < 1e-6);
+        // Notes which aren't playing are left alone.
+        poly.poly_aftertouch(Note::new(880.0), 0);
+        assert!(poly.gains.len() == 1);
+    }
+
+    #[test]
+    fn test_velocity_curve() {
+        for v in [0.01, 0.1, 0.25, 0.5, 0.8] {
+            assert!(VelocityCurve::Exponential.gain(v) < VelocityCurve::Linear.gain(v));
+            assert_eq!(VelocityCurve::Fixed.gain(v), 1.0);
+        }
+        for curve in [VelocityCurve::Linear, VelocityCurve::Exponential] {
+            assert_eq!(curve.gain(0.0), 0.0);
+            assert!((curve.gain(1.0) - 1.0).abs() < 1e-6);
+        }
+
+        let mut poly = PolyphonicGenerator::new();
+        poly.set_notegen(Box::new(|_| Box::new(Constant(1.0))));
+        poly.start_velocity(Note::new(440.0), 0.5);
+        let linear = poly.next();
+        assert!((linear - 0.3 * VelocityCurve::Linear.gain(0.5)).abs() < 1e-6);
+        poly.velocity_curve = VelocityCurve::Exponential;
+        poly.start_velocity(Note::new(440.0), 0.5);
+        assert!(poly.next() < linear);
+    }
+
+    #[test]
+    fn test_start_velocity() {
+        let mut poly = PolyphonicGenerator::new();
+        poly.set_notegen(Box::new(|_| Box::new(Constant(1.0))));
+        let (a, b) = (Note::new(440.0), Note::new(220.0));
+        poly.start_velocity(a, 0.5);
+        poly.start_velocity(b, 1.0);
+        poly.next();
+        let (sa, sb) = (poly.scopes[&a.into()][0], poly.scopes[&b.into()][0]);
+        assert!((sb / sa - 2.0).abs() < 1e-4, "{} {}", sa, sb);
+
+        // Plain start is full velocity.
+        poly.start(a);
+        poly.next();
+        assert_eq!(poly.scopes[&a.into()][1], sb);
+    }
+
+    #[test]
+    fn test_ungated() {
+        let mut poly = PolyphonicGenerator::new();
+        poly.set_notegen(Box::new(|_| Box::new(OneShot::new(vec![1.0; 10]))));
+        let (gated, one_shot) = (Note::new(440.0), Note::new(220.0));
+        poly.start(gated);
+        poly.stop(gated);
+        assert_eq!(poly.next(), 0.0);
+
+        poly.set_notegen(Box::new(|_| Box::new(ungated(OneShot::new(vec![1.0; 10])))));
+        poly.start(one_shot);
+        poly.next();
+        poly.stop(one_shot);
+        // Keeps playing until the sample runs out.
+        let out = (0..12).map(|_| poly.next()).collect::<Vec<f32>>();
+        assert!(out[..9].iter().all(|v| (v - 0.3).abs() < 1e-6));
+        assert_eq!(out[9..], [0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_pan() {
+        let energy = |pan_to: f32| {
+            let mut poly = PolyphonicGenerator::new();
+            poly.set_notegen(Box::new(move |_| Box::new(pan(Constant(1.0), pan_to))));
+            poly.start(Note::new(440.0));
+            (0..100).map(|_| poly.next_stereo()).fold((0.0, 0.0), |(l, r), (vl, vr)| (l + vl * vl, r + vr * vr))
+        };
+        let (l, r) = energy(-1.0);
+        assert!(l > 0.0 && r == 0.0);
+        let (l, r) = energy(1.0);
+        assert!(l == 0.0 && r > 0.0);
+        let (l, r) = energy(0.0);
+        assert!(l > 0.0 && l == r);
+
+        // Unpanned voices play in the centre.
+        let mut poly = PolyphonicGenerator::new();
+        poly.set_notegen(Box::new(|_| Box::new(Constant(1.0))));
+        poly.start(Note::new(440.0));
+        let (l, r) = poly.next_stereo();
+        assert!(l > 0.0 && l == r);
+    }
+
+    #[test]
+    fn test_mixer() {
+        let mut mixer = Mixer::default();
+        assert_eq!(mixer.next(), 0.0);
+        mixer.add(Box::new(Constant(0.5)), 1.0);
+        mixer.add(Box::new(Constant(-0.25)), 0.5);
+        assert_eq!(mixer.next(), 0.375);
+        mixer.sources[0].1 = 0.0;
+        assert_eq!(mixer.next(), -0.125);
+
+        let (l, r) = mixer.mix(&[((1.0, 0.0), 0.5), ((0.25, 0.5), 2.0)]);
+        assert_eq!((l, r), (0.875, 0.875));
+
+        // Finished sources are dropped.
+        mixer.add(Box::new(OneShot::new(vec![1.0])), 1.0);
+        assert_eq!(mixer.mix(&[]), (0.875, 0.875));
+        assert_eq!(mixer.sources.len(), 3);
+        assert_eq!(mixer.mix(&[]), (-0.125, -0.125));
+        assert_eq!(mixer.sources.len(), 2);
+    }
+
+    #[test]
+    fn test_notegen_crossfade() {
+        let mut poly = PolyphonicGenerator::new();
+        poly.set_notegen(Box::new(|_| Box::new(Constant(1.0))));
+        poly.start(Note::new(440.0));
+        poly.start(Note::new(220.0));
+        poly.stop(Note::new(220.0));
+        // Output of a single voice at 1.0.
+        let unit = poly.next() / 2.0;
+
+        // Without a crossfade, sounding voices carry on as they were.
+        poly.set_notegen(Box::new(|_| Box::new(Constant(0.5))));
+        assert_eq!(poly.next(), unit * 2.0);
+
+        // With one, the held voice moves over to the new notegen, and the
+        // released one is left alone.
+        poly.crossfade = 4;
+        poly.set_notegen(Box::new(|_| Box::new(Constant(0.0))));
+        let out = (0..6).map(|_| poly.next()).collect::<Vec<f32>>();
+        let want = [1.0, 0.75, 0.5, 0.25, 0.0, 0.0].map(|v| (v + 1.0) * unit);
+        assert!(out.iter().zip(want).all(|(o, w)| (o - w).abs() < 1e-6), "{:?}", out);
+        poly.stop(Note::new(440.0));
+        assert!(poly.held.is_empty());
+    }
+
+    #[test]
+    fn test_finished_voices() {
+        let mut poly = PolyphonicGenerator::new();
+        poly.set_notegen(Box::new(|_| {
+            let p = ADSRParams { a: 0.01, hold: 0.0, d: 0.01, s_level: 0.5, r: 0.1, curve: AdsrCurve::Linear };
+            Box::new(envelope(Constant(1.0), ADSR::new(&p), 100))
+        }));
+        let (released, held) = (Note::new(440.0), Note::new(220.0));
+        poly.start(released);
+        poly.start(held);
+        // Well into the sustain of both.
+        (0..100).for_each(|_| { poly.next(); });
+        assert_eq!(poly.generators.len(), 2);
+        poly.stop(released);
+        (0..5).for_each(|_| { poly.next(); });
+        assert_eq!(poly.generators.len(), 2);
+        (0..10).for_each(|_| { poly.next(); });
+        assert_eq!(poly.generators.keys().copied().collect::<Vec<_>>(), vec![held.into()]);
+        assert_eq!(poly.scopes.len(), 1);
+        assert!(poly.started.len() == 1 && poly.stage_marks.len() == 1);
+
+        poly.stop(held);
+        (0..20).for_each(|_| { poly.next(); });
+        assert!(poly.generators.is_empty());
+        assert!(poly.scopes.is_empty());
+    }
+
+    #[test]
+    fn test_scope_length() {
+        let mut poly = PolyphonicGenerator::new();
+        poly.set_scope_length(2048);
+        poly.set_notegen(Box::new(|_| Box::new(Constant(1.0))));
+        let note = Note::new(440.0);
+        poly.start(note);
+        for _ in 0..2048 {
+            poly.next();
+        }
+        let scope = &poly.scopes[&note.into()];
+        assert_eq!(scope.len(), 2048);
+        assert!(scope.iter().all(|v| *v == 1.0));
+    }
+}
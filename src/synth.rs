@@ -3,6 +3,30 @@ use crate::sound;
 pub trait Waveform {
     fn render(&self, i: f32) -> f32;
     fn period(&self) -> f32;
+    /// Correction to add to `render` at `phase` (0.0 to 1.0 through the
+    /// period) to smooth out jumps in the waveform, where `dt` is the phase
+    /// covered by one sample. Waveforms without jumps need none.
+    fn blep(&self, _phase: f32, _dt: f32) -> f32 {
+        0.0
+    }
+    /// Moves on state which doesn't follow `render`'s time, by `dt` seconds
+    /// of the waveform's time. Oscillators call this once per sample.
+    fn advance(&mut self, _dt: f64) {
+    }
+}
+
+/// PolyBLEP residual for a jump of +2 at phase 0: the difference between a
+/// band-limited step and the naive one, spread over one sample either side.
+fn poly_blep(phase: f32, dt: f32) -> f32 {
+    if phase < dt {
+        let t = phase / dt;
+        t + t - t * t - 1.0
+    } else if phase > 1.0 - dt {
+        let t = (phase - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
 }
 
 pub struct SineWave {
@@ -50,25 +74,250 @@ impl Waveform for SquareWave {
     fn period(&self) -> f32 {
         return 1.0 / self.freq;
     }
+    fn blep(&self, phase: f32, dt: f32) -> f32 {
+        // Jumps down at the start of the period, back up halfway through.
+        poly_blep((phase + 0.5) % 1.0, dt) - poly_blep(phase, dt)
+    }
+}
+
+/// Square wave with an adjustable duty cycle: the fraction of the period
+/// spent high. A duty of 0.5 is the same as SquareWave.
+pub struct PulseWave {
+    freq: f32,
+    duty: f32,
+}
+
+impl PulseWave {
+    pub fn new(freq: f32, duty: f32) -> Self {
+        Self {
+            freq,
+            // Keep both halves around so that the wave doesn't go silent.
+            duty: duty.clamp(0.01, 0.99),
+        }
+    }
+}
+
+impl Waveform for PulseWave {
+    fn render(&self, i: f32) -> f32 {
+        // Low first, like the square.
+        let v = (i * self.freq) % 1.0;
+        if v >= 1.0 - self.duty {
+            1.0
+        } else {
+            -1.0
+        }
+    }
+    fn period(&self) -> f32 {
+        1.0 / self.freq
+    }
+    fn blep(&self, phase: f32, dt: f32) -> f32 {
+        poly_blep((phase + self.duty) % 1.0, dt) - poly_blep(phase, dt)
+    }
+}
+
+pub struct TriangleWave {
+    freq: f32,
+}
+
+impl TriangleWave {
+    pub fn new(freq: f32) -> Self {
+        Self {
+            freq,
+        }
+    }
+}
+
+impl Waveform for TriangleWave {
+    fn render(&self, i: f32) -> f32 {
+        // Starts at zero going up, like the sine.
+        let v = (i * self.freq) % 1.0;
+        if v < 0.25 {
+            4.0 * v
+        } else if v < 0.75 {
+            2.0 - 4.0 * v
+        } else {
+            4.0 * v - 4.0
+        }
+    }
+    fn period(&self) -> f32 {
+        1.0 / self.freq
+    }
+}
+
+pub struct SawtoothWave {
+    freq: f32,
+}
+
+impl SawtoothWave {
+    pub fn new(freq: f32) -> Self {
+        Self {
+            freq,
+        }
+    }
+}
+
+impl Waveform for SawtoothWave {
+    fn render(&self, i: f32) -> f32 {
+        // Naive ramp, which aliases on high notes. Oscillators can smooth
+        // out the jump back down with `blep`.
+        let v = (i * self.freq) % 1.0;
+        2.0 * v - 1.0
+    }
+    fn period(&self) -> f32 {
+        1.0 / self.freq
+    }
+    fn blep(&self, phase: f32, dt: f32) -> f32 {
+        -poly_blep(phase, dt)
+    }
+}
+
+/// White noise. Samples are a hash of the phase, so rendering stays
+/// deterministic and doesn't need any state.
+pub struct NoiseWave {
+    seed: u32,
+}
+
+impl NoiseWave {
+    /// Noise doesn't repeat, but oscillators need a period to wrap their
+    /// phase at. This one is long enough not to be heard as a loop, and short
+    /// enough for the f32 phase to keep moving every sample.
+    const PERIOD: f32 = 60.0;
+
+    pub fn new(seed: u32) -> Self {
+        Self {
+            seed,
+        }
+    }
+
+    /// Noise with a seed of its own, different from all noise created
+    /// before it.
+    pub fn unique() -> Self {
+        static VOICES: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+        let voice = VOICES.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        // Spread consecutive voices over all the bits.
+        Self::new(xorshift32(voice.wrapping_add(1)))
+    }
+}
+
+/// One round of the xorshift32 PRNG.
+fn xorshift32(mut x: u32) -> u32 {
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    x
+}
+
+impl Waveform for NoiseWave {
+    fn render(&self, i: f32) -> f32 {
+        // xorshift never leaves zero, so make sure not to start there.
+        let mut x = (i.to_bits() ^ self.seed) | 1;
+        for _ in 0..3 {
+            x = xorshift32(x);
+        }
+        (x as f32) / (u32::MAX as f32) * 2.0 - 1.0
+    }
+    fn period(&self) -> f32 {
+        Self::PERIOD
+    }
+}
+
+/// Two-operator FM: a sine carrier with its phase modulated by a sine at
+/// `ratio` times its frequency, `index` setting how far the phase swings.
+/// Adds sidebands spaced by the modulator frequency around the carrier.
+/// The modulator keeps its own phase, moved on by `advance`, so that it
+/// runs on smoothly when oscillators wrap time around the carrier's period.
+pub struct FmWave {
+    freq: f32,
+    ratio: f32,
+    index: f32,
+    /// Modulator phase, 0.0 to 1.0 through its period.
+    modulator: f32,
+}
+
+impl FmWave {
+    pub fn new(freq: f32, ratio: f32, index: f32) -> Self {
+        Self {
+            freq,
+            ratio,
+            index,
+            modulator: 0.0,
+        }
+    }
+}
+
+impl Waveform for FmWave {
+    fn render(&self, i: f32) -> f32 {
+        let tau = 2.0 * std::f32::consts::PI;
+        let modulator = (tau * self.modulator).sin();
+        (tau * self.freq * i + self.index * modulator).sin()
+    }
+    fn period(&self) -> f32 {
+        1.0 / self.freq
+    }
+    fn advance(&mut self, dt: f64) {
+        let phase = self.modulator as f64 + (self.freq * self.ratio) as f64 * dt;
+        self.modulator = (phase % 1.0) as f32;
+    }
 }
 
 #[derive(PartialEq,Eq,Clone,Copy)]
 pub enum WaveformKind {
     Sine,
     Square,
+    Pulse,
+    Triangle,
+    Sawtooth,
+    Noise,
+    Fm,
 }
 
 pub enum AnyWaveform {
     Sine(SineWave),
     Square(SquareWave),
+    Pulse(PulseWave),
+    Triangle(TriangleWave),
+    Sawtooth(SawtoothWave),
+    Noise(NoiseWave),
+    Fm(FmWave),
 }
 
+/// Parameters of the waveforms which have any.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct WaveParams {
+    /// Fraction of the period pulse waves spend high.
+    pub duty: f32,
+    /// FM modulator frequency relative to the note, and modulation index.
+    pub fm_ratio: f32,
+    pub fm_index: f32,
+}
+
+impl Default for WaveParams {
+    fn default() -> Self {
+        Self {
+            duty: 0.5,
+            fm_ratio: 1.0,
+            fm_index: 2.0,
+        }
+    }
+}
 
 impl WaveformKind {
     pub fn new(&self, freq: f32) -> AnyWaveform {
+        self.new_with(freq, WaveParams::default())
+    }
+
+    /// Same as new, with the given waveform parameters.
+    pub fn new_with(&self, freq: f32, p: WaveParams) -> AnyWaveform {
         match self {
             WaveformKind::Sine => AnyWaveform::Sine(SineWave::new(freq)),
             WaveformKind::Square => AnyWaveform::Square(SquareWave::new(freq)),
+            WaveformKind::Pulse => AnyWaveform::Pulse(PulseWave::new(freq, p.duty)),
+            WaveformKind::Triangle => AnyWaveform::Triangle(TriangleWave::new(freq)),
+            WaveformKind::Sawtooth => AnyWaveform::Sawtooth(SawtoothWave::new(freq)),
+            // Each voice gets noise of its own, even when playing the same
+            // note as another.
+            WaveformKind::Noise => AnyWaveform::Noise(NoiseWave::unique()),
+            WaveformKind::Fm => AnyWaveform::Fm(FmWave::new(freq, p.fm_ratio, p.fm_index)),
         }
     }
 }
@@ -78,20 +327,126 @@ impl Waveform for AnyWaveform {
         match self {
             AnyWaveform::Sine(s) => s.period(),
             AnyWaveform::Square(s) => s.period(),
+            AnyWaveform::Pulse(s) => s.period(),
+            AnyWaveform::Triangle(s) => s.period(),
+            AnyWaveform::Sawtooth(s) => s.period(),
+            AnyWaveform::Noise(s) => s.period(),
+            AnyWaveform::Fm(s) => s.period(),
         }
     }
     fn render(&self, i: f32) -> f32 {
         match self {
             AnyWaveform::Sine(s) => s.render(i),
             AnyWaveform::Square(s) => s.render(i),
+            AnyWaveform::Pulse(s) => s.render(i),
+            AnyWaveform::Triangle(s) => s.render(i),
+            AnyWaveform::Sawtooth(s) => s.render(i),
+            AnyWaveform::Noise(s) => s.render(i),
+            AnyWaveform::Fm(s) => s.render(i),
+        }
+    }
+    fn blep(&self, phase: f32, dt: f32) -> f32 {
+        match self {
+            AnyWaveform::Sine(s) => s.blep(phase, dt),
+            AnyWaveform::Square(s) => s.blep(phase, dt),
+            AnyWaveform::Pulse(s) => s.blep(phase, dt),
+            AnyWaveform::Triangle(s) => s.blep(phase, dt),
+            AnyWaveform::Sawtooth(s) => s.blep(phase, dt),
+            AnyWaveform::Noise(s) => s.blep(phase, dt),
+            AnyWaveform::Fm(s) => s.blep(phase, dt),
+        }
+    }
+    fn advance(&mut self, dt: f64) {
+        if let AnyWaveform::Fm(s) = self {
+            s.advance(dt);
+        }
+    }
+}
+
+/// Crossfade between two waveforms of the same frequency. A blend of 0.0
+/// renders only `a`, 1.0 renders only `b`.
+pub struct MorphWave {
+    a: AnyWaveform,
+    b: AnyWaveform,
+    pub blend: f32,
+}
+
+impl MorphWave {
+    pub fn new(a: AnyWaveform, b: AnyWaveform, blend: f32) -> Self {
+        Self {
+            a, b, blend,
         }
     }
 }
 
+impl Waveform for MorphWave {
+    fn render(&self, i: f32) -> f32 {
+        let blend = self.blend.clamp(0.0, 1.0);
+        self.a.render(i) * (1.0 - blend) + self.b.render(i) * blend
+    }
+    fn period(&self) -> f32 {
+        self.a.period()
+    }
+    fn blep(&self, phase: f32, dt: f32) -> f32 {
+        let blend = self.blend.clamp(0.0, 1.0);
+        // Phase and step through each waveform's own period.
+        let period = self.period();
+        let own = |w: &AnyWaveform| {
+            let scale = period / w.period();
+            w.blep((phase * scale) % 1.0, dt * scale)
+        };
+        own(&self.a) * (1.0 - blend) + own(&self.b) * blend
+    }
+    fn advance(&mut self, dt: f64) {
+        self.a.advance(dt);
+        self.b.advance(dt);
+    }
+}
+
+/// Low frequency oscillator used for modulation, swinging between -depth and
+/// depth. Sine unless another waveform is set.
+pub struct Lfo {
+    sample_rate: f32,
+    cur: f32,
+    pub rate: f32,
+    pub depth: f32,
+    pub waveform: WaveformKind,
+}
+
+impl Lfo {
+    pub fn new(sample_rate: u32, rate: f32, depth: f32) -> Self {
+        Self {
+            sample_rate: sample_rate as f32,
+            cur: 0.0,
+            rate,
+            depth,
+            waveform: WaveformKind::Sine,
+        }
+    }
+}
+
+impl sound::Generator for Lfo {
+    fn next(&mut self) -> f32 {
+        let res = match self.waveform {
+            WaveformKind::Sine => (self.cur * 2.0 * std::f32::consts::PI).sin(),
+            // A waveform of 1Hz renders one period over the LFO's phase.
+            w => w.new(1.0).render(self.cur),
+        } * self.depth;
+        self.cur = (self.cur + self.rate / self.sample_rate) % 1.0;
+        res
+    }
+}
+
 pub struct Oscillator<W: Waveform> {
     sample_rate: f32,
     cur: f32,
     volume: f32,
+    /// Smooth out jumps in the waveform to reduce aliasing, see
+    /// Waveform::blep.
+    pub antialiased: bool,
+    /// Ratio to the waveform's own frequency to play at, for retuning the
+    /// oscillator while it runs.
+    pub pitch: f32,
 
     waveform: W,
 }
@@ -102,17 +457,475 @@ impl<W: Waveform> Oscillator<W> {
             sample_rate: sample_rate as f32,
             cur: 0.0,
             volume: 0.9,
+            antialiased: false,
+            pitch: 1.0,
 
             waveform: w,
         }
     }
+
+    /// Creates an oscillator picking up the phase of a free-running
+    /// oscillator started `t` seconds ago, so that oscillators created at the
+    /// same time are in phase with each other.
+    pub fn new_at(sample_rate: u32, w: W, t: f64) -> Self {
+        let mut res = Self::new(sample_rate, w);
+        res.cur = (t % (res.waveform.period() as f64)) as f32;
+        res.waveform.advance(t);
+        res
+    }
 }
 
 impl <W: Waveform> sound::Generator for Oscillator<W> {
     fn next(&mut self) -> f32 {
-        let res = self.waveform.render(self.cur) * self.volume;
-        self.cur += 1.0 / self.sample_rate;
+        let mut res = self.waveform.render(self.cur);
+        if self.antialiased {
+            let period = self.waveform.period();
+            res += self.waveform.blep(self.cur / period, self.pitch / (period * self.sample_rate));
+        }
+        let res = res * self.volume;
+        // Time runs faster through the waveform to raise the pitch.
+        self.cur += self.pitch / self.sample_rate;
         self.cur %= self.waveform.period();
+        self.waveform.advance((self.pitch / self.sample_rate) as f64);
         res
     }
 }
+
+/// Short decaying sine blip, used for metronome clicks.
+pub struct Click {
+    sample_rate: f32,
+    freq: f32,
+    /// Time since the click was triggered, in seconds, or None if silent.
+    t: Option<f32>,
+}
+
+impl Click {
+    const LENGTH: f32 = 0.03;
+
+    pub fn new(sample_rate: u32, freq: f32) -> Self {
+        Self {
+            sample_rate: sample_rate as f32,
+            freq,
+            t: None,
+        }
+    }
+
+    pub fn trigger(&mut self) {
+        self.t = Some(0.0);
+    }
+}
+
+impl sound::Generator for Click {
+    fn next(&mut self) -> f32 {
+        let t = match self.t {
+            Some(t) => t,
+            None => return 0.0,
+        };
+        let res = (t * self.freq * 2.0 * std::f32::consts::PI).sin() * (1.0 - t / Self::LENGTH);
+        let t = t + 1.0 / self.sample_rate;
+        self.t = if t < Self::LENGTH { Some(t) } else { None };
+        res
+    }
+}
+
+/// Oscillator morphing between two waveforms, with the blend swept by an LFO
+/// around its base value. Another LFO can add vibrato, with its depth in
+/// semitones.
+pub struct MorphOscillator {
+    osc: Oscillator<MorphWave>,
+    blend: f32,
+    lfo: Lfo,
+    vibrato: Option<Lfo>,
+}
+
+impl MorphOscillator {
+    /// Creates a morphing oscillator starting at the phase given by `t`, see
+    /// Oscillator::new_at.
+    pub fn new_at(sample_rate: u32, wave: MorphWave, lfo: Lfo, t: f64) -> Self {
+        Self {
+            blend: wave.blend,
+            osc: Oscillator::new_at(sample_rate, wave, t),
+            lfo,
+            vibrato: None,
+        }
+    }
+
+    pub fn set_vibrato(&mut self, lfo: Lfo) {
+        self.vibrato = Some(lfo);
+    }
+
+    pub fn set_antialiased(&mut self, antialiased: bool) {
+        self.osc.antialiased = antialiased;
+    }
+}
+
+impl sound::Generator for MorphOscillator {
+    fn next(&mut self) -> f32 {
+        self.osc.waveform.blend = self.blend + self.lfo.next();
+        if let Some(vibrato) = &mut self.vibrato {
+            self.osc.pitch = 2.0f32.powf(vibrato.next() / 12.0);
+        }
+        self.osc.next()
+    }
+}
+
+/// Tremolo: amplitude modulation by an LFO, applied after the envelope. At
+/// full depth the gain swings all the way down to silence, at zero depth the
+/// voice is left alone.
+pub struct Tremolo<G: sound::Generator> {
+    g: G,
+    lfo: Lfo,
+    depth: f32,
+}
+
+impl<G: sound::Generator> Tremolo<G> {
+    pub fn new(sample_rate: u32, g: G, rate: f32, depth: f32) -> Self {
+        Self {
+            g,
+            lfo: Lfo::new(sample_rate, rate, 1.0),
+            depth: depth.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl<G: sound::Generator> sound::Generator for Tremolo<G> {
+    fn next(&mut self) -> f32 {
+        // Full gain at the bottom of the LFO, lowest gain at its top.
+        let gain = 1.0 - self.depth * (0.5 + 0.5 * self.lfo.next());
+        self.g.next() * gain
+    }
+}
+
+impl<G: sound::Enveloped> sound::Enveloped for Tremolo<G> {
+    fn trigger_start(&mut self) {
+        self.g.trigger_start();
+    }
+    fn trigger_end(&mut self) {
+        self.g.trigger_end();
+    }
+    fn position(&self) -> Option<f32> {
+        self.g.position()
+    }
+    fn envelope_stage(&self) -> Option<(sound::ADSRState, f32)> {
+        self.g.envelope_stage()
+    }
+    fn is_finished(&self) -> bool {
+        self.g.is_finished()
+    }
+}
+
+/// Frequency ratios of `voices` unison voices, spread evenly from `detune`
+/// cents below the note to `detune` cents above it.
+pub fn unison_ratios(voices: u8, detune: f32) -> Vec<f32> {
+    let voices = voices.max(1);
+    if voices == 1 {
+        return vec![1.0];
+    }
+    (0..voices).map(|i| {
+        let cents = detune * (2.0 * i as f32 / (voices - 1) as f32 - 1.0);
+        (cents / 1200.0).exp2()
+    }).collect()
+}
+
+/// Stack of oscillators playing together, usually slightly detuned from each
+/// other. The sum is divided by the number of them, so it never gets louder
+/// than a single one.
+pub struct Unison<G: sound::Generator> {
+    voices: Vec<G>,
+}
+
+impl<G: sound::Generator> Unison<G> {
+    pub fn new(voices: Vec<G>) -> Self {
+        Self { voices }
+    }
+}
+
+impl<G: sound::Generator> sound::Generator for Unison<G> {
+    fn next(&mut self) -> f32 {
+        if self.voices.is_empty() {
+            return 0.0;
+        }
+        let sum: f32 = self.voices.iter_mut().map(|v| v.next()).sum();
+        sum / self.voices.len() as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::Generator;
+
+    #[test]
+    fn test_shared_phase() {
+        // Two voices started 543 samples apart, at times since a shared
+        // epoch.
+        let (t, late) = (12.3456, 543);
+        let mut a = Oscillator::new_at(44100, SineWave::new(440.0), t);
+        for _ in 0..late {
+            a.next();
+        }
+        let t_b = t + late as f64 / 44100.0;
+        let mut b = Oscillator::new_at(44100, SineWave::new(440.0), t_b);
+        let mut c = Oscillator::new(44100, SineWave::new(440.0));
+        // What a sine running freely since the epoch plays at each sample.
+        let clock = SineWave::new(440.0);
+        let free = |n: usize| 0.9 * clock.render(((t_b + n as f64 / 44100.0) % (1.0 / 440.0)) as f32);
+        let mut differ = false;
+        for n in 0..1000 {
+            let (va, vb, vc) = (a.next(), b.next(), c.next());
+            assert!((va - vb).abs() < 1e-3, "{} {} {}", n, va, vb);
+            assert!((vb - free(n)).abs() < 1e-3, "{} {} {}", n, vb, free(n));
+            differ |= (va - vc).abs() > 0.1;
+        }
+        // Unlike a voice starting at phase zero.
+        assert!(differ);
+    }
+
+    #[test]
+    fn test_triangle() {
+        let freq = 441.0;
+        let tri = WaveformKind::Triangle.new(freq);
+        assert_eq!(tri.period(), 1.0 / freq);
+        // One period is exactly 100 samples at 44.1kHz.
+        let period = (0..100).map(|i| tri.render((i as f32) / 44100.0)).collect::<Vec<f32>>();
+        assert!(period[0].abs() < 1e-4);
+        assert!((period[25] - 1.0).abs() < 1e-4);
+        assert!(period[50].abs() < 1e-4);
+        assert!((period[75] + 1.0).abs() < 1e-4);
+        assert!(period.iter().all(|v| (-1.0..=1.0).contains(v)));
+        // Linear in between.
+        assert!((period[10] - 0.4).abs() < 1e-4);
+        assert!((period[60] + 0.4).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_sawtooth() {
+        let freq = 441.0;
+        let saw = WaveformKind::Sawtooth.new(freq);
+        assert_eq!(saw.period(), 1.0 / freq);
+        // Two periods of exactly 100 samples each at 44.1kHz.
+        let out = (0..200).map(|i| saw.render((i as f32) / 44100.0)).collect::<Vec<f32>>();
+        assert!((out[0] + 1.0).abs() < 1e-4);
+        assert!(out[50].abs() < 1e-4);
+        assert!(out[..100].windows(2).all(|w| w[1] > w[0]));
+        assert!(out[..100].iter().all(|v| (-1.0..1.0).contains(v)));
+        // Jumps back down from the top at the start of the next period.
+        assert!(out[99] > 0.95);
+        assert!(out[100] < -0.95);
+    }
+
+    #[test]
+    fn test_pulse() {
+        // 100 samples per period at 44.1kHz.
+        let freq = 441.0;
+        for duty in [0.125, 0.25, 0.5, 0.75] {
+            let pulse = WaveformKind::Pulse.new_with(freq, WaveParams { duty, ..Default::default() });
+            let out = (0..1000).map(|i| pulse.render((i as f32 + 0.5) / 44100.0)).collect::<Vec<f32>>();
+            assert!(out.as_slice().iter().all(|v| *v == 1.0 || *v == -1.0));
+            let high = out.as_slice().iter().filter(|v| **v > 0.0).count();
+            assert!(((high as f32) / 1000.0 - duty).abs() < 0.01, "{} {}", duty, high);
+        }
+
+        // Duty 0.5 is the square wave.
+        let (pulse, square) = (WaveformKind::Pulse.new(freq), WaveformKind::Square.new(freq));
+        assert!((0..200).all(|i| {
+            let t = (i as f32 + 0.5) / 44100.0;
+            pulse.render(t) == square.render(t)
+        }));
+    }
+
+    #[test]
+    fn test_fm_sidebands() {
+        // Magnitude of the spectrum around `f`.
+        let peak = |kind: WaveformKind, f: f32| {
+            let mut osc = Oscillator::new(44100, kind.new_with(1000.0, WaveParams { fm_ratio: 0.25, fm_index: 1.5, ..Default::default() }));
+            let out = (0..4096).map(|_| osc.next()).collect::<Vec<f32>>();
+            let p = crate::dsp::StftParams { size: 4096, hop: 4096, window: crate::dsp::Window::Hann };
+            let frame = &crate::dsp::stft(&out, &p)[0];
+            let bin = (f / (44100.0 / 4096.0)).round() as usize;
+            frame[bin - 2..=bin + 2].iter().fold(0.0f32, |a, m| a.max(*m))
+        };
+        let carrier = peak(WaveformKind::Fm, 1000.0);
+        // Sidebands every 250Hz around the carrier, nothing in between.
+        for f in [500.0, 750.0, 1250.0, 1500.0] {
+            let sideband = peak(WaveformKind::Fm, f);
+            assert!(sideband > carrier * 0.05, "{}", f);
+            assert!(sideband > peak(WaveformKind::Fm, f + 125.0) * 20.0, "{}", f);
+            assert!(sideband > peak(WaveformKind::Sine, f) * 20.0, "{}", f);
+        }
+    }
+
+    #[test]
+    fn test_fm_continuous() {
+        // A ratio that's no simple fraction never lines up with the carrier,
+        // and still plays without jumps as the carrier's time wraps around.
+        let (freq, ratio, index) = (440.0, std::f32::consts::SQRT_2, 2.0);
+        let params = WaveParams { fm_ratio: ratio, fm_index: index, ..Default::default() };
+        let mut osc = Oscillator::new(44100, WaveformKind::Fm.new_with(freq, params));
+        let out = (0..44100).map(|_| osc.next()).collect::<Vec<f32>>();
+        // Steepest the wave can get, from both phases moving together.
+        let slope = 2.0 * std::f32::consts::PI * freq * (1.0 + index * ratio) / 44100.0 * 0.9;
+        assert!(out.windows(2).all(|w| (w[1] - w[0]).abs() < slope * 1.01));
+
+        // Oscillators started from a shared clock pick up the modulator's
+        // phase too.
+        let t = 12.345;
+        let mut late = Oscillator::new_at(44100, WaveformKind::Fm.new_with(freq, params), t);
+        let mut early = Oscillator::new_at(44100, WaveformKind::Fm.new_with(freq, params), t - 0.01);
+        for _ in 0..441 {
+            early.next();
+        }
+        for _ in 0..100 {
+            assert!((early.next() - late.next()).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn test_noise() {
+        let mut osc = Oscillator::new(44100, WaveformKind::Noise.new(440.0));
+        let out = (0..44100).map(|_| osc.next()).collect::<Vec<f32>>();
+        assert!(out.iter().all(|v| (-0.9..=0.9).contains(v)));
+        assert!(out.windows(2).all(|w| w[0] != w[1]));
+        // Roughly uniform: centred on zero, and reaching close to both ends.
+        let mean = out.iter().sum::<f32>() / (out.len() as f32);
+        assert!(mean.abs() < 0.02);
+        assert!(out.iter().any(|v| *v > 0.85) && out.iter().any(|v| *v < -0.85));
+
+        // Deterministic for a seed, but different for each voice, even of
+        // the same note.
+        let noise = WaveformKind::Noise.new(440.0);
+        assert_eq!(NoiseWave::new(7).render(0.5), NoiseWave::new(7).render(0.5));
+        assert_ne!(noise.render(0.5), WaveformKind::Noise.new(440.0).render(0.5));
+        assert!((0..1000).all(|i| (-1.0..=1.0).contains(&noise.render(i as f32 * 0.001))));
+    }
+
+    /// Share of a signal's spectrum outside of the odd harmonics of `freq`,
+    /// ie. aliasing for square and saw waves of that frequency.
+    fn inharmonic_energy(signal: &[f32], freq: f32) -> f32 {
+        let p = crate::dsp::StftParams { size: 4096, hop: 4096, window: crate::dsp::Window::Hann };
+        let frame = &crate::dsp::stft(signal, &p)[0];
+        let bin = 44100.0 / 4096.0;
+        let harmonic = |f: f32| {
+            let h = (f / freq).round();
+            h >= 1.0 && ((f - h * freq).abs() < 4.0 * bin)
+        };
+        let (mut off, mut total) = (0.0, 0.0);
+        for (i, m) in frame.iter().enumerate() {
+            let e = m * m;
+            total += e;
+            if !harmonic((i as f32) * bin) {
+                off += e;
+            }
+        }
+        off / total
+    }
+
+    #[test]
+    fn test_antialiasing() {
+        let freq = 3100.0;
+        for kind in [WaveformKind::Square, WaveformKind::Sawtooth] {
+            let mut naive = Oscillator::new(44100, kind.new(freq));
+            let mut smooth = Oscillator::new(44100, kind.new(freq));
+            smooth.antialiased = true;
+            let naive = (0..4096).map(|_| naive.next()).collect::<Vec<f32>>();
+            let smooth = (0..4096).map(|_| smooth.next()).collect::<Vec<f32>>();
+            let (n, s) = (inharmonic_energy(&naive, freq), inharmonic_energy(&smooth, freq));
+            assert!(s * 4.0 < n, "{} vs {}", s, n);
+        }
+
+        // Waveforms without jumps are left alone.
+        let mut osc = Oscillator::new(44100, WaveformKind::Triangle.new(freq));
+        let mut plain = Oscillator::new(44100, WaveformKind::Triangle.new(freq));
+        osc.antialiased = true;
+        assert!((0..100).all(|_| osc.next() == plain.next()));
+    }
+
+    #[test]
+    fn test_lfo_waveform() {
+        let mut lfo = Lfo::new(100, 1.0, 0.5);
+        lfo.waveform = WaveformKind::Triangle;
+        let out = (0..100).map(|_| lfo.next()).collect::<Vec<f32>>();
+        assert!((out[25] - 0.5).abs() < 1e-4);
+        assert!((out[75] + 0.5).abs() < 1e-4);
+        assert!((out[10] - 0.2).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_vibrato() {
+        let (freq, depth, sr) = (440.0, 1.0, 44100);
+        let wave = MorphWave::new(WaveformKind::Sine.new(freq), WaveformKind::Sine.new(freq), 0.0);
+        let mut osc = MorphOscillator::new_at(sr, wave, Lfo::new(sr, 0.0, 0.0), 0.0);
+        osc.set_vibrato(Lfo::new(sr, 4.0, depth));
+        let out = (0..sr).map(|_| osc.next()).collect::<Vec<f32>>();
+
+        // Instantaneous frequency from the distance between upward zero
+        // crossings.
+        let crossings = (1..out.len()).filter(|i| out[i - 1] < 0.0 && out[*i] >= 0.0).collect::<Vec<usize>>();
+        let freqs = crossings.windows(2).map(|w| (sr as f32) / ((w[1] - w[0]) as f32)).collect::<Vec<f32>>();
+        let (lo, hi) = freqs.iter().fold((f32::MAX, f32::MIN), |(lo, hi), f| (lo.min(*f), hi.max(*f)));
+        let (want_lo, want_hi) = (freq * 2.0f32.powf(-depth / 12.0), freq * 2.0f32.powf(depth / 12.0));
+        assert!((lo - want_lo).abs() < want_lo * 0.01, "{} {}", lo, want_lo);
+        assert!((hi - want_hi).abs() < want_hi * 0.01, "{} {}", hi, want_hi);
+        // Centred on the note: four vibrato periods average out.
+        assert!(((crossings.len() as f32) - freq).abs() < 2.0, "{}", crossings.len());
+    }
+
+    struct Constant;
+
+    impl sound::Generator for Constant {
+        fn next(&mut self) -> f32 {
+            1.0
+        }
+    }
+
+    #[test]
+    fn test_tremolo() {
+        let mut t = Tremolo::new(44100, Constant, 4.0, 0.5);
+        let out = (0..44100).map(|_| t.next()).collect::<Vec<f32>>();
+        let (lo, hi) = out.iter().fold((f32::MAX, f32::MIN), |(lo, hi), v| (lo.min(*v), hi.max(*v)));
+        assert!((lo - 0.5).abs() < 1e-3 && (hi - 1.0).abs() < 1e-3, "{} {}", lo, hi);
+        // Falls through the middle once per LFO period.
+        let falls = out.windows(2).filter(|w| w[0] > 0.75 && w[1] <= 0.75).count();
+        assert_eq!(falls, 4);
+        let rises = out.windows(2).filter(|w| w[0] < 0.75 && w[1] >= 0.75).count();
+        assert!((3..=4).contains(&rises));
+
+        let mut t = Tremolo::new(44100, Constant, 4.0, 0.0);
+        assert!((0..1000).all(|_| t.next() == 1.0));
+    }
+
+    #[test]
+    fn test_unison() {
+        assert_eq!(unison_ratios(1, 10.0), vec![1.0]);
+        let ratios = unison_ratios(3, 1200.0);
+        assert!((ratios[0] - 0.5).abs() < 1e-6 && ratios[1] == 1.0 && (ratios[2] - 2.0).abs() < 1e-6);
+
+        // Peak level over each 10ms, across a second.
+        let peaks = |voices: u8| {
+            let oscs = unison_ratios(voices, 10.0).iter().map(|r| Oscillator::new(44100, SineWave::new(440.0 * r))).collect();
+            let mut unison = Unison::new(oscs);
+            let out = (0..44100).map(|_| unison.next()).collect::<Vec<f32>>();
+            out.chunks(441).map(|c| c.iter().fold(0.0f32, |m, v| m.max(v.abs()))).collect::<Vec<f32>>()
+        };
+        // A single voice holds its level, detuned ones beat against each
+        // other.
+        let single = peaks(1);
+        let level = single[0];
+        assert!(single.iter().all(|p| (p - level).abs() < 0.01), "{:?}", single);
+        let stacked = peaks(3);
+        let (lo, hi) = stacked.iter().fold((f32::MAX, 0.0f32), |(lo, hi), p| (lo.min(*p), hi.max(*p)));
+        assert!(hi > level * 0.95 && hi <= level && lo < level * 0.5, "{} {}", lo, hi);
+    }
+
+    #[test]
+    fn test_morph_average() {
+        let freq = 440.0;
+        let morph = MorphWave::new(WaveformKind::Sine.new(freq), WaveformKind::Square.new(freq), 0.5);
+        let (sine, square) = (SineWave::new(freq), SquareWave::new(freq));
+        for i in 0..100 {
+            let t = (i as f32) / 44100.0;
+            let want = (sine.render(t) + square.render(t)) / 2.0;
+            assert!((morph.render(t) - want).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::io::Write;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+/// Removal of leading and trailing silence from rendered audio before it is
+/// written out.
+#[derive(Clone, Copy)]
+pub struct SilenceTrim {
+    pub enabled: bool,
+    /// Samples with an absolute value at or below this count as silent.
+    pub threshold: f32,
+}
+
+impl SilenceTrim {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            threshold: 0.001,
+        }
+    }
+
+    /// Trims an interleaved buffer with the given number of channels, if
+    /// enabled.
+    pub fn apply(&self, mut buf: Vec<f32>, channels: usize) -> Vec<f32> {
+        if !self.enabled {
+            return buf;
+        }
+        let range = trim_silence(&buf, channels, self.threshold);
+        buf.truncate(range.end);
+        buf.drain(..range.start);
+        buf
+    }
+}
+
+/// Returns the range of an interleaved buffer between its first and last
+/// non-silent frames. Fully silent buffers yield an empty range.
+fn trim_silence(buf: &[f32], channels: usize, threshold: f32) -> std::ops::Range<usize> {
+    let channels = std::cmp::max(channels, 1);
+    let loud = |frame: &[f32]| frame.iter().any(|v| v.abs() > threshold);
+    let frames = buf.chunks(channels).collect::<Vec<_>>();
+    let first = match frames.iter().position(|f| loud(f)) {
+        Some(f) => f,
+        None => return 0..0,
+    };
+    let last = frames.iter().rposition(|f| loud(f)).unwrap();
+    (first * channels)..std::cmp::min((last + 1) * channels, buf.len())
+}
+
+/// Writes an interleaved buffer as a 16-bit PCM WAV file. Values are scaled
+/// like in the audio output, clipping at ±1.0.
+pub fn write<W: Write>(w: &mut W, buf: &[f32], channels: u16, sample_rate: u32) -> std::io::Result<()> {
+    let data_len = (buf.len() * 2) as u32;
+    let block_align = channels * 2;
+    w.write_all(b"RIFF")?;
+    w.write_u32::<LittleEndian>(36 + data_len)?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_u32::<LittleEndian>(16)?;
+    // PCM
+    w.write_u16::<LittleEndian>(1)?;
+    w.write_u16::<LittleEndian>(channels)?;
+    w.write_u32::<LittleEndian>(sample_rate)?;
+    w.write_u32::<LittleEndian>(sample_rate * (block_align as u32))?;
+    w.write_u16::<LittleEndian>(block_align)?;
+    w.write_u16::<LittleEndian>(16)?;
+
+    w.write_all(b"data")?;
+    w.write_u32::<LittleEndian>(data_len)?;
+    for v in buf {
+        w.write_i16::<LittleEndian>((v * 32767.0).clamp(-32768.0, 32767.0) as i16)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let mut out = vec![];
+        write(&mut out, &[0.0, 1.0, -1.0, 2.0], 2, 8000).unwrap();
+        assert_eq!(out.len(), 44 + 8);
+        assert_eq!(&out[0..4], b"RIFF");
+        assert_eq!(&out[4..8], &(36u32 + 8).to_le_bytes());
+        assert_eq!(&out[22..24], &2u16.to_le_bytes());
+        assert_eq!(&out[24..28], &8000u32.to_le_bytes());
+        assert_eq!(&out[28..32], &32000u32.to_le_bytes());
+        assert_eq!(&out[36..40], b"data");
+        let samples = out[44..].chunks(2).map(|c| i16::from_le_bytes([c[0], c[1]])).collect::<Vec<_>>();
+        assert_eq!(samples, vec![0, 32767, -32767, 32767]);
+    }
+
+    #[test]
+    fn test_trim_silence() {
+        let buf = vec![0.0, 0.0005, 0.5, -0.25, 0.002, 0.0, 0.0, 0.0];
+        assert_eq!(trim_silence(&buf, 1, 0.001), 2..5);
+        assert_eq!(trim_silence(&buf, 2, 0.001), 2..6);
+        assert_eq!(trim_silence(&[0.0; 16], 2, 0.001), 0..0);
+
+        let trim = SilenceTrim { enabled: true, threshold: 0.001 };
+        assert_eq!(trim.apply(buf.clone(), 1), vec![0.5, -0.25, 0.002]);
+        let trim = SilenceTrim { enabled: false, threshold: 0.001 };
+        assert_eq!(trim.apply(buf.clone(), 1), buf);
+    }
+}